@@ -50,21 +50,16 @@ async fn main() {
     let mut rl = rustyline::Editor::<()>::new();
     let prompt = format!("{}>> ", options.room);
 
-    loop {
-        match rl.readline(&prompt) {
-            Ok(line) => {
-                if let Err(err) = client
-                    .add(
-                        format!("/{}/-", options.room),
-                        &format!("{}: {}", options.name, line),
-                    )
-                    .await
-                {
-                    println!("Error: {}", err);
-                    break;
-                }
-            }
-            Err(_) => break,
+    while let Ok(line) = rl.readline(&prompt) {
+        if let Err(err) = client
+            .add(
+                format!("/{}/-", options.room),
+                &format!("{}: {}", options.name, line),
+            )
+            .await
+        {
+            println!("Error: {}", err);
+            break;
         }
     }
 }
@@ -6,6 +6,12 @@ pub struct Batch {
     pub(crate) res: Result<Vec<JsonPatch>, BigJsonClientError>,
 }
 
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Batch {
     pub fn new() -> Self {
         Self { res: Ok(vec![]) }
@@ -34,9 +40,9 @@ impl Batch {
     }
 
     pub fn remove(mut self, path: impl Into<String>) -> Self {
-        self.res = self.res.and_then(|mut patch_list| {
+        self.res = self.res.map(|mut patch_list| {
             patch_list.push(JsonPatch::Remove { path: path.into() });
-            Ok(patch_list)
+            patch_list
         });
         self
     }
@@ -46,12 +52,12 @@ impl Batch {
         from: impl Into<String>,
         path: impl Into<String>,
     ) -> Self {
-        self.res = self.res.and_then(|mut patch_list| {
+        self.res = self.res.map(|mut patch_list| {
             patch_list.push(JsonPatch::Move {
                 from: from.into(),
                 path: path.into(),
             });
-            Ok(patch_list)
+            patch_list
         });
         self
     }
@@ -61,12 +67,12 @@ impl Batch {
         from: impl Into<String>,
         path: impl Into<String>,
     ) -> Self {
-        self.res = self.res.and_then(|mut patch_list| {
+        self.res = self.res.map(|mut patch_list| {
             patch_list.push(JsonPatch::Copy {
                 from: from.into(),
                 path: path.into(),
             });
-            Ok(patch_list)
+            patch_list
         });
         self
     }
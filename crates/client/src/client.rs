@@ -1,7 +1,6 @@
 use futures_util::TryStreamExt;
 use reqwest::{Client, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
-use std::io::ErrorKind;
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
 use crate::{json_patch::JsonPatch, Batch, BigJsonClientError, SubscriptionStream};
@@ -131,7 +130,7 @@ impl BigJsonClient {
         let stream = sse_codec::decode_stream(
             tokio_util::io::StreamReader::new(
                 resp.bytes_stream()
-                    .map_err(|err| std::io::Error::new(ErrorKind::Other, err.to_string())),
+                    .map_err(|err| std::io::Error::other(err.to_string())),
             )
             .compat(),
         );
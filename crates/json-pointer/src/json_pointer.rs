@@ -110,6 +110,12 @@ impl JsonPointer {
         }
     }
 
+    /// Appends a reference token, which is not escaped.
+    #[inline]
+    pub fn push(&mut self, segment: impl Into<String>) {
+        self.0.push(segment.into());
+    }
+
     pub fn split_last(&self) -> Option<(JsonPointerRef<'_>, &'_ str)> {
         self.as_ref().split_last()
     }
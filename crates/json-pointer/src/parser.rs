@@ -48,7 +48,7 @@ fn is_escape_char(ch: u8) -> bool {
     ch == b'0' || ch == b'1'
 }
 
-fn parse_segment(value: &[u8]) -> Cow<str> {
+fn parse_segment(value: &[u8]) -> Cow<'_, str> {
     fn find_escape(value: &[u8]) -> Option<usize> {
         let mut p = 0;
         let len = value.len();
@@ -1,63 +1,371 @@
+use std::{collections::HashMap, sync::Arc};
+
 use json_patch::JsonPatch;
-use json_pointer::{JsonPointer, JsonPointerRef, ToJsonPointerRef, ValueExt};
+use json_pointer::{JsonPointer, JsonPointerRef, ToJsonPointerRef};
 use serde_json::Value;
 
 use crate::{
+    history::History,
+    revision::Revisions,
     undo_command::{UndoCommand, UpdateSource, UpdateTarget},
-    MemDbError,
+    Effect, MemDbError, Node, Transaction,
 };
 
 #[derive(Debug)]
 pub struct MemDb {
-    root: Value,
+    /// Shares its unchanged subtrees with the snapshots taken before, a
+    /// change copies only the containers on its path.
+    root: Node,
+    /// Undo histories by the subtree they cover.
+    histories: HashMap<JsonPointer, History>,
+    /// The revision of the latest write, which is bumped by every write.
+    revision: u64,
+    revisions: Revisions,
+    /// Whether the running write has changed the document, and so takes the
+    /// next revision once it is committed.
+    writing: bool,
+}
+
+/// The revisions as they were at some point of the running write, which are
+/// restored when the changes made after it are rolled back.
+#[derive(Debug, Clone)]
+pub(crate) struct WriteMark {
+    revisions: Revisions,
+    writing: bool,
 }
 
 impl Default for MemDb {
     fn default() -> Self {
+        Self::new(Value::Object(Default::default()))
+    }
+}
+
+impl From<Node> for MemDb {
+    fn from(root: Node) -> Self {
         Self {
-            root: Value::Object(Default::default()),
+            root,
+            histories: HashMap::new(),
+            revision: 0,
+            revisions: Revisions::default(),
+            writing: false,
         }
     }
 }
 
 impl MemDb {
     pub fn new(root: Value) -> Self {
-        Self { root }
+        Self::from(Node::from(root))
+    }
+
+    /// Returns a copy of the value at `path`, see [`MemDb::get_node`] for
+    /// reading it without copying.
+    pub fn get(&self, path: impl ToJsonPointerRef) -> Option<Value> {
+        self.get_node(path).map(Node::to_value)
     }
 
-    pub fn get(&self, path: impl ToJsonPointerRef) -> Option<&Value> {
+    pub fn get_node(&self, path: impl ToJsonPointerRef) -> Option<&Node> {
         self.root.locate(path)
     }
 
+    /// Returns a copy of the whole document, see [`MemDb::root_node`] for
+    /// reading it without copying.
     #[inline]
-    pub fn root(&self) -> &Value {
+    pub fn root(&self) -> Value {
+        self.root.to_value()
+    }
+
+    #[inline]
+    pub fn root_node(&self) -> &Node {
         &self.root
     }
 
+    /// Returns a point-in-time view of the whole document which is not
+    /// affected by later changes, in O(1).
+    #[inline]
+    pub fn snapshot(&self) -> Node {
+        self.root.clone()
+    }
+
+    #[inline]
+    pub(crate) fn root_mut(&mut self) -> &mut Node {
+        &mut self.root
+    }
+
+    /// Returns the revision of the latest write, starting at 0 for a new
+    /// database.
+    #[inline]
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Returns the revision at which the value at `path`, or anything below
+    /// it, last changed. This includes the value being added or removed, so
+    /// it is also tracked for a path which does not exist.
+    pub fn revision_of(&self, path: impl ToJsonPointerRef) -> u64 {
+        self.revisions.get(path.to_json_pointer_ref())
+    }
+
+    /// Returns a copy of the value at `path` together with its revision, see
+    /// [`MemDb::revision_of`].
+    pub fn get_with_revision(&self, path: impl ToJsonPointerRef) -> (Option<Value>, u64) {
+        let (node, revision) = self.get_node_with_revision(path);
+        (node.map(Node::to_value), revision)
+    }
+
+    /// Like [`MemDb::get_with_revision`], but without copying the value.
+    pub fn get_node_with_revision(&self, path: impl ToJsonPointerRef) -> (Option<&Node>, u64) {
+        let path = path.to_json_pointer_ref();
+        (self.root.locate(path), self.revisions.get(path))
+    }
+
+    /// Fails with [`MemDbError::Changed`] unless the value at `path` is
+    /// unchanged since revision `since`, like a `test` operation on the whole
+    /// subtree.
+    pub fn check_unchanged(
+        &self,
+        path: impl ToJsonPointerRef,
+        since: u64,
+    ) -> Result<(), MemDbError> {
+        let path = path.to_json_pointer_ref();
+        let revision = self.revisions.get(path);
+        if revision > since {
+            Err(MemDbError::Changed {
+                path: path.to_owned(),
+                revision,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Replaces the whole document as a single write, like an `add` to the
+    /// root but without converting it from a `Value`.
+    pub fn replace_root(&mut self, root: Node) {
+        let prev_value = std::mem::replace(&mut self.root, root);
+        self.mark_changed(vec![JsonPointer::root()]);
+        self.end_write();
+        self.record_history(vec![UndoCommand::ReplaceRoot { prev_value }], None);
+    }
+
+    /// Stamps `paths`, which have just been changed, with the revision of the
+    /// running write.
+    fn mark_changed(&mut self, paths: Vec<JsonPointer>) {
+        for path in paths {
+            let exists = self.root.locate(&path).is_some();
+            self.revisions.set(path.as_ref(), self.revision + 1, exists);
+            self.writing = true;
+        }
+    }
+
+    /// Marks the current point of the running write, see
+    /// [`MemDb::roll_back`].
+    pub(crate) fn write_mark(&self) -> WriteMark {
+        WriteMark {
+            revisions: self.revisions.clone(),
+            writing: self.writing,
+        }
+    }
+
+    /// Reverts the changes made by the running write since `mark` was taken,
+    /// with `undo_commands` in the order the changes were made.
+    pub(crate) fn roll_back(
+        &mut self,
+        undo_commands: impl DoubleEndedIterator<Item = UndoCommand>,
+        mark: WriteMark,
+    ) {
+        for undo_command in undo_commands.rev() {
+            undo_command.execute(&mut self.root);
+        }
+        self.revisions = mark.revisions;
+        self.writing = mark.writing;
+    }
+
+    /// Ends a write, bumping the revision if it has left the document
+    /// changed.
+    pub(crate) fn end_write(&mut self) {
+        if self.writing {
+            self.revision += 1;
+            self.writing = false;
+        }
+    }
+
+    /// Starts a transaction, whose changes are applied right away and rolled
+    /// back unless it is committed.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
     pub fn patch(
         &mut self,
         prefix: Option<&JsonPointer>,
         commands: Vec<JsonPatch>,
     ) -> Result<(), MemDbError> {
-        let mut commands = commands;
-        let mut undo_commands = Vec::new();
-
-        match self.patch_all(&mut undo_commands, prefix, &mut commands) {
-            Ok(()) => Ok(()),
-            Err(err) => {
-                for undo_command in undo_commands.into_iter().rev() {
-                    undo_command.execute(&mut self.root);
+        let mut transaction = self.transaction();
+        transaction.patch(prefix, commands)?;
+        transaction.commit();
+        Ok(())
+    }
+
+    /// Like [`MemDb::patch`], but also returns what each operation changed.
+    ///
+    /// The effects share the values involved with the document, so this
+    /// costs little more than a plain patch.
+    pub fn patch_with_effects(
+        &mut self,
+        prefix: Option<&JsonPointer>,
+        commands: Vec<JsonPatch>,
+    ) -> Result<Vec<Effect>, MemDbError> {
+        let mut transaction = self.transaction();
+        let effects = transaction.patch_with_effects(prefix, commands)?;
+        transaction.commit();
+        Ok(effects)
+    }
+
+    /// Starts to record the changes below `scope`, keeping up to `limit` of
+    /// them for [`MemDb::undo`]. An existing history for `scope` is cleared.
+    ///
+    /// Every change of the subtree is recorded, whether it was made through
+    /// the history or not. A change which touches the subtree as well as
+    /// other parts of the document can not be undone for the subtree alone,
+    /// so it clears the history instead.
+    pub fn enable_history(&mut self, scope: JsonPointer, limit: usize) {
+        self.histories.insert(scope, History::new(limit));
+    }
+
+    pub fn disable_history(&mut self, scope: &JsonPointer) {
+        self.histories.remove(scope);
+    }
+
+    pub fn can_undo(&self, scope: &JsonPointer) -> bool {
+        self.histories
+            .get(scope)
+            .is_some_and(|history| !history.undo.is_empty())
+    }
+
+    pub fn can_redo(&self, scope: &JsonPointer) -> bool {
+        self.histories
+            .get(scope)
+            .is_some_and(|history| !history.redo.is_empty())
+    }
+
+    /// Reverts the latest change below `scope` and returns the patch which
+    /// did it, or `None` if there is nothing to undo.
+    ///
+    /// The patch is relative to the root and can be applied to a copy of the
+    /// document to make the same change.
+    pub fn undo(&mut self, scope: &JsonPointer) -> Result<Option<Vec<JsonPatch>>, MemDbError> {
+        let history = self.history_mut(scope)?;
+        let undo_commands = match history.undo.pop_back() {
+            Some(undo_commands) => undo_commands,
+            None => return Ok(None),
+        };
+
+        let res = self.revert(scope, undo_commands);
+        self.end_write();
+        let (patch, redo_commands) = res?;
+        if self.histories.len() > 1 {
+            self.record_history(redo_commands.clone(), Some(scope));
+        }
+        self.history_mut(scope)?.redo.push(redo_commands);
+        Ok(Some(patch))
+    }
+
+    /// Makes the latest change reverted by [`MemDb::undo`] again, see there.
+    pub fn redo(&mut self, scope: &JsonPointer) -> Result<Option<Vec<JsonPatch>>, MemDbError> {
+        let history = self.history_mut(scope)?;
+        let redo_commands = match history.redo.pop() {
+            Some(redo_commands) => redo_commands,
+            None => return Ok(None),
+        };
+
+        let res = self.revert(scope, redo_commands);
+        self.end_write();
+        let (patch, undo_commands) = res?;
+        if self.histories.len() > 1 {
+            self.record_history(undo_commands.clone(), Some(scope));
+        }
+        self.history_mut(scope)?.push_undo(undo_commands);
+        Ok(Some(patch))
+    }
+
+    fn history_mut(&mut self, scope: &JsonPointer) -> Result<&mut History, MemDbError> {
+        self.histories
+            .get_mut(scope)
+            .ok_or_else(|| MemDbError::NoHistory {
+                path: scope.clone(),
+            })
+    }
+
+    /// Applies the patches reverting `undo_commands`, returning them together
+    /// with the undo commands reverting them in turn.
+    fn revert(
+        &mut self,
+        scope: &JsonPointer,
+        undo_commands: Vec<UndoCommand>,
+    ) -> Result<(Vec<JsonPatch>, Vec<UndoCommand>), MemDbError> {
+        let mark = self.write_mark();
+        let mut patch = Vec::new();
+        let mut reverted = Vec::new();
+        for undo_command in undo_commands.into_iter().rev() {
+            for mut command in undo_command.into_patch(&self.root) {
+                patch.push(command.clone());
+                if let Err(err) = self.patch_command(&mut reverted, None, &mut command) {
+                    self.roll_back(reverted.into_iter(), mark);
+                    // the history does not match the document
+                    self.history_mut(scope)?.clear();
+                    return Err(err);
                 }
-                Err(err)
             }
         }
+        Ok((patch, reverted))
     }
 
-    fn patch_all<'a>(
+    /// Records a committed change in the histories, except in the one for
+    /// `skip`.
+    pub(crate) fn record_history(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        prefix: Option<&'a JsonPointer>,
-        commands: &'a mut [JsonPatch],
+        undo_commands: Vec<UndoCommand>,
+        skip: Option<&JsonPointer>,
+    ) {
+        if self.histories.is_empty() || undo_commands.is_empty() {
+            return;
+        }
+
+        let changed_paths = undo_commands
+            .iter()
+            .flat_map(UndoCommand::changed_paths)
+            .collect::<Vec<_>>();
+        let mut recording = Vec::new();
+        for (scope, history) in &mut self.histories {
+            if Some(scope) == skip {
+                continue;
+            }
+            if changed_paths
+                .iter()
+                .all(|path| path.starts_with(scope.as_ref()))
+            {
+                recording.push(history);
+            } else if changed_paths
+                .iter()
+                .any(|path| path.starts_with(scope.as_ref()) || scope.starts_with(path.as_ref()))
+            {
+                history.clear();
+            }
+        }
+
+        if let Some(last) = recording.pop() {
+            for history in recording {
+                history.record(undo_commands.clone());
+            }
+            last.record(undo_commands);
+        }
+    }
+
+    pub(crate) fn patch_all(
+        &mut self,
+        undo_commands: &mut Vec<UndoCommand>,
+        prefix: Option<&JsonPointer>,
+        commands: &mut [JsonPatch],
     ) -> Result<(), MemDbError> {
         for command in commands {
             self.patch_command(undo_commands, prefix, command)?;
@@ -65,17 +373,18 @@ impl MemDb {
         Ok(())
     }
 
-    fn patch_command<'a>(
+    pub(crate) fn patch_command(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        prefix: Option<&'a JsonPointer>,
-        command: &'a mut JsonPatch,
+        undo_commands: &mut Vec<UndoCommand>,
+        prefix: Option<&JsonPointer>,
+        command: &mut JsonPatch,
     ) -> Result<(), MemDbError> {
+        let len = undo_commands.len();
         match command {
             JsonPatch::Add { path, value } => self.patch_command_add(
                 undo_commands,
                 path.with_prefix_opt(prefix),
-                std::mem::take(value),
+                Node::from(std::mem::take(value)),
             ),
             JsonPatch::Remove { path } => {
                 self.patch_command_remove(undo_commands, path.with_prefix_opt(prefix))
@@ -83,7 +392,7 @@ impl MemDb {
             JsonPatch::Replace { path, value } => self.patch_command_replace(
                 undo_commands,
                 path.with_prefix_opt(prefix),
-                std::mem::take(value),
+                Node::from(std::mem::take(value)),
             ),
             JsonPatch::Move { from, path } => self.patch_command_move(
                 undo_commands,
@@ -98,39 +407,48 @@ impl MemDb {
             JsonPatch::Test { path, value } => {
                 self.patch_command_test(path.with_prefix_opt(prefix), value)
             }
-        }
+        }?;
+        let paths = undo_commands[len..]
+            .iter()
+            .flat_map(UndoCommand::changed_paths)
+            .collect();
+        self.mark_changed(paths);
+        Ok(())
     }
 
-    fn patch_command_add<'a>(
+    fn patch_command_add(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        path: JsonPointerRef<'a>,
-        value: Value,
+        undo_commands: &mut Vec<UndoCommand>,
+        path: JsonPointerRef<'_>,
+        value: Node,
     ) -> Result<(), MemDbError> {
         match path.split_last() {
             Some((parent_path, key)) => {
-                let parent =
-                    self.root
-                        .locate_mut(parent_path)
-                        .ok_or_else(|| MemDbError::PathNotFound {
-                            path: parent_path.to_owned(),
-                        })?;
+                let parent = self.root_mut().locate_mut(parent_path).ok_or_else(|| {
+                    MemDbError::PathNotFound {
+                        path: parent_path.to_owned(),
+                    }
+                })?;
                 match parent {
-                    Value::Object(obj) => {
-                        let prev_value = obj.insert(key.to_string(), value);
+                    Node::Object(obj) => {
+                        let obj = Arc::make_mut(obj);
+                        let prev_value = obj.insert(key.into(), value);
                         undo_commands.push(UndoCommand::Add {
                             target: UpdateTarget::Object {
-                                path: parent_path,
-                                key,
+                                path: parent_path.to_owned(),
+                                key: key.into(),
                             },
                             prev_value,
                         });
                     }
-                    Value::Array(array) => {
+                    Node::Array(array) => {
+                        let array = Arc::make_mut(array);
                         if key == "-" {
                             array.push(value);
                             undo_commands.push(UndoCommand::Add {
-                                target: UpdateTarget::ArrayAppend { path: parent_path },
+                                target: UpdateTarget::ArrayAppend {
+                                    path: parent_path.to_owned(),
+                                },
                                 prev_value: None,
                             });
                         } else {
@@ -145,7 +463,7 @@ impl MemDb {
                             array.insert(index, value);
                             undo_commands.push(UndoCommand::Add {
                                 target: UpdateTarget::ArrayInsert {
-                                    path: parent_path,
+                                    path: parent_path.to_owned(),
                                     index,
                                 },
                                 prev_value: None,
@@ -160,7 +478,7 @@ impl MemDb {
                 }
             }
             None => {
-                let prev_value = std::mem::replace(&mut self.root, value);
+                let prev_value = std::mem::replace(self.root_mut(), value);
                 undo_commands.push(UndoCommand::ReplaceRoot { prev_value });
             }
         }
@@ -168,33 +486,35 @@ impl MemDb {
         Ok(())
     }
 
-    fn patch_command_remove<'a>(
+    fn patch_command_remove(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        path: JsonPointerRef<'a>,
+        undo_commands: &mut Vec<UndoCommand>,
+        path: JsonPointerRef<'_>,
     ) -> Result<(), MemDbError> {
         let (parent_path, key) = path.split_last().ok_or(MemDbError::EmptyPath)?;
-        let parent = self
-            .root
-            .locate_mut(parent_path)
-            .ok_or_else(|| MemDbError::PathNotFound {
-                path: parent_path.to_owned(),
-            })?;
+        let parent =
+            self.root_mut()
+                .locate_mut(parent_path)
+                .ok_or_else(|| MemDbError::PathNotFound {
+                    path: parent_path.to_owned(),
+                })?;
 
         match parent {
-            Value::Object(obj) => {
+            Node::Object(obj) => {
+                let obj = Arc::make_mut(obj);
                 let prev_value = obj.remove(key).ok_or_else(|| MemDbError::PathNotFound {
                     path: path.to_owned(),
                 })?;
                 undo_commands.push(UndoCommand::Remove {
                     source: UpdateSource::Object {
-                        path: parent_path,
-                        key,
+                        path: parent_path.to_owned(),
+                        key: key.into(),
                     },
                     prev_value,
                 });
             }
-            Value::Array(array) => {
+            Node::Array(array) => {
+                let array = Arc::make_mut(array);
                 let index = key
                     .parse::<usize>()
                     .ok()
@@ -206,7 +526,7 @@ impl MemDb {
                 let prev_value = array.remove(index);
                 undo_commands.push(UndoCommand::Remove {
                     source: UpdateSource::Array {
-                        path: parent_path,
+                        path: parent_path.to_owned(),
                         index,
                     },
                     prev_value,
@@ -222,54 +542,55 @@ impl MemDb {
         Ok(())
     }
 
-    fn patch_command_replace<'a>(
+    fn patch_command_replace(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        path: JsonPointerRef<'a>,
-        value: Value,
+        undo_commands: &mut Vec<UndoCommand>,
+        path: JsonPointerRef<'_>,
+        value: Node,
     ) -> Result<(), MemDbError> {
-        let prev_value = self
-            .root
-            .locate_mut(path)
-            .ok_or_else(|| MemDbError::PathNotFound {
-                path: path.to_owned(),
-            })?;
+        let prev_value =
+            self.root_mut()
+                .locate_mut(path)
+                .ok_or_else(|| MemDbError::PathNotFound {
+                    path: path.to_owned(),
+                })?;
         undo_commands.push(UndoCommand::Replace {
-            path,
+            path: path.to_owned(),
             prev_value: std::mem::replace(prev_value, value),
         });
         Ok(())
     }
 
-    fn patch_command_move<'a>(
+    fn patch_command_move(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        from: JsonPointerRef<'a>,
-        path: JsonPointerRef<'a>,
+        undo_commands: &mut Vec<UndoCommand>,
+        from: JsonPointerRef<'_>,
+        path: JsonPointerRef<'_>,
     ) -> Result<(), MemDbError> {
         let (parent_path, key) = from.split_last().ok_or(MemDbError::EmptyPath)?;
 
         let (source, value) = {
-            let parent =
-                self.root
-                    .locate_mut(parent_path)
-                    .ok_or_else(|| MemDbError::PathNotFound {
-                        path: parent_path.to_owned(),
-                    })?;
+            let parent = self.root_mut().locate_mut(parent_path).ok_or_else(|| {
+                MemDbError::PathNotFound {
+                    path: parent_path.to_owned(),
+                }
+            })?;
             match parent {
-                Value::Object(obj) => {
+                Node::Object(obj) => {
+                    let obj = Arc::make_mut(obj);
                     let value = obj.remove(key).ok_or_else(|| MemDbError::PathNotFound {
                         path: path.to_owned(),
                     })?;
                     (
                         UpdateSource::Object {
-                            path: parent_path,
-                            key,
+                            path: parent_path.to_owned(),
+                            key: key.into(),
                         },
                         value,
                     )
                 }
-                Value::Array(array) => {
+                Node::Array(array) => {
+                    let array = Arc::make_mut(array);
                     let index = key
                         .parse::<usize>()
                         .ok()
@@ -281,7 +602,7 @@ impl MemDb {
                     let value = array.remove(index);
                     (
                         UpdateSource::Array {
-                            path: parent_path,
+                            path: parent_path.to_owned(),
                             index,
                         },
                         value,
@@ -297,27 +618,33 @@ impl MemDb {
 
         match path.split_last() {
             Some((parent_path, key)) => {
-                let parent =
-                    self.root
-                        .locate_mut(parent_path)
-                        .ok_or_else(|| MemDbError::PathNotFound {
-                            path: parent_path.to_owned(),
-                        })?;
+                let parent = self.root_mut().locate_mut(parent_path).ok_or_else(|| {
+                    MemDbError::PathNotFound {
+                        path: parent_path.to_owned(),
+                    }
+                })?;
                 let (target, prev_value) = match parent {
-                    Value::Object(obj) => {
-                        let prev_value = obj.insert(key.to_string(), value);
+                    Node::Object(obj) => {
+                        let obj = Arc::make_mut(obj);
+                        let prev_value = obj.insert(key.into(), value);
                         (
                             UpdateTarget::Object {
-                                path: parent_path,
-                                key,
+                                path: parent_path.to_owned(),
+                                key: key.into(),
                             },
                             prev_value,
                         )
                     }
-                    Value::Array(array) => {
+                    Node::Array(array) => {
+                        let array = Arc::make_mut(array);
                         if key == "-" {
                             array.push(value);
-                            (UpdateTarget::ArrayAppend { path: parent_path }, None)
+                            (
+                                UpdateTarget::ArrayAppend {
+                                    path: parent_path.to_owned(),
+                                },
+                                None,
+                            )
                         } else {
                             let index = key
                                 .parse::<usize>()
@@ -330,7 +657,7 @@ impl MemDb {
                             array.insert(index, value);
                             (
                                 UpdateTarget::ArrayInsert {
-                                    path: parent_path,
+                                    path: parent_path.to_owned(),
                                     index,
                                 },
                                 None,
@@ -351,7 +678,7 @@ impl MemDb {
                 });
             }
             None => {
-                let prev_value = std::mem::replace(&mut self.root, value);
+                let prev_value = std::mem::replace(self.root_mut(), value);
                 undo_commands.push(UndoCommand::MoveToRoot { source, prev_value });
             }
         }
@@ -359,29 +686,29 @@ impl MemDb {
         Ok(())
     }
 
-    fn patch_command_copy<'a>(
+    fn patch_command_copy(
         &mut self,
-        undo_commands: &mut Vec<UndoCommand<'a>>,
-        from: JsonPointerRef<'a>,
-        path: JsonPointerRef<'a>,
+        undo_commands: &mut Vec<UndoCommand>,
+        from: JsonPointerRef<'_>,
+        path: JsonPointerRef<'_>,
     ) -> Result<(), MemDbError> {
         let (parent_path, key) = from.split_last().ok_or(MemDbError::EmptyPath)?;
 
         let value = {
-            let parent =
-                self.root
-                    .locate_mut(parent_path)
-                    .ok_or_else(|| MemDbError::PathNotFound {
-                        path: parent_path.to_owned(),
-                    })?;
+            let parent = self
+                .root
+                .locate(parent_path)
+                .ok_or_else(|| MemDbError::PathNotFound {
+                    path: parent_path.to_owned(),
+                })?;
             match parent {
-                Value::Object(obj) => obj
+                Node::Object(obj) => obj
                     .get(key)
                     .ok_or_else(|| MemDbError::PathNotFound {
                         path: path.to_owned(),
                     })
                     .cloned()?,
-                Value::Array(array) => {
+                Node::Array(array) => {
                     let index = key
                         .parse::<usize>()
                         .ok()
@@ -402,27 +729,33 @@ impl MemDb {
 
         match path.split_last() {
             Some((parent_path, key)) => {
-                let parent =
-                    self.root
-                        .locate_mut(parent_path)
-                        .ok_or_else(|| MemDbError::PathNotFound {
-                            path: parent_path.to_owned(),
-                        })?;
+                let parent = self.root_mut().locate_mut(parent_path).ok_or_else(|| {
+                    MemDbError::PathNotFound {
+                        path: parent_path.to_owned(),
+                    }
+                })?;
                 let (target, prev_value) = match parent {
-                    Value::Object(obj) => {
-                        let prev_value = obj.insert(key.to_string(), value);
+                    Node::Object(obj) => {
+                        let obj = Arc::make_mut(obj);
+                        let prev_value = obj.insert(key.into(), value);
                         (
                             UpdateTarget::Object {
-                                path: parent_path,
-                                key,
+                                path: parent_path.to_owned(),
+                                key: key.into(),
                             },
                             prev_value,
                         )
                     }
-                    Value::Array(array) => {
+                    Node::Array(array) => {
+                        let array = Arc::make_mut(array);
                         if key == "-" {
                             array.push(value);
-                            (UpdateTarget::ArrayAppend { path: parent_path }, None)
+                            (
+                                UpdateTarget::ArrayAppend {
+                                    path: parent_path.to_owned(),
+                                },
+                                None,
+                            )
                         } else {
                             let index = key
                                 .parse::<usize>()
@@ -435,7 +768,7 @@ impl MemDb {
                             array.insert(index, value);
                             (
                                 UpdateTarget::ArrayInsert {
-                                    path: parent_path,
+                                    path: parent_path.to_owned(),
                                     index,
                                 },
                                 None,
@@ -452,7 +785,7 @@ impl MemDb {
                 undo_commands.push(UndoCommand::Copy { target, prev_value });
             }
             None => {
-                let prev_value = std::mem::replace(&mut self.root, value);
+                let prev_value = std::mem::replace(self.root_mut(), value);
                 undo_commands.push(UndoCommand::CopyToRoot { prev_value });
             }
         }
@@ -460,12 +793,16 @@ impl MemDb {
         Ok(())
     }
 
-    fn patch_command_test<'a>(
+    fn patch_command_test(
         &mut self,
-        path: JsonPointerRef<'a>,
+        path: JsonPointerRef<'_>,
         value: &Value,
     ) -> Result<(), MemDbError> {
-        if self.root.locate(path).unwrap_or(&Value::Null) != value {
+        let equal = match self.root.locate(path) {
+            Some(node) => node == value,
+            None => value.is_null(),
+        };
+        if !equal {
             Err(MemDbError::TestFailed)
         } else {
             Ok(())
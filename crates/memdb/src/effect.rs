@@ -0,0 +1,158 @@
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use serde::Serialize;
+
+use crate::Node;
+
+/// What a single patch operation changed, see [`MemDb::patch_with_effects`].
+///
+/// Paths are absolute and array indices resolved, so an append to `/a/-`
+/// is reported at the index it landed at. The values are shared with the
+/// document, so collecting them does not copy anything.
+///
+/// [`MemDb::patch_with_effects`]: crate::MemDb::patch_with_effects
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Effect {
+    /// `value` was added, replacing `prev_value` if the path was an existing
+    /// member or the root.
+    Add {
+        path: JsonPointer,
+        value: Node,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prev_value: Option<Node>,
+    },
+    Remove {
+        path: JsonPointer,
+        prev_value: Node,
+    },
+    Replace {
+        path: JsonPointer,
+        value: Node,
+        prev_value: Node,
+    },
+    /// `value` was moved, replacing `prev_value` like an add.
+    Move {
+        from: JsonPointer,
+        path: JsonPointer,
+        value: Node,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prev_value: Option<Node>,
+    },
+    /// `value` was copied, replacing `prev_value` like an add.
+    Copy {
+        from: JsonPointer,
+        path: JsonPointer,
+        value: Node,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prev_value: Option<Node>,
+    },
+}
+
+impl Effect {
+    /// Returns the operation which makes the same change to a copy of the
+    /// document as it was before, copying the value it adds.
+    pub fn into_patch(self) -> JsonPatch {
+        match self {
+            Effect::Add { path, value, .. } => JsonPatch::Add {
+                path,
+                value: value.to_value(),
+            },
+            Effect::Remove { path, .. } => JsonPatch::Remove { path },
+            Effect::Replace { path, value, .. } => JsonPatch::Replace {
+                path,
+                value: value.to_value(),
+            },
+            Effect::Move { from, path, .. } => JsonPatch::Move { from, path },
+            Effect::Copy { from, path, .. } => JsonPatch::Copy { from, path },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use super::*;
+    use crate::MemDb;
+
+    #[test]
+    fn test_patch_with_effects() {
+        let before = json!({ "a": { "items": [1] }, "b": 2 });
+        let mut db = MemDb::new(before.clone());
+        let prefix = json_pointer!("/a");
+        let effects = db
+            .patch_with_effects(
+                Some(&prefix),
+                vec![
+                    JsonPatch::Add {
+                        path: json_pointer!("/items/-"),
+                        value: json!(3),
+                    },
+                    JsonPatch::Test {
+                        path: json_pointer!("/items/1"),
+                        value: json!(3),
+                    },
+                    JsonPatch::Move {
+                        from: json_pointer!("/items/0"),
+                        path: json_pointer!("/first"),
+                    },
+                    JsonPatch::Copy {
+                        from: json_pointer!("/first"),
+                        path: json_pointer!("/items/0"),
+                    },
+                    JsonPatch::Replace {
+                        path: json_pointer!("/first"),
+                        value: json!(4),
+                    },
+                    JsonPatch::Remove {
+                        path: json_pointer!("/items/1"),
+                    },
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            effects,
+            vec![
+                Effect::Add {
+                    path: json_pointer!("/a/items/1"),
+                    value: json!(3).into(),
+                    prev_value: None,
+                },
+                Effect::Move {
+                    from: json_pointer!("/a/items/0"),
+                    path: json_pointer!("/a/first"),
+                    value: json!(1).into(),
+                    prev_value: None,
+                },
+                Effect::Copy {
+                    from: json_pointer!("/a/first"),
+                    path: json_pointer!("/a/items/0"),
+                    value: json!(1).into(),
+                    prev_value: None,
+                },
+                Effect::Replace {
+                    path: json_pointer!("/a/first"),
+                    value: json!(4).into(),
+                    prev_value: json!(1).into(),
+                },
+                Effect::Remove {
+                    path: json_pointer!("/a/items/1"),
+                    prev_value: json!(3).into(),
+                },
+            ]
+        );
+
+        // the effects replay the change without a prefix
+        let mut copy = MemDb::new(before);
+        copy.patch(None, effects.into_iter().map(Effect::into_patch).collect())
+            .unwrap();
+        assert_eq!(copy.root(), db.root());
+        assert_eq!(
+            db.root(),
+            json!({ "a": { "items": [1], "first": 4 }, "b": 2 })
+        );
+    }
+}
@@ -12,4 +12,10 @@ pub enum MemDbError {
     EmptyPath,
     #[error("test failed")]
     TestFailed,
+    #[error("savepoint has been rolled back or released")]
+    InvalidSavepoint,
+    #[error("no undo history for: {path}")]
+    NoHistory { path: JsonPointer },
+    #[error("changed at revision {revision}: {path}")]
+    Changed { path: JsonPointer, revision: u64 },
 }
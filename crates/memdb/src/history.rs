@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+use crate::undo_command::UndoCommand;
+
+/// The changes to a subtree which can be undone and redone, see
+/// [`MemDb::enable_history`](crate::MemDb::enable_history).
+#[derive(Debug)]
+pub(crate) struct History {
+    limit: usize,
+    /// Oldest first, each entry reverts one change.
+    pub(crate) undo: VecDeque<Vec<UndoCommand>>,
+    /// Most recently undone last, each entry reverts one undo.
+    pub(crate) redo: Vec<Vec<UndoCommand>>,
+}
+
+impl History {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    /// Records a new change, which can no longer be followed by a redo.
+    pub(crate) fn record(&mut self, undo_commands: Vec<UndoCommand>) {
+        self.push_undo(undo_commands);
+        self.redo.clear();
+    }
+
+    pub(crate) fn push_undo(&mut self, undo_commands: Vec<UndoCommand>) {
+        self.undo.push_back(undo_commands);
+        if self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_patch::JsonPatch;
+    use json_pointer::{json_pointer, JsonPointer};
+    use serde_json::{json, Value};
+
+    use crate::{MemDb, MemDbError};
+
+    fn add(path: &str, value: Value) -> Vec<JsonPatch> {
+        vec![JsonPatch::Add {
+            path: json_pointer!(path),
+            value,
+        }]
+    }
+
+    #[test]
+    fn test_history() {
+        let root = JsonPointer::root();
+        let items = json_pointer!("/items");
+        let mut db = MemDb::new(json!({ "items": [], "title": "a" }));
+        db.enable_history(root.clone(), 3);
+        db.enable_history(items.clone(), 10);
+        assert!(matches!(
+            db.undo(&json_pointer!("/title")),
+            Err(MemDbError::NoHistory { .. })
+        ));
+
+        db.patch(None, add("/items/-", json!(1))).unwrap();
+        db.patch(None, add("/items/-", json!(2))).unwrap();
+        db.patch(
+            None,
+            vec![JsonPatch::Move {
+                from: json_pointer!("/items/0"),
+                path: json_pointer!("/items/-"),
+            }],
+        )
+        .unwrap();
+        db.patch(None, add("/title", json!("b"))).unwrap();
+        assert_eq!(db.root(), json!({ "items": [2, 1], "title": "b" }));
+
+        // the patches make the same change to a copy of the document
+        let mut copy = MemDb::from(db.snapshot());
+        let patch = db.undo(&items).unwrap().unwrap();
+        copy.patch(None, patch).unwrap();
+        assert_eq!(db.root(), json!({ "items": [1, 2], "title": "b" }));
+        assert_eq!(copy.root(), db.root());
+
+        db.undo(&items).unwrap().unwrap();
+        db.redo(&items).unwrap().unwrap();
+        assert_eq!(db.root(), json!({ "items": [1, 2], "title": "b" }));
+        assert!(db.can_redo(&items));
+
+        // the root history sees the undo and redo as changes, and keeps the
+        // latest three
+        db.undo(&root).unwrap().unwrap();
+        db.undo(&root).unwrap().unwrap();
+        db.undo(&root).unwrap().unwrap();
+        assert_eq!(db.root(), json!({ "items": [2, 1], "title": "b" }));
+        assert_eq!(db.undo(&root).unwrap(), None);
+
+        // a change of the whole document can not be undone for a subtree
+        assert!(db.can_undo(&items));
+        db.patch(None, add("", json!({ "items": [] }))).unwrap();
+        assert!(!db.can_undo(&items));
+        assert!(!db.can_redo(&items));
+
+        // a new change drops the undone ones
+        db.undo(&root).unwrap().unwrap();
+        assert!(db.can_redo(&root));
+        db.patch(None, add("/title", json!("c"))).unwrap();
+        assert!(!db.can_redo(&root));
+
+        // a transaction is undone as a whole
+        let mut txn = db.transaction();
+        txn.patch(None, add("/items/-", json!(3))).unwrap();
+        txn.patch(None, add("/items/-", json!(4))).unwrap();
+        txn.commit();
+        db.undo(&items).unwrap().unwrap();
+        assert_eq!(db.root(), json!({ "items": [2, 1], "title": "c" }));
+    }
+}
@@ -1,7 +1,15 @@
 mod db;
+mod effect;
 mod error;
+mod history;
+mod node;
+mod revision;
+mod transaction;
 mod undo_command;
 
 pub use db::MemDb;
+pub use effect::Effect;
 pub use error::MemDbError;
+pub use node::{Node, NodeMap};
+pub use transaction::{Savepoint, Transaction};
 pub use undo_command::{UpdateSource, UpdateTarget};
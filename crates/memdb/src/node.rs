@@ -0,0 +1,250 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+};
+
+use json_pointer::ToJsonPointerRef;
+use serde::{
+    ser::SerializeMap, ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::{Number, Value};
+
+/// The members of a [`Node::Object`], sorted by key like a
+/// `serde_json::Map`.
+pub type NodeMap = BTreeMap<Arc<str>, Node>;
+
+/// An immutable JSON value whose arrays and objects are shared between
+/// clones, so cloning a node is O(1).
+///
+/// Changing a node through [`Node::locate_mut`] copies only the containers
+/// on the path to it which are shared with another clone, the rest of the
+/// tree stays shared. This makes [`MemDb::snapshot`](crate::MemDb::snapshot)
+/// cheap even while the document keeps changing.
+#[derive(Clone, Default, PartialEq)]
+pub enum Node {
+    #[default]
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(Arc<str>),
+    Array(Arc<Vec<Node>>),
+    Object(Arc<NodeMap>),
+}
+
+impl Node {
+    pub fn locate(&self, pointer: impl ToJsonPointerRef) -> Option<&Node> {
+        pointer
+            .to_json_pointer_ref()
+            .iter()
+            .try_fold(self, |node, segment| match node {
+                Node::Object(obj) => obj.get(segment.as_str()),
+                Node::Array(array) => array.get(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    /// Returns the node at `pointer` for changing it, first copying the
+    /// containers on the way which are shared with other clones.
+    pub fn locate_mut(&mut self, pointer: impl ToJsonPointerRef) -> Option<&mut Node> {
+        pointer
+            .to_json_pointer_ref()
+            .iter()
+            .try_fold(self, |node, segment| match node {
+                Node::Object(obj) => Arc::make_mut(obj).get_mut(segment.as_str()),
+                Node::Array(array) => Arc::make_mut(array).get_mut(segment.parse::<usize>().ok()?),
+                _ => None,
+            })
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Node::Null)
+    }
+
+    pub fn as_array(&self) -> Option<&[Node]> {
+        match self {
+            Node::Array(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&NodeMap> {
+        match self {
+            Node::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// Returns the members of an object for changing them, copying them
+    /// first if they are shared.
+    pub fn object_mut(&mut self) -> Option<&mut NodeMap> {
+        match self {
+            Node::Object(obj) => Some(Arc::make_mut(obj)),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements of an array for changing them, copying them
+    /// first if they are shared.
+    pub fn array_mut(&mut self) -> Option<&mut Vec<Node>> {
+        match self {
+            Node::Array(array) => Some(Arc::make_mut(array)),
+            _ => None,
+        }
+    }
+
+    /// Copies the node into a `serde_json::Value`.
+    pub fn to_value(&self) -> Value {
+        match self {
+            Node::Null => Value::Null,
+            Node::Bool(b) => Value::Bool(*b),
+            Node::Number(n) => Value::Number(n.clone()),
+            Node::String(s) => Value::String(s.to_string()),
+            Node::Array(array) => Value::Array(array.iter().map(Node::to_value).collect()),
+            Node::Object(obj) => Value::Object(
+                obj.iter()
+                    .map(|(key, node)| (key.to_string(), node.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<Value> for Node {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Node::Null,
+            Value::Bool(b) => Node::Bool(b),
+            Value::Number(n) => Node::Number(n),
+            Value::String(s) => Node::String(s.into()),
+            Value::Array(array) => {
+                Node::Array(Arc::new(array.into_iter().map(Node::from).collect()))
+            }
+            Value::Object(obj) => Node::Object(Arc::new(
+                obj.into_iter()
+                    .map(|(key, value)| (key.into(), Node::from(value)))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+impl From<&Node> for Value {
+    fn from(node: &Node) -> Self {
+        node.to_value()
+    }
+}
+
+impl From<Node> for Value {
+    fn from(node: Node) -> Self {
+        node.to_value()
+    }
+}
+
+impl PartialEq<Value> for Node {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Node::Null, Value::Null) => true,
+            (Node::Bool(a), Value::Bool(b)) => a == b,
+            (Node::Number(a), Value::Number(b)) => a == b,
+            (Node::String(a), Value::String(b)) => **a == **b,
+            (Node::Array(a), Value::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a == b)
+            }
+            (Node::Object(a), Value::Object(b)) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .all(|(key, a)| b.get(&**key).is_some_and(|b| a == b))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq<Node> for Value {
+    fn eq(&self, other: &Node) -> bool {
+        other == self
+    }
+}
+
+impl Debug for Node {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Null => f.write_str("Null"),
+            Node::Bool(b) => write!(f, "Bool({})", b),
+            Node::Number(n) => write!(f, "Number({})", n),
+            Node::String(s) => write!(f, "String({:?})", s),
+            Node::Array(array) => {
+                f.write_str("Array ")?;
+                f.debug_list().entries(array.iter()).finish()
+            }
+            Node::Object(obj) => {
+                f.write_str("Object ")?;
+                f.debug_map().entries(obj.iter()).finish()
+            }
+        }
+    }
+}
+
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Node::Null => serializer.serialize_unit(),
+            Node::Bool(b) => serializer.serialize_bool(*b),
+            Node::Number(n) => n.serialize(serializer),
+            Node::String(s) => serializer.serialize_str(s),
+            Node::Array(array) => {
+                let mut seq = serializer.serialize_seq(Some(array.len()))?;
+                for node in array.iter() {
+                    seq.serialize_element(node)?;
+                }
+                seq.end()
+            }
+            Node::Object(obj) => {
+                let mut map = serializer.serialize_map(Some(obj.len()))?;
+                for (key, node) in obj.iter() {
+                    map.serialize_entry(&**key, node)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(Node::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_structural_sharing() {
+        let value = json!({ "a": { "b": [1, 2] }, "c": { "d": "e" } });
+        let mut node = Node::from(value.clone());
+        assert_eq!(node, value);
+        assert_eq!(node.to_value(), value);
+        assert_eq!(serde_json::to_value(&node).unwrap(), value);
+
+        let snapshot = node.clone();
+        *node.locate_mut(json_pointer!("/a/b/0")).unwrap() = Node::Bool(true);
+        assert_eq!(snapshot, value);
+        assert_eq!(node, json!({ "a": { "b": [true, 2] }, "c": { "d": "e" } }));
+
+        // only the path to the change has been copied
+        let (Node::Object(before), Node::Object(after)) = (&snapshot, &node) else {
+            unreachable!()
+        };
+        assert!(!Arc::ptr_eq(before, after));
+        match (&before["c"], &after["c"]) {
+            (Node::Object(before), Node::Object(after)) => assert!(Arc::ptr_eq(before, after)),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, sync::Arc};
+
+use json_pointer::JsonPointerRef;
+
+/// The revisions at which the paths of a document last changed, see
+/// [`MemDb::revision_of`](crate::MemDb::revision_of).
+///
+/// Only the paths which changed have an entry, every other path shares the
+/// revision of its nearest ancestor which was replaced as a whole.
+///
+/// Like a [`Node`](crate::Node), clones share the entries below them, so a
+/// write can keep the revisions from before it to restore on rollback.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Revisions {
+    /// The latest revision which replaced the value at this path.
+    replaced: u64,
+    /// The latest revision which changed anything at or below this path.
+    changed: u64,
+    /// The latest revision which removed a child, whose entry is dropped to
+    /// keep the tree as small as the document.
+    removed: u64,
+    children: Arc<HashMap<String, Revisions>>,
+}
+
+impl Revisions {
+    pub(crate) fn get(&self, path: JsonPointerRef<'_>) -> u64 {
+        let mut revision = self.replaced;
+        let mut entry = self;
+        for segment in path.iter() {
+            match entry.children.get(segment.as_str()) {
+                Some(child) => {
+                    revision = revision.max(child.replaced);
+                    entry = child;
+                }
+                None => return revision.max(entry.removed),
+            }
+        }
+        revision.max(entry.changed)
+    }
+
+    /// Records that the value at `path` was replaced, or removed if it no
+    /// longer `exists`, at `revision`.
+    pub(crate) fn set(&mut self, path: JsonPointerRef<'_>, revision: u64, exists: bool) {
+        let mut entry = self;
+        entry.changed = revision;
+        let (parent_path, key) = match path.split_last() {
+            Some(split) if !exists => split,
+            _ => {
+                for segment in path.iter() {
+                    entry = Arc::make_mut(&mut entry.children)
+                        .entry(segment.to_string())
+                        .or_default();
+                    entry.changed = revision;
+                }
+                entry.replaced = revision;
+                entry.children = Arc::default();
+                return;
+            }
+        };
+
+        for segment in parent_path.iter() {
+            entry = Arc::make_mut(&mut entry.children)
+                .entry(segment.to_string())
+                .or_default();
+            entry.changed = revision;
+        }
+        Arc::make_mut(&mut entry.children).remove(key);
+        entry.removed = revision;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_patch::JsonPatch;
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use crate::{MemDb, MemDbError};
+
+    #[test]
+    fn test_revisions() {
+        let mut db = MemDb::new(json!({ "a": { "b": 1, "c": [1, 2] }, "d": {} }));
+        assert_eq!(db.revision(), 0);
+
+        db.patch(
+            None,
+            vec![JsonPatch::Replace {
+                path: json_pointer!("/a/b"),
+                value: json!(2),
+            }],
+        )
+        .unwrap();
+        db.patch(
+            None,
+            vec![JsonPatch::Add {
+                path: json_pointer!("/d/e"),
+                value: json!({ "f": 1 }),
+            }],
+        )
+        .unwrap();
+        assert_eq!(db.revision(), 2);
+        assert_eq!(db.revision_of(json_pointer!("")), 2);
+        assert_eq!(db.revision_of(json_pointer!("/a")), 1);
+        assert_eq!(db.revision_of(json_pointer!("/a/b")), 1);
+        assert_eq!(db.revision_of(json_pointer!("/a/c")), 0);
+        assert_eq!(db.revision_of(json_pointer!("/d/e/f")), 2);
+        assert_eq!(
+            db.get_with_revision(json_pointer!("/a/c/1")),
+            (Some(json!(2)), 0)
+        );
+
+        // an element shifts all elements after it
+        db.patch(
+            None,
+            vec![JsonPatch::Remove {
+                path: json_pointer!("/a/c/0"),
+            }],
+        )
+        .unwrap();
+        assert_eq!(db.revision_of(json_pointer!("/a/c/0")), 3);
+        assert_eq!(db.revision_of(json_pointer!("/a/b")), 1);
+
+        // a removed path keeps its revision, and so does a moved value
+        db.patch(
+            None,
+            vec![JsonPatch::Move {
+                from: json_pointer!("/d/e"),
+                path: json_pointer!("/g"),
+            }],
+        )
+        .unwrap();
+        assert_eq!(db.get_with_revision(json_pointer!("/d/e")), (None, 4));
+        assert_eq!(db.revision_of(json_pointer!("/g/f")), 4);
+
+        db.check_unchanged(json_pointer!("/a/b"), 1).unwrap();
+        assert!(matches!(
+            db.check_unchanged(json_pointer!("/a"), 2),
+            Err(MemDbError::Changed { revision: 3, .. })
+        ));
+
+        // the checks inside a transaction see its changes, but only a
+        // committed transaction takes a revision
+        let mut txn = db.transaction();
+        txn.check_unchanged(json_pointer!("/a/b"), 1).unwrap();
+        txn.patch(
+            None,
+            vec![JsonPatch::Remove {
+                path: json_pointer!("/a/b"),
+            }],
+        )
+        .unwrap();
+        assert!(txn.check_unchanged(json_pointer!("/a/b"), 4).is_err());
+        drop(txn);
+        assert_eq!(db.revision(), 4);
+        assert_eq!(db.revision_of(json_pointer!("/a/b")), 1);
+        db.check_unchanged(json_pointer!("/a"), 3).unwrap();
+
+        // a failed patch changes nothing, even if its first operation
+        // succeeded
+        db.patch(
+            None,
+            vec![
+                JsonPatch::Replace {
+                    path: json_pointer!("/a/b"),
+                    value: json!(3),
+                },
+                JsonPatch::Remove {
+                    path: json_pointer!("/missing"),
+                },
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(db.revision(), 4);
+        assert_eq!(db.revision_of(json_pointer!("/a/b")), 1);
+
+        // a change rolled back to a savepoint is not part of the commit
+        let mut txn = db.transaction();
+        let savepoint = txn.savepoint();
+        txn.patch(
+            None,
+            vec![JsonPatch::Remove {
+                path: json_pointer!("/a/b"),
+            }],
+        )
+        .unwrap();
+        txn.rollback_to(savepoint).unwrap();
+        txn.patch(
+            None,
+            vec![JsonPatch::Replace {
+                path: json_pointer!("/g/f"),
+                value: json!(2),
+            }],
+        )
+        .unwrap();
+        txn.commit();
+        assert_eq!(db.revision(), 5);
+        assert_eq!(db.revision_of(json_pointer!("/a/b")), 1);
+        assert_eq!(db.revision_of(json_pointer!("/g/f")), 5);
+    }
+}
@@ -0,0 +1,257 @@
+use json_patch::JsonPatch;
+use json_pointer::{JsonPointer, ToJsonPointerRef};
+use serde_json::Value;
+
+use crate::{db::WriteMark, undo_command::UndoCommand, Effect, MemDb, MemDbError, Node};
+
+/// A marker inside a [`Transaction`] which the changes made after it can be
+/// rolled back to, see [`Transaction::savepoint`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Savepoint {
+    id: usize,
+}
+
+/// Changes to a [`MemDb`] which are applied as they are made and kept only
+/// if the transaction is committed.
+///
+/// Reads through the transaction see its uncommitted changes. Dropping the
+/// transaction without calling [`Transaction::commit`] rolls back all of
+/// them.
+pub struct Transaction<'a> {
+    db: &'a mut MemDb,
+    undo_commands: Vec<UndoCommand>,
+    /// The revisions from before the transaction, which are restored on
+    /// rollback, so only a committed transaction takes a revision.
+    start: WriteMark,
+    /// Open savepoints with the length of the undo log when each was taken,
+    /// innermost last.
+    savepoints: Vec<(usize, usize, WriteMark)>,
+    next_savepoint_id: usize,
+    committed: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a mut MemDb) -> Self {
+        Self {
+            start: db.write_mark(),
+            db,
+            undo_commands: Vec::new(),
+            savepoints: Vec::new(),
+            next_savepoint_id: 0,
+            committed: false,
+        }
+    }
+
+    /// See [`MemDb::get`].
+    pub fn get(&self, path: impl ToJsonPointerRef) -> Option<Value> {
+        self.db.get(path)
+    }
+
+    pub fn get_node(&self, path: impl ToJsonPointerRef) -> Option<&Node> {
+        self.db.get_node(path)
+    }
+
+    /// See [`MemDb::root`].
+    #[inline]
+    pub fn root(&self) -> Value {
+        self.db.root()
+    }
+
+    #[inline]
+    pub fn root_node(&self) -> &Node {
+        self.db.root_node()
+    }
+
+    /// See [`MemDb::check_unchanged`], changes made in the transaction count.
+    pub fn check_unchanged(
+        &self,
+        path: impl ToJsonPointerRef,
+        since: u64,
+    ) -> Result<(), MemDbError> {
+        self.db.check_unchanged(path, since)
+    }
+
+    /// Applies `commands`, either all of them or, if one fails, none.
+    ///
+    /// A failed patch leaves the changes made before it in place.
+    pub fn patch(
+        &mut self,
+        prefix: Option<&JsonPointer>,
+        commands: Vec<JsonPatch>,
+    ) -> Result<(), MemDbError> {
+        let mut commands = commands;
+        let len = self.undo_commands.len();
+        let mark = self.db.write_mark();
+        match self
+            .db
+            .patch_all(&mut self.undo_commands, prefix, &mut commands)
+        {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.undo(len, mark);
+                Err(err)
+            }
+        }
+    }
+
+    /// Like [`Transaction::patch`], but also returns what each operation
+    /// changed, see [`Effect`]. A `test` operation has no effect.
+    pub fn patch_with_effects(
+        &mut self,
+        prefix: Option<&JsonPointer>,
+        commands: Vec<JsonPatch>,
+    ) -> Result<Vec<Effect>, MemDbError> {
+        let len = self.undo_commands.len();
+        let mark = self.db.write_mark();
+        let mut effects = Vec::with_capacity(commands.len());
+        for mut command in commands {
+            let copied_from = match &command {
+                JsonPatch::Copy { from, .. } => Some(from.with_prefix_opt(prefix).to_owned()),
+                _ => None,
+            };
+            let applied = self.undo_commands.len();
+            if let Err(err) = self
+                .db
+                .patch_command(&mut self.undo_commands, prefix, &mut command)
+            {
+                self.undo(len, mark);
+                return Err(err);
+            }
+            if let Some(undo_command) = self.undo_commands.get(applied) {
+                effects.push(undo_command.effect(copied_from, self.db.root_node()));
+            }
+        }
+        Ok(effects)
+    }
+
+    /// Marks the current state so that the changes made from now on can be
+    /// rolled back with [`Transaction::rollback_to`], savepoints may be
+    /// nested.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint_id;
+        self.next_savepoint_id += 1;
+        self.savepoints
+            .push((id, self.undo_commands.len(), self.db.write_mark()));
+        Savepoint { id }
+    }
+
+    /// Rolls back the changes made after `savepoint`, which stays open. The
+    /// savepoints taken after it are released.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), MemDbError> {
+        let index = self.savepoint_index(savepoint)?;
+        self.savepoints.truncate(index + 1);
+        let (_, len, mark) = self.savepoints[index].clone();
+        self.undo(len, mark);
+        Ok(())
+    }
+
+    /// Forgets `savepoint` and the savepoints taken after it, keeping their
+    /// changes as part of the transaction.
+    pub fn release(&mut self, savepoint: Savepoint) -> Result<(), MemDbError> {
+        let index = self.savepoint_index(savepoint)?;
+        self.savepoints.truncate(index);
+        Ok(())
+    }
+
+    /// Keeps all changes made in the transaction, which are recorded as a
+    /// single change in the undo histories.
+    pub fn commit(mut self) {
+        let undo_commands = std::mem::take(&mut self.undo_commands);
+        self.db.record_history(undo_commands, None);
+        self.committed = true;
+    }
+
+    /// Rolls back all changes made in the transaction, the same as dropping
+    /// it.
+    pub fn rollback(self) {}
+
+    fn savepoint_index(&self, savepoint: Savepoint) -> Result<usize, MemDbError> {
+        self.savepoints
+            .iter()
+            .position(|(id, ..)| *id == savepoint.id)
+            .ok_or(MemDbError::InvalidSavepoint)
+    }
+
+    fn undo(&mut self, len: usize, mark: WriteMark) {
+        self.db.roll_back(self.undo_commands.drain(len..), mark);
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.undo(0, self.start.clone());
+        }
+        self.db.end_write();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn add(path: JsonPointer, value: Value) -> Vec<JsonPatch> {
+        vec![JsonPatch::Add { path, value }]
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut db = MemDb::new(json!({ "a": 1 }));
+
+        let mut txn = db.transaction();
+        txn.patch(None, add(json_pointer!("/b"), json!([])))
+            .unwrap();
+        assert_eq!(txn.get(json_pointer!("/b")).unwrap(), json!([]));
+
+        let outer = txn.savepoint();
+        txn.patch(None, add(json_pointer!("/b/-"), json!(1)))
+            .unwrap();
+        let inner = txn.savepoint();
+        txn.patch(None, add(json_pointer!("/b/-"), json!(2)))
+            .unwrap();
+        txn.patch(
+            None,
+            vec![
+                JsonPatch::Remove {
+                    path: json_pointer!("/a"),
+                },
+                JsonPatch::Remove {
+                    path: json_pointer!("/missing"),
+                },
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(txn.root(), json!({ "a": 1, "b": [1, 2] }));
+
+        txn.rollback_to(inner).unwrap();
+        assert_eq!(txn.root(), json!({ "a": 1, "b": [1] }));
+        txn.rollback_to(outer).unwrap();
+        assert!(matches!(
+            txn.rollback_to(inner),
+            Err(MemDbError::InvalidSavepoint)
+        ));
+        assert_eq!(txn.root(), json!({ "a": 1, "b": [] }));
+
+        let savepoint = txn.savepoint();
+        txn.patch(None, add(json_pointer!("/c"), json!(3))).unwrap();
+        txn.release(savepoint).unwrap();
+        txn.commit();
+        assert_eq!(db.root(), json!({ "a": 1, "b": [], "c": 3 }));
+
+        let mut txn = db.transaction();
+        txn.patch(None, add(json_pointer!("/d"), json!(4))).unwrap();
+        txn.patch(
+            None,
+            vec![JsonPatch::Move {
+                from: json_pointer!("/d"),
+                path: json_pointer!("/b/-"),
+            }],
+        )
+        .unwrap();
+        drop(txn);
+        assert_eq!(db.root(), json!({ "a": 1, "b": [], "c": 3 }));
+    }
+}
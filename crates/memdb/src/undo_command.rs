@@ -1,67 +1,252 @@
-use json_pointer::{JsonPointerRef, ValueExt};
-use serde_json::Value;
+use std::sync::Arc;
 
-pub enum UpdateSource<'a> {
-    Object {
-        path: JsonPointerRef<'a>,
-        key: &'a str,
-    },
-    Array {
-        path: JsonPointerRef<'a>,
-        index: usize,
-    },
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+
+use crate::{Effect, Node};
+
+#[derive(Debug, Clone)]
+pub enum UpdateSource {
+    Object { path: JsonPointer, key: Arc<str> },
+    Array { path: JsonPointer, index: usize },
 }
 
-pub enum UpdateTarget<'a> {
-    Object {
-        path: JsonPointerRef<'a>,
-        key: &'a str,
-    },
-    ArrayInsert {
-        path: JsonPointerRef<'a>,
-        index: usize,
-    },
-    ArrayAppend {
-        path: JsonPointerRef<'a>,
-    },
+#[derive(Debug, Clone)]
+pub enum UpdateTarget {
+    Object { path: JsonPointer, key: Arc<str> },
+    ArrayInsert { path: JsonPointer, index: usize },
+    ArrayAppend { path: JsonPointer },
 }
 
-pub(crate) enum UndoCommand<'a> {
+#[derive(Debug, Clone)]
+pub(crate) enum UndoCommand {
     ReplaceRoot {
-        prev_value: Value,
+        prev_value: Node,
     },
     Add {
-        target: UpdateTarget<'a>,
-        prev_value: Option<Value>,
+        target: UpdateTarget,
+        prev_value: Option<Node>,
     },
     Remove {
-        source: UpdateSource<'a>,
-        prev_value: Value,
+        source: UpdateSource,
+        prev_value: Node,
     },
     Replace {
-        path: JsonPointerRef<'a>,
-        prev_value: Value,
+        path: JsonPointer,
+        prev_value: Node,
     },
     Move {
-        source: UpdateSource<'a>,
-        target: UpdateTarget<'a>,
-        prev_value: Option<Value>,
+        source: UpdateSource,
+        target: UpdateTarget,
+        prev_value: Option<Node>,
     },
     MoveToRoot {
-        source: UpdateSource<'a>,
-        prev_value: Value,
+        source: UpdateSource,
+        prev_value: Node,
     },
     Copy {
-        target: UpdateTarget<'a>,
-        prev_value: Option<Value>,
+        target: UpdateTarget,
+        prev_value: Option<Node>,
     },
     CopyToRoot {
-        prev_value: Value,
+        prev_value: Node,
     },
 }
 
-impl<'a> UndoCommand<'a> {
-    pub(crate) fn execute(self, root: &mut Value) {
+fn child(path: &JsonPointer, key: impl Into<String>) -> JsonPointer {
+    let mut pointer = path.clone();
+    pointer.push(key);
+    pointer
+}
+
+impl UpdateSource {
+    fn pointer(&self) -> JsonPointer {
+        match self {
+            UpdateSource::Object { path, key } => child(path, &**key),
+            UpdateSource::Array { path, index } => child(path, index.to_string()),
+        }
+    }
+
+    /// The array as a whole is changed when one of its elements is removed,
+    /// since the following elements are shifted.
+    fn changed_path(&self) -> JsonPointer {
+        match self {
+            UpdateSource::Object { path, key } => child(path, &**key),
+            UpdateSource::Array { path, .. } => path.clone(),
+        }
+    }
+}
+
+impl UpdateTarget {
+    fn pointer(&self, root: &Node) -> JsonPointer {
+        match self {
+            UpdateTarget::Object { path, key } => child(path, &**key),
+            UpdateTarget::ArrayInsert { path, index } => child(path, index.to_string()),
+            UpdateTarget::ArrayAppend { path } => {
+                let last = root
+                    .locate(path)
+                    .and_then(Node::as_array)
+                    .and_then(|array| array.len().checked_sub(1));
+                match last {
+                    Some(index) => child(path, index.to_string()),
+                    None => child(path, "-"),
+                }
+            }
+        }
+    }
+
+    fn changed_path(&self) -> JsonPointer {
+        match self {
+            UpdateTarget::Object { path, key } => child(path, &**key),
+            UpdateTarget::ArrayInsert { path, .. } | UpdateTarget::ArrayAppend { path } => {
+                path.clone()
+            }
+        }
+    }
+}
+
+impl UndoCommand {
+    /// Returns the patch which reverts the change, given the document right
+    /// after it.
+    pub(crate) fn into_patch(self, root: &Node) -> Vec<JsonPatch> {
+        match self {
+            UndoCommand::ReplaceRoot { prev_value } | UndoCommand::CopyToRoot { prev_value } => {
+                vec![JsonPatch::Replace {
+                    path: JsonPointer::root(),
+                    value: prev_value.into(),
+                }]
+            }
+            UndoCommand::Add { target, prev_value } | UndoCommand::Copy { target, prev_value } => {
+                let path = target.pointer(root);
+                match prev_value {
+                    Some(value) => vec![JsonPatch::Replace {
+                        path,
+                        value: value.into(),
+                    }],
+                    None => vec![JsonPatch::Remove { path }],
+                }
+            }
+            UndoCommand::Remove { source, prev_value } => vec![JsonPatch::Add {
+                path: source.pointer(),
+                value: prev_value.into(),
+            }],
+            UndoCommand::Replace { path, prev_value } => vec![JsonPatch::Replace {
+                path,
+                value: prev_value.into(),
+            }],
+            UndoCommand::Move {
+                source,
+                target,
+                prev_value,
+            } => {
+                let to = target.pointer(root);
+                let mut patch = vec![JsonPatch::Move {
+                    from: to.clone(),
+                    path: source.pointer(),
+                }];
+                if let Some(value) = prev_value {
+                    patch.push(JsonPatch::Add {
+                        path: to,
+                        value: value.into(),
+                    });
+                }
+                patch
+            }
+            UndoCommand::MoveToRoot { source, prev_value } => vec![
+                JsonPatch::Replace {
+                    path: JsonPointer::root(),
+                    value: prev_value.into(),
+                },
+                JsonPatch::Add {
+                    path: source.pointer(),
+                    value: root.to_value(),
+                },
+            ],
+        }
+    }
+
+    /// Describes the change right after it has been made, `copied_from` is
+    /// the source of a copy.
+    pub(crate) fn effect(&self, copied_from: Option<JsonPointer>, root: &Node) -> Effect {
+        let located = |path: &JsonPointer| root.locate(path).cloned().unwrap_or_default();
+        match self {
+            UndoCommand::ReplaceRoot { prev_value } => Effect::Add {
+                path: JsonPointer::root(),
+                value: root.clone(),
+                prev_value: Some(prev_value.clone()),
+            },
+            UndoCommand::Add { target, prev_value } => {
+                let path = target.pointer(root);
+                Effect::Add {
+                    value: located(&path),
+                    path,
+                    prev_value: prev_value.clone(),
+                }
+            }
+            UndoCommand::Remove { source, prev_value } => Effect::Remove {
+                path: source.pointer(),
+                prev_value: prev_value.clone(),
+            },
+            UndoCommand::Replace { path, prev_value } => Effect::Replace {
+                path: path.clone(),
+                value: located(path),
+                prev_value: prev_value.clone(),
+            },
+            UndoCommand::Move {
+                source,
+                target,
+                prev_value,
+            } => {
+                let path = target.pointer(root);
+                Effect::Move {
+                    from: source.pointer(),
+                    value: located(&path),
+                    path,
+                    prev_value: prev_value.clone(),
+                }
+            }
+            UndoCommand::MoveToRoot { source, prev_value } => Effect::Move {
+                from: source.pointer(),
+                path: JsonPointer::root(),
+                value: root.clone(),
+                prev_value: Some(prev_value.clone()),
+            },
+            UndoCommand::Copy { target, prev_value } => {
+                let path = target.pointer(root);
+                Effect::Copy {
+                    from: copied_from.unwrap_or_else(JsonPointer::root),
+                    value: located(&path),
+                    path,
+                    prev_value: prev_value.clone(),
+                }
+            }
+            UndoCommand::CopyToRoot { prev_value } => Effect::Copy {
+                from: copied_from.unwrap_or_else(JsonPointer::root),
+                path: JsonPointer::root(),
+                value: root.clone(),
+                prev_value: Some(prev_value.clone()),
+            },
+        }
+    }
+
+    /// Returns the locations whose values are changed by the change.
+    pub(crate) fn changed_paths(&self) -> Vec<JsonPointer> {
+        match self {
+            UndoCommand::ReplaceRoot { .. }
+            | UndoCommand::MoveToRoot { .. }
+            | UndoCommand::CopyToRoot { .. } => vec![JsonPointer::root()],
+            UndoCommand::Add { target, .. } | UndoCommand::Copy { target, .. } => {
+                vec![target.changed_path()]
+            }
+            UndoCommand::Remove { source, .. } => vec![source.changed_path()],
+            UndoCommand::Replace { path, .. } => vec![path.clone()],
+            UndoCommand::Move { source, target, .. } => {
+                vec![source.changed_path(), target.changed_path()]
+            }
+        }
+    }
+
+    pub(crate) fn execute(self, root: &mut Node) {
         match self {
             UndoCommand::ReplaceRoot { prev_value } => {
                 *root = prev_value;
@@ -70,13 +255,13 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::Object { path, key },
                 prev_value,
             } => {
-                if let Some(Value::Object(parent)) = root.locate_mut(path) {
+                if let Some(parent) = root.locate_mut(&path).and_then(Node::object_mut) {
                     match prev_value {
                         Some(prev_value) => {
-                            parent.insert(key.to_string(), prev_value);
+                            parent.insert(key, prev_value);
                         }
                         None => {
-                            parent.remove(key);
+                            parent.remove(&*key);
                         }
                     }
                 }
@@ -85,7 +270,7 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::ArrayInsert { path, index },
                 ..
             } => {
-                if let Some(Value::Array(parent)) = root.locate_mut(path) {
+                if let Some(parent) = root.locate_mut(&path).and_then(Node::array_mut) {
                     parent.remove(index);
                 }
             }
@@ -93,7 +278,7 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::ArrayAppend { path },
                 ..
             } => {
-                if let Some(Value::Array(parent)) = root.locate_mut(path) {
+                if let Some(parent) = root.locate_mut(&path).and_then(Node::array_mut) {
                     parent.pop();
                 }
             }
@@ -101,20 +286,20 @@ impl<'a> UndoCommand<'a> {
                 source: UpdateSource::Object { path, key },
                 prev_value,
             } => {
-                if let Some(Value::Object(parent)) = root.locate_mut(path) {
-                    parent.insert(key.to_string(), prev_value);
+                if let Some(parent) = root.locate_mut(&path).and_then(Node::object_mut) {
+                    parent.insert(key, prev_value);
                 }
             }
             UndoCommand::Remove {
                 source: UpdateSource::Array { path, index },
                 prev_value,
             } => {
-                if let Some(Value::Array(parent)) = root.locate_mut(path) {
+                if let Some(parent) = root.locate_mut(&path).and_then(Node::array_mut) {
                     parent.insert(index, prev_value);
                 }
             }
             UndoCommand::Replace { path, prev_value } => {
-                if let Some(value) = root.locate_mut(path) {
+                if let Some(value) = root.locate_mut(&path) {
                     *value = prev_value;
                 }
             }
@@ -131,14 +316,14 @@ impl<'a> UndoCommand<'a> {
                     },
                 prev_value,
             } => {
-                if let Some(Value::Object(obj)) = root.locate_mut(to_path) {
+                if let Some(obj) = root.locate_mut(&to_path).and_then(Node::object_mut) {
                     let value = match prev_value {
-                        Some(prev_value) => obj.insert(to_key.to_string(), prev_value),
-                        None => obj.remove(to_key),
+                        Some(prev_value) => obj.insert(to_key, prev_value),
+                        None => obj.remove(&*to_key),
                     };
                     if let Some(value) = value {
-                        if let Some(Value::Object(obj)) = root.locate_mut(from_path) {
-                            obj.insert(from_key.to_string(), value);
+                        if let Some(obj) = root.locate_mut(&from_path).and_then(Node::object_mut) {
+                            obj.insert(from_key, value);
                         }
                     }
                 }
@@ -156,10 +341,10 @@ impl<'a> UndoCommand<'a> {
                     },
                 ..
             } => {
-                if let Some(Value::Array(array)) = root.locate_mut(to_path) {
+                if let Some(array) = root.locate_mut(&to_path).and_then(Node::array_mut) {
                     let value = array.remove(to_index);
-                    if let Some(Value::Object(obj)) = root.locate_mut(from_path) {
-                        obj.insert(from_key.to_string(), value);
+                    if let Some(obj) = root.locate_mut(&from_path).and_then(Node::object_mut) {
+                        obj.insert(from_key, value);
                     }
                 }
             }
@@ -172,11 +357,11 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::ArrayAppend { path: to_path },
                 ..
             } => {
-                if let Some(Value::Array(array)) = root.locate_mut(to_path) {
+                if let Some(array) = root.locate_mut(&to_path).and_then(Node::array_mut) {
                     let value = array.pop();
                     if let Some(value) = value {
-                        if let Some(Value::Object(obj)) = root.locate_mut(from_path) {
-                            obj.insert(from_key.to_string(), value);
+                        if let Some(obj) = root.locate_mut(&from_path).and_then(Node::object_mut) {
+                            obj.insert(from_key, value);
                         }
                     }
                 }
@@ -194,13 +379,13 @@ impl<'a> UndoCommand<'a> {
                     },
                 prev_value,
             } => {
-                if let Some(Value::Object(obj)) = root.locate_mut(to_path) {
+                if let Some(obj) = root.locate_mut(&to_path).and_then(Node::object_mut) {
                     let value = match prev_value {
-                        Some(prev_value) => obj.insert(to_key.to_string(), prev_value),
-                        None => obj.remove(to_key),
+                        Some(prev_value) => obj.insert(to_key, prev_value),
+                        None => obj.remove(&*to_key),
                     };
                     if let Some(value) = value {
-                        if let Some(Value::Array(array)) = root.locate_mut(from_path) {
+                        if let Some(array) = root.locate_mut(&from_path).and_then(Node::array_mut) {
                             array.insert(from_index, value);
                         }
                     }
@@ -219,9 +404,9 @@ impl<'a> UndoCommand<'a> {
                     },
                 ..
             } => {
-                if let Some(Value::Array(array)) = root.locate_mut(to_path) {
+                if let Some(array) = root.locate_mut(&to_path).and_then(Node::array_mut) {
                     let value = array.remove(to_index);
-                    if let Some(Value::Array(array)) = root.locate_mut(from_path) {
+                    if let Some(array) = root.locate_mut(&from_path).and_then(Node::array_mut) {
                         array.insert(from_index, value);
                     }
                 }
@@ -235,10 +420,10 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::ArrayAppend { path: to_path },
                 ..
             } => {
-                if let Some(Value::Array(array)) = root.locate_mut(to_path) {
+                if let Some(array) = root.locate_mut(&to_path).and_then(Node::array_mut) {
                     let value = array.pop();
                     if let Some(value) = value {
-                        if let Some(Value::Array(array)) = root.locate_mut(from_path) {
+                        if let Some(array) = root.locate_mut(&from_path).and_then(Node::array_mut) {
                             array.insert(from_index, value);
                         }
                     }
@@ -249,8 +434,8 @@ impl<'a> UndoCommand<'a> {
                 prev_value,
             } => {
                 let value = std::mem::replace(root, prev_value);
-                if let Some(Value::Object(obj)) = root.locate_mut(path) {
-                    obj.insert(key.to_string(), value);
+                if let Some(obj) = root.locate_mut(&path).and_then(Node::object_mut) {
+                    obj.insert(key, value);
                 }
             }
             UndoCommand::MoveToRoot {
@@ -258,7 +443,7 @@ impl<'a> UndoCommand<'a> {
                 prev_value,
             } => {
                 let value = std::mem::replace(root, prev_value);
-                if let Some(Value::Array(array)) = root.locate_mut(path) {
+                if let Some(array) = root.locate_mut(&path).and_then(Node::array_mut) {
                     array.insert(index, value);
                 }
             }
@@ -266,10 +451,10 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::Object { path, key },
                 prev_value,
             } => {
-                if let Some(Value::Object(obj)) = root.locate_mut(path) {
+                if let Some(obj) = root.locate_mut(&path).and_then(Node::object_mut) {
                     match prev_value {
-                        Some(prev_value) => obj.insert(key.to_string(), prev_value),
-                        None => obj.remove(key),
+                        Some(prev_value) => obj.insert(key, prev_value),
+                        None => obj.remove(&*key),
                     };
                 }
             }
@@ -277,7 +462,7 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::ArrayInsert { path, index },
                 ..
             } => {
-                if let Some(Value::Array(array)) = root.locate_mut(path) {
+                if let Some(array) = root.locate_mut(&path).and_then(Node::array_mut) {
                     array.remove(index);
                 }
             }
@@ -285,7 +470,7 @@ impl<'a> UndoCommand<'a> {
                 target: UpdateTarget::ArrayAppend { path },
                 ..
             } => {
-                if let Some(Value::Array(array)) = root.locate_mut(path) {
+                if let Some(array) = root.locate_mut(&path).and_then(Node::array_mut) {
                     array.pop();
                 }
             }
@@ -0,0 +1,73 @@
+//! Compares how long it takes to load a snapshot in each format.
+//!
+//! Run with `cargo bench -p bigjson-persistentdb --bench snapshot_load`.
+
+use std::time::{Duration, Instant};
+
+use bigjson_persistentdb::{PersistentDb, PersistentDbOptions, SnapshotFormat};
+use json_patch::JsonPatch;
+use json_pointer::json_pointer;
+use serde_json::{json, Value};
+
+const ITEMS: usize = 200_000;
+const ROUNDS: u32 = 5;
+
+fn document() -> Value {
+    let items = (0..ITEMS)
+        .map(|n| {
+            json!({
+                "id": n,
+                "name": format!("item-{}", n),
+                "price": n as f64 * 0.25,
+                "tags": ["a", "b", "c"],
+                "active": n % 2 == 0,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!(items)
+}
+
+fn bench(format: SnapshotFormat, value: &Value) -> Duration {
+    let dir = tempfile::tempdir().unwrap();
+    let options = PersistentDbOptions::default()
+        .snapshot_format(format)
+        .compact_block_threshold(0);
+    let mut pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+    pdb.append(
+        None,
+        &[JsonPatch::Add {
+            path: json_pointer!("/items"),
+            value: value.clone(),
+        }],
+    )
+    .unwrap();
+    pdb.compact(pdb.create_memdb().unwrap().snapshot()).unwrap();
+    while pdb.is_compacting() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    drop(pdb);
+
+    let pdb = PersistentDb::open(dir.path()).unwrap();
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let now = Instant::now();
+        let memdb = pdb.create_memdb().unwrap();
+        elapsed += now.elapsed();
+        // freeing the document is not part of loading it
+        drop(memdb);
+    }
+    elapsed / ROUNDS
+}
+
+fn main() {
+    let value = document();
+    let json = bench(SnapshotFormat::Json, &value);
+    let binary = bench(SnapshotFormat::Binary, &value);
+    println!("json:   {:?} per load", json);
+    println!("binary: {:?} per load", binary);
+    println!(
+        "binary loads {:.2}x faster",
+        json.as_secs_f64() / binary.as_secs_f64()
+    );
+}
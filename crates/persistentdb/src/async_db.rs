@@ -0,0 +1,417 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use memdb::{MemDb, Node};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{PersistentDb, PersistentDbError, PersistentDbOptions, RecordStamp};
+
+/// Gets [`PersistentDbError::Poisoned`] instead of the database after a
+/// failed sync.
+type Call = Box<dyn FnOnce(Result<&mut PersistentDb, PersistentDbError>) + Send>;
+
+enum Command {
+    Append {
+        prefix: Option<JsonPointer>,
+        patch: Vec<JsonPatch>,
+        queued_at: Instant,
+        reply: oneshot::Sender<Result<RecordStamp, PersistentDbError>>,
+    },
+    Call(Call),
+}
+
+/// A [`PersistentDb`] for async code, whose blocking file I/O runs on a
+/// background thread owned by it.
+///
+/// Appends queued while the thread is busy are written and synced together,
+/// so many concurrent appends cost a single `fsync`. The thread stops once
+/// the value is dropped or [closed](AsyncPersistentDb::close), after syncing
+/// everything appended so far.
+///
+/// If a sync fails, the appends waiting for it fail with
+/// [`PersistentDbError::SyncFailed`]: their records have been written but may
+/// or may not survive a crash, so retrying them could apply them twice. The
+/// database is poisoned then, every later append and call fails with
+/// [`PersistentDbError::Poisoned`]. Reopening it recovers the records which
+/// have actually been persisted, whose last one tells which appends to retry.
+pub struct AsyncPersistentDb {
+    tx: mpsc::UnboundedSender<Command>,
+    counters: Arc<Counters>,
+    closed: Option<oneshot::Receiver<Result<(), PersistentDbError>>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    queue_depth: AtomicUsize,
+    appended: AtomicU64,
+    syncs: AtomicU64,
+    total_write_latency_micros: AtomicU64,
+    max_write_latency_micros: AtomicU64,
+}
+
+/// A snapshot of the counters of an [`AsyncPersistentDb`].
+#[derive(Debug, Clone, Serialize)]
+pub struct AsyncMetrics {
+    /// Appends and other calls waiting for the background thread.
+    pub queue_depth: usize,
+    /// Records appended and synced so far.
+    pub appended: u64,
+    /// Number of times the appended records have been synced.
+    pub syncs: u64,
+    /// Mean time from calling [`AsyncPersistentDb::append`] until the record
+    /// has been synced.
+    pub mean_write_latency: Duration,
+    /// Longest time from calling [`AsyncPersistentDb::append`] until the
+    /// record has been synced.
+    pub max_write_latency: Duration,
+}
+
+impl AsyncPersistentDb {
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self, PersistentDbError> {
+        Self::open_with_options(path, PersistentDbOptions::default()).await
+    }
+
+    /// Opens the database on a new background thread.
+    pub async fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: PersistentDbOptions,
+    ) -> Result<Self, PersistentDbError> {
+        let path = path.into();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let counters = Arc::new(Counters::default());
+        std::thread::Builder::new()
+            .name("persistentdb".to_string())
+            .spawn({
+                let counters = counters.clone();
+                move || match PersistentDb::open_with_options(path, options) {
+                    Ok(pdb) => {
+                        let _ = opened_tx.send(Ok(()));
+                        let res = io_loop(pdb, rx, &counters);
+                        // the data directory has been unlocked by now
+                        let _ = closed_tx.send(res);
+                    }
+                    Err(err) => {
+                        let _ = opened_tx.send(Err(err));
+                    }
+                }
+            })?;
+
+        opened_rx.await.map_err(|_| PersistentDbError::Closed)??;
+        Ok(Self {
+            tx,
+            counters,
+            closed: Some(closed_rx),
+        })
+    }
+
+    /// Stops the background thread after syncing everything appended so far,
+    /// resolving once the data directory can be opened again.
+    pub async fn close(mut self) -> Result<(), PersistentDbError> {
+        self.close_channel();
+        match self.closed.take() {
+            Some(closed) => closed.await.map_err(|_| PersistentDbError::Closed)?,
+            None => Ok(()),
+        }
+    }
+
+    /// Appends a record, resolving once it has been synced to the disk.
+    pub async fn append(
+        &self,
+        prefix: Option<JsonPointer>,
+        patch: Vec<JsonPatch>,
+    ) -> Result<RecordStamp, PersistentDbError> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Append {
+            prefix,
+            patch,
+            queued_at: Instant::now(),
+            reply,
+        })?;
+        rx.await.map_err(|_| PersistentDbError::Closed)?
+    }
+
+    /// Loads the document, see [`PersistentDb::create_memdb`].
+    pub async fn create_memdb(&self) -> Result<MemDb, PersistentDbError> {
+        self.call(|pdb| pdb.create_memdb()).await
+    }
+
+    /// Starts a compaction in the background if it is due, see
+    /// [`PersistentDb::compact`].
+    pub async fn compact(&self, snapshot: Node) -> Result<(), PersistentDbError> {
+        self.call(|pdb| pdb.compact(snapshot)).await
+    }
+
+    /// Writes `snapshot` as the new snapshot, resolving once it is done, see
+    /// [`PersistentDb::compact_now`].
+    ///
+    /// Appends wait until the snapshot has been written.
+    pub async fn compact_now(&self, snapshot: Node) -> Result<(), PersistentDbError> {
+        self.call(|pdb| pdb.compact_now(snapshot)).await
+    }
+
+    /// Runs `f` on the background thread, after the appends queued before.
+    pub async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut PersistentDb) -> Result<T, PersistentDbError> + Send + 'static,
+    ) -> Result<T, PersistentDbError> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Call(Box::new(move |pdb| {
+            let _ = reply.send(pdb.and_then(f));
+        })))?;
+        rx.await.map_err(|_| PersistentDbError::Closed)?
+    }
+
+    pub fn metrics(&self) -> AsyncMetrics {
+        let counters = &self.counters;
+        let appended = counters.appended.load(Ordering::Relaxed);
+        let total_micros = counters.total_write_latency_micros.load(Ordering::Relaxed);
+        AsyncMetrics {
+            queue_depth: counters.queue_depth.load(Ordering::Relaxed),
+            appended,
+            syncs: counters.syncs.load(Ordering::Relaxed),
+            mean_write_latency: Duration::from_micros(
+                total_micros.checked_div(appended).unwrap_or_default(),
+            ),
+            max_write_latency: Duration::from_micros(
+                counters.max_write_latency_micros.load(Ordering::Relaxed),
+            ),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), PersistentDbError> {
+        self.counters.queue_depth.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(command).map_err(|_| {
+            self.counters.queue_depth.fetch_sub(1, Ordering::Relaxed);
+            PersistentDbError::Closed
+        })
+    }
+
+    fn close_channel(&mut self) {
+        // closing the channel stops the thread
+        let (tx, _) = mpsc::unbounded_channel();
+        drop(std::mem::replace(&mut self.tx, tx));
+    }
+}
+
+impl Drop for AsyncPersistentDb {
+    /// Stops the background thread without waiting for it, which would block
+    /// the async runtime, see [`AsyncPersistentDb::close`].
+    fn drop(&mut self) {
+        self.close_channel();
+    }
+}
+
+/// An appended record waiting to be synced.
+struct PendingAppend {
+    stamp: RecordStamp,
+    queued_at: Instant,
+    reply: oneshot::Sender<Result<RecordStamp, PersistentDbError>>,
+}
+
+/// Runs the commands until the channel is closed, and returns the result of
+/// the final sync.
+fn io_loop(
+    mut pdb: PersistentDb,
+    mut rx: mpsc::UnboundedReceiver<Command>,
+    counters: &Counters,
+) -> Result<(), PersistentDbError> {
+    let mut pending = Vec::new();
+    let mut poisoned = false;
+    while let Some(command) = rx.blocking_recv() {
+        // group commit: everything already queued is synced together
+        let mut next = Some(command);
+        while let Some(command) = next {
+            counters.queue_depth.fetch_sub(1, Ordering::Relaxed);
+            match command {
+                Command::Append { reply, .. } if poisoned => {
+                    let _ = reply.send(Err(PersistentDbError::Poisoned));
+                }
+                Command::Append {
+                    prefix,
+                    patch,
+                    queued_at,
+                    reply,
+                } => match pdb.append(prefix.as_ref(), &patch) {
+                    Ok(stamp) => pending.push(PendingAppend {
+                        stamp,
+                        queued_at,
+                        reply,
+                    }),
+                    Err(err) => {
+                        let _ = reply.send(Err(err));
+                    }
+                },
+                Command::Call(f) => {
+                    poisoned |= !sync_pending(&mut pdb, &mut pending, counters);
+                    match poisoned {
+                        true => f(Err(PersistentDbError::Poisoned)),
+                        false => f(Ok(&mut pdb)),
+                    }
+                }
+            }
+            next = rx.try_recv().ok();
+        }
+        poisoned |= !sync_pending(&mut pdb, &mut pending, counters);
+    }
+
+    match poisoned {
+        true => Err(PersistentDbError::Poisoned),
+        false => pdb.sync(),
+    }
+}
+
+/// Syncs the pending appends and replies to them, returns `false` if the
+/// sync failed.
+fn sync_pending(
+    pdb: &mut PersistentDb,
+    pending: &mut Vec<PendingAppend>,
+    counters: &Counters,
+) -> bool {
+    if pending.is_empty() {
+        return true;
+    }
+
+    let res = pdb.sync();
+    counters.syncs.fetch_add(1, Ordering::Relaxed);
+    if let Err(err) = &res {
+        tracing::error!(error = %err, "failed to sync data, stop writing");
+    }
+    for append in pending.drain(..) {
+        let res = match &res {
+            Ok(()) => {
+                let latency = append.queued_at.elapsed().as_micros() as u64;
+                counters.appended.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .total_write_latency_micros
+                    .fetch_add(latency, Ordering::Relaxed);
+                counters
+                    .max_write_latency_micros
+                    .fetch_max(latency, Ordering::Relaxed);
+                Ok(append.stamp)
+            }
+            // every waiter gets its own copy of the error
+            Err(err) => Err(PersistentDbError::SyncFailed {
+                reason: err.to_string(),
+            }),
+        };
+        let _ = append.reply.send(res);
+    }
+    res.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use super::*;
+    use crate::fs::faults::{inject, FaultKind};
+
+    #[tokio::test]
+    async fn test_async_persistent_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdb = Arc::new(AsyncPersistentDb::open(dir.path()).await.unwrap());
+        pdb.append(
+            None,
+            vec![JsonPatch::Add {
+                path: json_pointer!("/items"),
+                value: json!([]),
+            }],
+        )
+        .await
+        .unwrap();
+
+        let appends = (0..10).map(|n| {
+            let pdb = pdb.clone();
+            tokio::spawn(async move {
+                pdb.append(
+                    None,
+                    vec![JsonPatch::Add {
+                        path: json_pointer!("/items/-"),
+                        value: json!(n),
+                    }],
+                )
+                .await
+            })
+        });
+        let mut seqs = Vec::new();
+        for append in appends.collect::<Vec<_>>() {
+            seqs.push(append.await.unwrap().unwrap().seq);
+        }
+        seqs.sort_unstable();
+        assert_eq!(seqs, (2..=11).collect::<Vec<_>>());
+
+        let metrics = pdb.metrics();
+        assert_eq!(metrics.appended, 11);
+        assert!(metrics.syncs <= 11);
+        assert_eq!(metrics.queue_depth, 0);
+        assert!(metrics.max_write_latency >= metrics.mean_write_latency);
+
+        let db = pdb.create_memdb().await.unwrap();
+        assert_eq!(
+            db.get(json_pointer!("/items"))
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            10
+        );
+        pdb.compact_now(db.snapshot()).await.unwrap();
+        assert_eq!(pdb.call(|pdb| Ok(pdb.last_record().seq)).await.unwrap(), 11);
+
+        // reopening right away needs the data directory to be unlocked
+        let pdb = Arc::try_unwrap(pdb).ok().unwrap();
+        pdb.close().await.unwrap();
+        let pdb = AsyncPersistentDb::open(dir.path()).await.unwrap();
+        assert_eq!(pdb.create_memdb().await.unwrap().root(), db.root());
+    }
+
+    #[tokio::test]
+    async fn test_failed_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdb = AsyncPersistentDb::open(dir.path()).await.unwrap();
+        let add = |n: i32| {
+            vec![JsonPatch::Add {
+                path: json_pointer!("/a"),
+                value: json!(n),
+            }]
+        };
+        pdb.append(None, add(1)).await.unwrap();
+
+        // the record is written, but its sync fails
+        let guard = inject(dir.path(), 1, FaultKind::Error);
+        assert!(matches!(
+            pdb.append(None, add(2)).await,
+            Err(PersistentDbError::SyncFailed { .. })
+        ));
+        assert!(guard.hit());
+        drop(guard);
+        assert!(matches!(
+            pdb.append(None, add(3)).await,
+            Err(PersistentDbError::Poisoned)
+        ));
+        assert!(matches!(
+            pdb.create_memdb().await,
+            Err(PersistentDbError::Poisoned)
+        ));
+        assert!(matches!(
+            pdb.close().await,
+            Err(PersistentDbError::Poisoned)
+        ));
+
+        // reopening tells whether the record has been persisted after all
+        let pdb = AsyncPersistentDb::open(dir.path()).await.unwrap();
+        assert_eq!(pdb.call(|pdb| Ok(pdb.last_record().seq)).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    fs::sync_dir,
+    manifest::{Manifest, MANIFEST_FILE_NAME},
+    PersistentDbError,
+};
+
+/// A consistent set of files of a data directory which can be copied while
+/// the database keeps writing, see [`PersistentDb::begin_backup`].
+///
+/// The files are kept from being removed by compaction until the backup is
+/// dropped.
+///
+/// [`PersistentDb::begin_backup`]: crate::PersistentDb::begin_backup
+pub struct Backup {
+    path: PathBuf,
+    pub(crate) manifest: Manifest,
+    /// Block files with the number of bytes belonging to the backup.
+    pub(crate) blocks: Vec<(usize, u64)>,
+    _keep_files: KeepFiles,
+}
+
+impl Backup {
+    /// Creates an empty backup and registers it in `backups`.
+    pub(crate) fn new(path: PathBuf, backups: Arc<AtomicUsize>) -> Self {
+        Self {
+            path,
+            manifest: Manifest::default(),
+            blocks: Vec::new(),
+            _keep_files: KeepFiles::new(backups),
+        }
+    }
+
+    /// Copies the files into the new directory `dest`.
+    ///
+    /// The files are written to a temporary directory next to `dest` first,
+    /// so `dest` never contains an incomplete backup.
+    pub fn write_to(&self, dest: impl AsRef<Path>) -> Result<(), PersistentDbError> {
+        let dest = dest.as_ref();
+        if dest.exists() {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", dest.display()),
+            )
+            .into());
+        }
+
+        let temp_dest = temp_path(dest, "temp");
+        tracing::info!(dest = %dest.display(), "backup start");
+        if temp_dest.exists() {
+            std::fs::remove_dir_all(&temp_dest)?;
+        }
+        std::fs::create_dir_all(&temp_dest)?;
+
+        for snapshot in self.manifest.snapshots() {
+            copy_file(
+                &self.path.join(&snapshot.file_name),
+                &temp_dest.join(&snapshot.file_name),
+                None,
+            )?;
+        }
+        for (block_id, len) in &self.blocks {
+            let file_name = format!("{}.block", block_id);
+            copy_file(
+                &self.path.join(&file_name),
+                &temp_dest.join(&file_name),
+                Some(*len),
+            )?;
+        }
+        self.manifest.store(&temp_dest)?;
+
+        std::fs::rename(&temp_dest, dest)?;
+        sync_parent_dir(dest)?;
+        tracing::info!(dest = %dest.display(), "backup finish");
+        Ok(())
+    }
+}
+
+/// Keeps compaction from removing obsolete files until it is dropped, by
+/// counting itself in `backups`.
+pub(crate) struct KeepFiles {
+    backups: Arc<AtomicUsize>,
+}
+
+impl KeepFiles {
+    pub(crate) fn new(backups: Arc<AtomicUsize>) -> Self {
+        backups.fetch_add(1, Ordering::SeqCst);
+        Self { backups }
+    }
+}
+
+impl Drop for KeepFiles {
+    fn drop(&mut self) {
+        self.backups.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns `<path>.<suffix>`.
+pub(crate) fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<(), PersistentDbError> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => Ok(sync_dir(parent)?),
+        _ => Ok(sync_dir(Path::new("."))?),
+    }
+}
+
+/// Copies the first `len` bytes, or everything, of `src` to `dest`.
+fn copy_file(src: &Path, dest: &Path, len: Option<u64>) -> Result<(), PersistentDbError> {
+    let src = File::open(src)?;
+    let mut dest = File::create(dest)?;
+    match len {
+        Some(len) => std::io::copy(&mut src.take(len), &mut dest)?,
+        None => std::io::copy(&mut &src, &mut dest)?,
+    };
+    dest.sync_all()?;
+    Ok(())
+}
+
+/// Copies all files which make up the data directory `src` into `dest`.
+pub(crate) fn copy_data_dir(src: &Path, dest: &Path) -> Result<(), PersistentDbError> {
+    std::fs::create_dir_all(dest)?;
+    for res in src.read_dir()? {
+        let entry = res?;
+        let file_name = entry.file_name();
+        let is_data_file = file_name.to_str().is_some_and(|name| {
+            name == MANIFEST_FILE_NAME || name.starts_with("snapshot") || name.ends_with(".block")
+        });
+        if is_data_file && entry.file_type()?.is_file() {
+            copy_file(&entry.path(), &dest.join(&file_name), None)?;
+        }
+    }
+    Ok(sync_dir(dest)?)
+}
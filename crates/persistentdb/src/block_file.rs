@@ -1,6 +1,6 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{BufReader, Write},
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
     path::Path,
 };
 
@@ -9,70 +9,581 @@ use json_pointer::JsonPointer;
 use serde::{Deserialize, Serialize};
 use serde_json::{de::IoRead, StreamDeserializer};
 
-use crate::PersistentDbError;
+use crate::{
+    codec::Codec,
+    encryption::Location,
+    fs::{self, DataFile},
+    PersistentDbError,
+};
+
+const BLOCK_MAGIC: &[u8; 4] = b"BJBK";
+const BLOCK_VERSION: u32 = 1;
+const BLOCK_HEADER_SIZE: u64 = 8;
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// Position of a record in the log.
+///
+/// Records written by older versions have neither, so they are stamped with
+/// zeros and sort before every other record.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RecordStamp {
+    /// Increases with every record.
+    pub seq: u64,
+    /// Milliseconds since the UNIX epoch when the record was appended.
+    pub timestamp: u64,
+}
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct BlockRecord {
+    #[serde(default)]
+    pub(crate) seq: u64,
+    #[serde(default)]
+    pub(crate) timestamp: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) prefix: Option<JsonPointer>,
     pub(crate) patch_records: Vec<JsonPatch>,
 }
 
+impl BlockRecord {
+    #[inline]
+    pub(crate) fn stamp(&self) -> RecordStamp {
+        RecordStamp {
+            seq: self.seq,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Serialize)]
 pub(crate) struct BlockRecordRef<'a> {
+    pub(crate) seq: u64,
+    pub(crate) timestamp: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) prefix: Option<&'a JsonPointer>,
     pub(crate) patch_records: &'a [JsonPatch],
 }
 
+/// On-disk layout of a block file.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum BlockFormat {
+    /// Concatenated JSON records without any framing, written by older
+    /// versions.
+    Legacy,
+    /// A versioned header followed by `length + crc32 + payload` records.
+    Framed,
+}
+
 pub(crate) struct ActiveBlockFile {
-    file: File,
+    file: DataFile,
+    block_id: usize,
     size: u64,
+    max_size: u64,
+    codec: Codec,
+    /// Set if a failed write may have left part of a record after `size`.
+    torn: bool,
 }
 
 impl ActiveBlockFile {
-    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, PersistentDbError> {
-        let file = OpenOptions::new().append(true).create(true).open(path)?;
-        let md = file.metadata()?;
+    /// Opens a framed block file for appending, writing the header if the
+    /// file is empty or only contains part of it.
+    ///
+    /// The caller is responsible for calling [`recover_block_file`] on
+    /// existing files first.
+    pub(crate) fn open(
+        path: impl AsRef<Path>,
+        block_id: usize,
+        max_size: u64,
+        codec: Codec,
+    ) -> Result<Self, PersistentDbError> {
+        let mut file = DataFile::open_append(path.as_ref())?;
+        let mut size = file.len()?;
+        if size < BLOCK_HEADER_SIZE {
+            // left behind by a failed attempt to create the file
+            if size > 0 {
+                file.set_len(0)?;
+            }
+            let mut header = [0; BLOCK_HEADER_SIZE as usize];
+            header[..4].copy_from_slice(BLOCK_MAGIC);
+            header[4..].copy_from_slice(&BLOCK_VERSION.to_le_bytes());
+            file.write_all(&header)?;
+            size = BLOCK_HEADER_SIZE;
+        }
         Ok(Self {
             file,
-            size: md.len(),
+            block_id,
+            size,
+            max_size,
+            codec,
+            torn: false,
         })
     }
 
     pub(crate) fn append(&mut self, record: BlockRecordRef<'_>) -> Result<(), PersistentDbError> {
-        let data = serde_json::to_vec(&record)?;
-        let data_len = data.len() as u64;
-        if self.size + data_len > 1024 * 1024 * 256 {
+        let location = Location::Record {
+            block: self.block_id,
+            offset: self.size,
+        };
+        let payload = self.codec.encode(serde_json::to_vec(&record)?, location)?;
+        let data_len = RECORD_HEADER_SIZE + payload.len() as u64;
+        // an oversized record is still written to an empty block file
+        if self.size > BLOCK_HEADER_SIZE && self.size + data_len > self.max_size {
             return Err(PersistentDbError::BlockFileIsFull);
         }
-        self.file.write_all(&data)?;
+
+        // a record appended after a torn one could never be read again
+        if self.torn {
+            self.file.set_len(self.size)?;
+            self.torn = false;
+        }
+
+        let mut data = Vec::with_capacity(data_len as usize);
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        data.extend_from_slice(&payload);
+        if let Err(err) = self.file.write_all(&data) {
+            self.torn = true;
+            return Err(err.into());
+        }
         self.size += data_len;
         Ok(())
     }
 
-    pub(crate) fn flush(&mut self) -> Result<(), PersistentDbError> {
-        Ok(self.file.flush()?)
+    pub(crate) fn sync(&mut self) -> Result<(), PersistentDbError> {
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Length of the records written so far.
+    #[inline]
+    pub(crate) fn size(&self) -> u64 {
+        self.size
     }
 }
 
+enum Records {
+    Legacy(StreamDeserializer<'static, IoRead<BufReader<File>>, BlockRecord>),
+    Framed {
+        reader: BufReader<File>,
+        offset: u64,
+        file_len: u64,
+    },
+}
+
 pub(crate) struct InactiveBlockFile {
-    deserializer: StreamDeserializer<'static, IoRead<BufReader<File>>, BlockRecord>,
+    format: BlockFormat,
+    records: Records,
+    block_id: usize,
+    codec: Codec,
+    finished: bool,
 }
 
 impl InactiveBlockFile {
-    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, PersistentDbError> {
-        let file = File::open(path)?;
+    pub(crate) fn open(
+        path: impl AsRef<Path>,
+        block_id: usize,
+        codec: &Codec,
+    ) -> Result<Self, PersistentDbError> {
+        let file = fs::open(path.as_ref())?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = [0; BLOCK_HEADER_SIZE as usize];
+        let header_len = read_full(&mut reader, &mut header)?;
+
+        if header_len == 0 {
+            // an empty block file, created just before a crash
+            return Ok(Self {
+                format: BlockFormat::Framed,
+                records: Records::Framed {
+                    reader,
+                    offset: 0,
+                    file_len,
+                },
+                block_id,
+                codec: codec.clone(),
+                finished: true,
+            });
+        }
+
+        if header[..header_len.min(4)] != BLOCK_MAGIC[..header_len.min(4)] {
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(Self {
+                format: BlockFormat::Legacy,
+                records: Records::Legacy(StreamDeserializer::new(IoRead::new(reader))),
+                block_id,
+                codec: codec.clone(),
+                finished: false,
+            });
+        }
+
+        if header_len < BLOCK_HEADER_SIZE as usize {
+            return Err(PersistentDbError::CorruptedBlock { offset: 0 });
+        }
+
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != BLOCK_VERSION {
+            return Err(PersistentDbError::UnsupportedBlockVersion { version });
+        }
+
         Ok(Self {
-            deserializer: StreamDeserializer::new(IoRead::new(BufReader::new(file))),
+            format: BlockFormat::Framed,
+            records: Records::Framed {
+                reader,
+                offset: BLOCK_HEADER_SIZE,
+                file_len,
+            },
+            block_id,
+            codec: codec.clone(),
+            finished: false,
         })
     }
+
+    #[inline]
+    pub(crate) fn format(&self) -> BlockFormat {
+        self.format
+    }
+
+    /// Returns the length of the prefix of the file that has been read
+    /// successfully so far.
+    pub(crate) fn valid_len(&self) -> u64 {
+        match &self.records {
+            Records::Legacy(deserializer) => deserializer.byte_offset() as u64,
+            Records::Framed { offset, .. } => *offset,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<BlockRecord>, PersistentDbError> {
+        match &mut self.records {
+            Records::Legacy(deserializer) => match deserializer.next() {
+                Some(Ok(record)) => Ok(Some(record)),
+                Some(Err(err)) if err.is_eof() || err.is_syntax() => {
+                    Err(PersistentDbError::CorruptedBlock {
+                        offset: deserializer.byte_offset() as u64,
+                    })
+                }
+                Some(Err(err)) => Err(err.into()),
+                None => Ok(None),
+            },
+            Records::Framed {
+                reader,
+                offset,
+                file_len,
+            } => {
+                let corrupted = PersistentDbError::CorruptedBlock { offset: *offset };
+
+                let mut header = [0; RECORD_HEADER_SIZE as usize];
+                match read_full(reader, &mut header)? {
+                    0 => return Ok(None),
+                    n if n < header.len() => return Err(corrupted),
+                    _ => {}
+                }
+
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+                if *offset + RECORD_HEADER_SIZE + len > *file_len {
+                    return Err(corrupted);
+                }
+
+                let mut payload = vec![0; len as usize];
+                if read_full(reader, &mut payload)? < payload.len()
+                    || crc32fast::hash(&payload) != checksum
+                {
+                    return Err(corrupted);
+                }
+
+                let location = Location::Record {
+                    block: self.block_id,
+                    offset: *offset,
+                };
+                let record = serde_json::from_slice(&self.codec.decode(&payload, location)?)?;
+                *offset += RECORD_HEADER_SIZE + len;
+                Ok(Some(record))
+            }
+        }
+    }
 }
 
 impl Iterator for InactiveBlockFile {
     type Item = Result<BlockRecord, PersistentDbError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.deserializer.next().map(|res| res.map_err(Into::into))
+        if self.finished {
+            return None;
+        }
+
+        let res = self.next_record().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.finished = true;
+        }
+        res
+    }
+}
+
+/// Checks every record in the block file and truncates a torn tail left
+/// behind by a crash.
+///
+/// Only a damaged last record counts as torn, a damaged record followed by
+/// further data is reported as [`PersistentDbError::CorruptedBlock`] instead
+/// of discarding the records after it.
+///
+/// Returns the format of the file and the stamp of its last valid record.
+pub(crate) fn recover_block_file(
+    path: &Path,
+    block_id: usize,
+    codec: &Codec,
+) -> Result<(BlockFormat, Option<RecordStamp>), PersistentDbError> {
+    let mut block = match InactiveBlockFile::open(path, block_id, codec) {
+        Ok(block) => block,
+        Err(PersistentDbError::CorruptedBlock { .. }) => {
+            // the header itself is torn, so the file cannot contain any records
+            tracing::warn!(path = %path.display(), "truncate block file with torn header");
+            DataFile::open_write(path)?.set_len(0)?;
+            return Ok((BlockFormat::Framed, None));
+        }
+        Err(err) => return Err(err),
+    };
+    let format = block.format();
+    let mut records = 0;
+    let mut last_stamp = None;
+
+    let err = loop {
+        match block.next() {
+            Some(Ok(record)) => {
+                records += 1;
+                last_stamp = Some(record.stamp());
+            }
+            Some(Err(err)) => break err,
+            None => return Ok((format, last_stamp)),
+        }
+    };
+
+    if !matches!(err, PersistentDbError::CorruptedBlock { .. }) {
+        return Err(err);
+    }
+
+    let valid_len = block.valid_len();
+    let data = fs::read(path)?;
+    if !is_torn_tail(format, &data[valid_len as usize..]) {
+        return Err(err);
+    }
+
+    let file = DataFile::open_write(path)?;
+    tracing::warn!(
+        path = %path.display(),
+        valid_records = records,
+        offset = valid_len,
+        lost_bytes = data.len() as u64 - valid_len,
+        "truncate torn block tail"
+    );
+    file.set_len(valid_len)?;
+    file.sync_all()?;
+    Ok((format, last_stamp))
+}
+
+/// Whether `tail`, which starts with a damaged record, contains nothing but
+/// that record.
+fn is_torn_tail(format: BlockFormat, tail: &[u8]) -> bool {
+    // file systems may zero fill the end of a file after a crash
+    if tail.iter().all(|&byte| byte == 0) {
+        return true;
+    }
+
+    match format {
+        BlockFormat::Legacy => matches!(
+            serde_json::Deserializer::from_slice(tail)
+                .into_iter::<serde::de::IgnoredAny>()
+                .next(),
+            Some(Err(err)) if err.is_eof()
+        ),
+        BlockFormat::Framed => match tail.get(..RECORD_HEADER_SIZE as usize) {
+            Some(header) => {
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                RECORD_HEADER_SIZE + len >= tail.len() as u64
+            }
+            None => true,
+        },
+    }
+}
+
+/// Returns the stamp of the last record in the block file.
+pub(crate) fn last_record_stamp(
+    path: &Path,
+    block_id: usize,
+    codec: &Codec,
+) -> Result<Option<RecordStamp>, PersistentDbError> {
+    let mut last_stamp = None;
+    for res in InactiveBlockFile::open(path, block_id, codec)? {
+        last_stamp = Some(res?.stamp());
+    }
+    Ok(last_stamp)
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, PersistentDbError> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use super::*;
+
+    fn patch(n: i32) -> Vec<JsonPatch> {
+        vec![JsonPatch::Add {
+            path: json_pointer!("/a"),
+            value: json!(n),
+        }]
+    }
+
+    fn read_all(path: &Path) -> Vec<Vec<JsonPatch>> {
+        InactiveBlockFile::open(path, 1, &Codec::default())
+            .unwrap()
+            .map(|res| res.unwrap().patch_records)
+            .collect()
+    }
+
+    fn write_records(path: &Path, count: i32) {
+        let mut block = ActiveBlockFile::open(path, 1, u64::MAX, Codec::default()).unwrap();
+        for n in 0..count {
+            block
+                .append(BlockRecordRef {
+                    seq: n as u64,
+                    timestamp: 0,
+                    prefix: None,
+                    patch_records: &patch(n),
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.block");
+        write_records(&path, 3);
+
+        assert_eq!(read_all(&path), vec![patch(0), patch(1), patch(2)]);
+        assert_eq!(
+            recover_block_file(&path, 1, &Codec::default()).unwrap(),
+            (
+                BlockFormat::Framed,
+                Some(RecordStamp {
+                    seq: 2,
+                    timestamp: 0
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn test_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.block");
+        let mut block = ActiveBlockFile::open(&path, 1, 1, Codec::default()).unwrap();
+        let record = BlockRecordRef {
+            seq: 1,
+            timestamp: 0,
+            prefix: None,
+            patch_records: &patch(0),
+        };
+
+        // the first record fits even if it is larger than the limit
+        block.append(record).unwrap();
+        assert!(matches!(
+            block.append(record),
+            Err(PersistentDbError::BlockFileIsFull)
+        ));
+        assert_eq!(read_all(&path), vec![patch(0)]);
+    }
+
+    #[test]
+    fn test_recover_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.block");
+        write_records(&path, 3);
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        assert!(matches!(
+            InactiveBlockFile::open(&path, 1, &Codec::default())
+                .unwrap()
+                .nth(2),
+            Some(Err(PersistentDbError::CorruptedBlock { .. }))
+        ));
+
+        recover_block_file(&path, 1, &Codec::default()).unwrap();
+        assert_eq!(read_all(&path), vec![patch(0), patch(1)]);
+
+        write_records(&path, 1);
+        assert_eq!(read_all(&path), vec![patch(0), patch(1), patch(0)]);
+    }
+
+    #[test]
+    fn test_recover_corrupted_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.block");
+        write_records(&path, 2);
+
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 2;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        recover_block_file(&path, 1, &Codec::default()).unwrap();
+        assert_eq!(read_all(&path), vec![patch(0)]);
+    }
+
+    #[test]
+    fn test_corrupted_record_before_valid_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.block");
+        write_records(&path, 3);
+
+        // flip a byte in the payload of the second record
+        let mut data = std::fs::read(&path).unwrap();
+        let len = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+        let second = 8 + 8 + len;
+        data[second + 8] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        assert!(matches!(
+            recover_block_file(&path, 1, &Codec::default()),
+            Err(PersistentDbError::CorruptedBlock { offset }) if offset == second as u64
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_legacy_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.block");
+        let mut data = Vec::new();
+        for n in 0..2 {
+            data.extend(
+                // records written by older versions have no stamp
+                serde_json::to_vec(&json!({ "patch_records": patch(n) })).unwrap(),
+            );
+        }
+        data.extend_from_slice(br#"{"patch_records":[{"op":"add","#);
+        std::fs::write(&path, data).unwrap();
+
+        assert_eq!(
+            recover_block_file(&path, 1, &Codec::default()).unwrap(),
+            (BlockFormat::Legacy, Some(RecordStamp::default()))
+        );
+        assert_eq!(read_all(&path), vec![patch(0), patch(1)]);
     }
 }
@@ -0,0 +1,57 @@
+use std::borrow::Cow;
+
+use crate::{
+    compression::{compress, decompress},
+    encryption::{decrypt, encrypt, Location},
+    Compression, EncryptionKey, PersistentDbError, PersistentDbOptions,
+};
+
+/// Turns snapshots and block records into the bytes written to the disk,
+/// compressing them first and encrypting them afterwards.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Codec {
+    compression: Compression,
+    key: Option<EncryptionKey>,
+    allow_unencrypted: bool,
+}
+
+impl Codec {
+    pub(crate) fn new(options: &PersistentDbOptions) -> Self {
+        Self {
+            compression: options.compression,
+            key: options.encryption_key.clone(),
+            allow_unencrypted: options.allow_unencrypted,
+        }
+    }
+
+    pub(crate) fn encode(
+        &self,
+        data: Vec<u8>,
+        location: Location,
+    ) -> Result<Vec<u8>, PersistentDbError> {
+        Ok(encrypt(
+            compress(data, self.compression)?,
+            self.key.as_ref(),
+            location,
+        ))
+    }
+
+    /// Reverses [`Codec::encode`] for data read from the same `location`,
+    /// whatever compression it used.
+    pub(crate) fn decode<'a>(
+        &self,
+        data: &'a [u8],
+        location: Location,
+    ) -> Result<Cow<'a, [u8]>, PersistentDbError> {
+        match decrypt(data, self.key.as_ref(), location, self.allow_unencrypted)? {
+            Cow::Borrowed(data) => decompress(data),
+            Cow::Owned(data) => {
+                let decompressed = match decompress(&data)? {
+                    Cow::Owned(decompressed) => Some(decompressed),
+                    Cow::Borrowed(_) => None,
+                };
+                Ok(Cow::Owned(decompressed.unwrap_or(data)))
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::borrow::Cow;
+
+use crate::{Compression, PersistentDbError};
+
+/// The magic number at the start of every zstd frame, JSON never starts with
+/// these bytes.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compresses `data`, keeping it as it is if that is not smaller.
+pub(crate) fn compress(
+    data: Vec<u8>,
+    compression: Compression,
+) -> Result<Vec<u8>, PersistentDbError> {
+    match compression {
+        Compression::None => Ok(data),
+        Compression::Zstd(level) => {
+            let compressed = zstd::bulk::compress(&data, level)?;
+            if compressed.len() < data.len() {
+                Ok(compressed)
+            } else {
+                Ok(data)
+            }
+        }
+    }
+}
+
+/// Decompresses `data` if it has been compressed by [`compress`].
+pub(crate) fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>, PersistentDbError> {
+    if data.starts_with(&ZSTD_MAGIC) {
+        Ok(Cow::Owned(zstd::stream::decode_all(data)?))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = br#"{"items":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]}"#.to_vec();
+
+        let compressed = compress(data.clone(), Compression::Zstd(3)).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed).unwrap(), &data[..]);
+
+        // data which does not get smaller is stored as it is
+        let small = br#"{"a":1}"#.to_vec();
+        assert_eq!(
+            compress(small.clone(), Compression::Zstd(3)).unwrap(),
+            small
+        );
+        assert_eq!(decompress(&small).unwrap(), &small[..]);
+    }
+}
@@ -1,39 +1,128 @@
 use std::{
+    io::ErrorKind,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
-    time::Instant,
+    time::{Duration, Instant, SystemTime},
 };
 
 use json_patch::JsonPatch;
 use json_pointer::JsonPointer;
-use memdb::MemDb;
+use memdb::{MemDb, Node};
 
 use crate::{
-    block_file::{ActiveBlockFile, BlockRecordRef, InactiveBlockFile},
-    PersistentDbError,
+    backup::{copy_data_dir, sync_parent_dir, temp_path, Backup, KeepFiles},
+    block_file::{
+        last_record_stamp, recover_block_file, ActiveBlockFile, BlockFormat, BlockRecordRef,
+        InactiveBlockFile, RecordStamp,
+    },
+    codec::Codec,
+    encryption::Location,
+    fs::{self, sync_dir},
+    inspect::{BlockInfo, DataDirInfo, LogRecords, SnapshotInfo},
+    lock::DirLock,
+    manifest::{write_file_atomic, Manifest, SnapshotMeta, LEGACY_SNAPSHOT_FILE_NAME},
+    recovery::unix_millis,
+    seed::{read_seed, SeedFormat},
+    snapshot::{decode_snapshot, encode_snapshot},
+    storage::{Change, Changes, LazySnapshot},
+    Durability, PersistentDbError, PersistentDbOptions, RecoveryTarget, SnapshotRetention,
 };
 
-const SNAPSHOT_FILE_NAME: &str = "snapshot.data";
 const TEMP_SNAPSHOT_FILE_NAME: &str = "snapshot.temp";
 
 pub struct PersistentDb {
     path: PathBuf,
     active_block: Option<(usize, ActiveBlockFile)>,
+    /// Index of the last block file covered by a snapshot, new block files
+    /// are numbered after it while there is no active block file.
+    base_block: usize,
+    /// Stamp of the last appended record.
+    last_record: RecordStamp,
+    options: PersistentDbOptions,
+    codec: Codec,
+    /// Whether there are appended records which have not been synced yet.
+    dirty: bool,
+    /// Stamp of the last synced record while there are unsynced ones.
+    synced_record: RecordStamp,
+    last_sync: Instant,
     compacting: Arc<AtomicBool>,
+    /// Number of unfinished backups and [`Changes`], compaction keeps
+    /// obsolete files while there are any.
+    backups: Arc<AtomicUsize>,
+    /// `None` if the database is opened read-only.
+    _lock: Option<DirLock>,
 }
 
 impl PersistentDb {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self, PersistentDbError> {
+        Self::open_with_options(path, PersistentDbOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: PersistentDbOptions,
+    ) -> Result<Self, PersistentDbError> {
         let path = path.into();
+        let codec = Codec::new(&options);
+        if options.read_only {
+            let manifest = Manifest::load(&path)?;
+            return Ok(Self {
+                path,
+                active_block: None,
+                base_block: manifest.last_block(),
+                last_record: manifest.last_record(),
+                options,
+                codec,
+                dirty: false,
+                synced_record: manifest.last_record(),
+                last_sync: Instant::now(),
+                compacting: Arc::new(AtomicBool::new(false)),
+                backups: Arc::new(AtomicUsize::new(0)),
+                _lock: None,
+            });
+        }
+
         std::fs::create_dir_all(&path)?;
+        let lock = DirLock::acquire(&path)?;
+        let manifest = Manifest::load(&path)?;
+        remove_obsolete_files(&path, &manifest)?;
         let blocks = get_block_list(&path)?;
+        let mut last_record = manifest.last_record();
 
         let active_block = match blocks.last().copied() {
             Some(index) => {
-                let active_block = ActiveBlockFile::open(path.join(format!("{}.block", index)))?;
+                let (format, mut last_stamp) =
+                    recover_block_file(&path.join(format!("{}.block", index)), index, &codec)?;
+                // the last block file is empty if it was created just before a crash
+                for block_id in blocks.iter().rev().skip(1) {
+                    if last_stamp.is_some() {
+                        break;
+                    }
+                    last_stamp = last_record_stamp(
+                        &path.join(format!("{}.block", block_id)),
+                        *block_id,
+                        &codec,
+                    )?;
+                }
+                if let Some(last_stamp) = last_stamp {
+                    last_record.seq = last_record.seq.max(last_stamp.seq);
+                    last_record.timestamp = last_stamp.timestamp;
+                }
+
+                let index = match format {
+                    BlockFormat::Framed => index,
+                    // never mix record formats within a block file
+                    BlockFormat::Legacy => index + 1,
+                };
+                let active_block = ActiveBlockFile::open(
+                    path.join(format!("{}.block", index)),
+                    index,
+                    options.max_block_size,
+                    codec.clone(),
+                )?;
                 Some((index, active_block))
             }
             None => None,
@@ -42,22 +131,424 @@ impl PersistentDb {
         Ok(Self {
             path,
             active_block,
+            base_block: manifest.last_block(),
+            last_record,
+            options,
+            codec,
+            dirty: false,
+            synced_record: last_record,
+            last_sync: Instant::now(),
             compacting: Arc::new(AtomicBool::new(false)),
+            backups: Arc::new(AtomicUsize::new(0)),
+            _lock: Some(lock),
         })
     }
 
     pub fn create_memdb(&self) -> Result<MemDb, PersistentDbError> {
+        self.load(None)
+    }
+
+    /// Rebuilds the document as it was after the last record included by
+    /// `target`, without changing the data directory.
+    ///
+    /// Fails with [`PersistentDbError::RecoveryTargetUnavailable`] if the
+    /// records before `target` have already been folded into the snapshot.
+    pub fn create_memdb_at(&self, target: RecoveryTarget) -> Result<MemDb, PersistentDbError> {
+        self.load(Some(target))
+    }
+
+    /// Rebuilds the document like [`create_memdb_at`] and writes it as the
+    /// new snapshot, discarding all records after `target`.
+    ///
+    /// Records appended afterwards continue the sequence numbers of the
+    /// discarded records.
+    ///
+    /// [`create_memdb_at`]: PersistentDb::create_memdb_at
+    pub fn recover_to(&mut self, target: RecoveryTarget) -> Result<MemDb, PersistentDbError> {
+        self.exclusive_compact(|pdb| {
+            let memdb = pdb.create_memdb_at(target)?;
+            let last_block = pdb.close_active_block()?;
+            tracing::warn!(%target, last_block, "discard records after the recovery target");
+            do_compact(
+                &pdb.path,
+                last_block,
+                pdb.last_record,
+                memdb.root_node(),
+                &pdb.options,
+                &pdb.backups,
+            )?;
+            Ok(memdb)
+        })
+    }
+
+    /// Loads the snapshot generation `generation` and writes it as the new
+    /// snapshot, discarding all records after it.
+    ///
+    /// Like with [`recover_to`], records appended afterwards continue the
+    /// sequence numbers of the discarded records. The newer generations stay
+    /// available as far as the [`SnapshotRetention`] keeps them.
+    ///
+    /// [`recover_to`]: PersistentDb::recover_to
+    pub fn start_from_generation(&mut self, generation: u64) -> Result<MemDb, PersistentDbError> {
+        self.exclusive_compact(|pdb| {
+            let manifest = Manifest::load(&pdb.path)?;
+            let snapshot = manifest
+                .snapshots()
+                .find(|snapshot| snapshot.generation == generation)
+                .ok_or(PersistentDbError::GenerationNotFound { generation })?;
+            let memdb = read_snapshot(&pdb.path, snapshot, &pdb.codec)?;
+            let last_block = pdb.close_active_block()?;
+            tracing::warn!(
+                generation,
+                last_block,
+                "discard records after the snapshot generation"
+            );
+            do_compact(
+                &pdb.path,
+                last_block,
+                pdb.last_record,
+                memdb.root_node(),
+                &pdb.options,
+                &pdb.backups,
+            )?;
+            Ok(memdb)
+        })
+    }
+
+    /// Replaces the document with the one read from the file `source` and
+    /// writes it as the new snapshot.
+    ///
+    /// Fails with [`PersistentDbError::NotEmpty`] if the data directory
+    /// already contains a snapshot or block files, unless `force` is set.
+    /// Records appended afterwards continue the sequence numbers of the
+    /// replaced ones.
+    pub fn seed(
+        &mut self,
+        source: impl AsRef<Path>,
+        format: SeedFormat,
+        force: bool,
+    ) -> Result<MemDb, PersistentDbError> {
+        let source = source.as_ref();
+        self.exclusive_compact(|pdb| {
+            let has_data = Manifest::load(&pdb.path)?.snapshot.is_some()
+                || !get_block_list(&pdb.path)?.is_empty();
+            if has_data && !force {
+                return Err(PersistentDbError::NotEmpty {
+                    path: pdb.path.clone(),
+                });
+            }
+
+            tracing::info!(source = %source.display(), %format, "seed data directory");
+            let memdb = read_seed(source, format)?;
+            let last_block = pdb.close_active_block()?;
+            do_compact(
+                &pdb.path,
+                last_block,
+                pdb.last_record,
+                memdb.root_node(),
+                &pdb.options,
+                &pdb.backups,
+            )?;
+            Ok(memdb)
+        })
+    }
+
+    /// Writes `snapshot`, the document after the last appended record, as the
+    /// new snapshot and waits until it is done.
+    ///
+    /// Unlike [`PersistentDb::compact`] the snapshot is written even if there
+    /// are only a few block files.
+    pub fn compact_now(&mut self, snapshot: Node) -> Result<(), PersistentDbError> {
+        self.exclusive_compact(|pdb| {
+            let last_block = pdb.close_active_block()?;
+            do_compact(
+                &pdb.path,
+                last_block,
+                pdb.last_record,
+                &snapshot,
+                &pdb.options,
+                &pdb.backups,
+            )
+        })
+    }
+
+    /// Replaces all data with `snapshot`, the document after the record
+    /// stamped with `stamp` in another database, and waits until it is done.
+    ///
+    /// Records appended afterwards continue after `stamp`.
+    pub fn reset(&mut self, snapshot: Node, stamp: RecordStamp) -> Result<(), PersistentDbError> {
+        self.exclusive_compact(|pdb| {
+            let last_block = pdb.close_active_block()?;
+            pdb.last_record = stamp;
+            do_compact(
+                &pdb.path,
+                last_block,
+                stamp,
+                &snapshot,
+                &pdb.options,
+                &pdb.backups,
+            )
+        })
+    }
+
+    /// Returns the changes after the record with the sequence number `seq`.
+    ///
+    /// If some of them have already been folded into the snapshot, the
+    /// snapshot comes first and the changes continue after it. Compaction
+    /// keeps the files they are read from until they are dropped.
+    pub fn changes_since(&self, seq: u64) -> Result<Changes, PersistentDbError> {
+        if seq > self.last_record.seq {
+            return Err(PersistentDbError::AheadOfLog {
+                seq,
+                last_seq: self.last_record.seq,
+            });
+        }
+
+        // must be registered before the manifest is read, like for a backup
+        let keep_files = Arc::new(KeepFiles::new(self.backups.clone()));
+        let manifest = Manifest::load(&self.path)?;
+        let snapshot = match &manifest.snapshot {
+            // a follower without any data gets the snapshot even if it does
+            // not have a sequence number
+            Some(snapshot) if seq < snapshot.last_seq || seq == 0 => {
+                let path = self.path.clone();
+                let snapshot = snapshot.clone();
+                let codec = self.codec.clone();
+                let keep_files = keep_files.clone();
+                Some(LazySnapshot::new(manifest.last_record(), move || {
+                    let _keep_files = keep_files;
+                    Ok(read_snapshot(&path, &snapshot, &codec)?.snapshot())
+                }))
+            }
+            _ => None,
+        };
+        let changes = self.log_records_after(&manifest)?.filter_map(move |res| {
+            let _keep_files = &keep_files;
+            match res {
+                Ok(record) if record.seq <= seq => None,
+                Ok(record) => Some(Ok(Change {
+                    seq: record.seq,
+                    timestamp: record.timestamp,
+                    prefix: record.prefix,
+                    patch: record.patch,
+                })),
+                Err(err) => Some(Err(err)),
+            }
+        });
+        Ok(Changes {
+            snapshot,
+            changes: Box::new(changes),
+        })
+    }
+
+    /// Stamp of the last appended record.
+    #[inline]
+    pub fn last_record(&self) -> RecordStamp {
+        self.last_record
+    }
+
+    /// Stamp of the last record which survives a crash.
+    ///
+    /// With [`Durability::None`] nothing is synced before the database is
+    /// closed, so this is the last appended record, which may be lost.
+    pub fn synced_record(&self) -> RecordStamp {
+        if self.dirty && self.options.durability != Durability::None {
+            self.synced_record
+        } else {
+            self.last_record
+        }
+    }
+
+    /// Runs `f` once no compaction is running in the background, and keeps
+    /// new ones from starting meanwhile.
+    fn exclusive_compact<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, PersistentDbError>,
+    ) -> Result<T, PersistentDbError> {
+        if self.options.read_only {
+            return Err(PersistentDbError::ReadOnly);
+        }
+
+        // a snapshot written in the background must not replace this one
+        while self
+            .compacting
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire)
+            .is_err()
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let res = f(self);
+        self.compacting.store(false, Ordering::SeqCst);
+        res
+    }
+
+    /// Describes the snapshot and block files of the data directory.
+    pub fn inspect(&self) -> Result<DataDirInfo, PersistentDbError> {
+        let manifest = Manifest::load(&self.path)?;
+        let snapshot = match &manifest.snapshot {
+            Some(snapshot) => Some(self.snapshot_info(snapshot)?),
+            None => None,
+        };
+        let generations = manifest
+            .generations
+            .iter()
+            .map(|snapshot| self.snapshot_info(snapshot))
+            .collect::<Result<_, _>>()?;
+
+        let mut blocks = Vec::new();
+        for block_id in get_block_list(&self.path)? {
+            blocks.push(BlockInfo {
+                id: block_id,
+                size: std::fs::metadata(self.path.join(format!("{}.block", block_id)))?.len(),
+                obsolete: block_id <= manifest.last_block(),
+            });
+        }
+
+        Ok(DataDirInfo {
+            snapshot,
+            generations,
+            blocks,
+        })
+    }
+
+    /// Lists the current snapshot and the older ones kept by the
+    /// [`SnapshotRetention`], newest first.
+    pub fn generations(&self) -> Result<Vec<SnapshotInfo>, PersistentDbError> {
+        Manifest::load(&self.path)?
+            .snapshots()
+            .map(|snapshot| self.snapshot_info(snapshot))
+            .collect()
+    }
+
+    fn snapshot_info(&self, snapshot: &SnapshotMeta) -> Result<SnapshotInfo, PersistentDbError> {
+        Ok(SnapshotInfo {
+            file_name: snapshot.file_name.clone(),
+            size: std::fs::metadata(self.path.join(&snapshot.file_name))?.len(),
+            last_block: snapshot.last_block,
+            last_seq: snapshot.last_seq,
+            last_timestamp: snapshot.last_timestamp,
+            generation: snapshot.generation,
+            created_at: snapshot.created_at,
+        })
+    }
+
+    /// Loads the snapshot without replaying any records.
+    pub fn load_snapshot(&self) -> Result<MemDb, PersistentDbError> {
+        load_snapshot(&self.path, &Manifest::load(&self.path)?, &self.codec)
+    }
+
+    /// Returns the records which have not been folded into the snapshot, in
+    /// the order they have been appended.
+    pub fn log_records(&self) -> Result<LogRecords, PersistentDbError> {
+        self.log_records_after(&Manifest::load(&self.path)?)
+    }
+
+    fn log_records_after(&self, manifest: &Manifest) -> Result<LogRecords, PersistentDbError> {
+        let blocks = get_block_list(&self.path)?
+            .into_iter()
+            .filter(|block_id| *block_id > manifest.last_block())
+            .collect();
+        Ok(LogRecords::new(
+            self.path.clone(),
+            self.codec.clone(),
+            blocks,
+        ))
+    }
+
+    fn load(&self, target: Option<RecoveryTarget>) -> Result<MemDb, PersistentDbError> {
         tracing::info!(path = %self.path.display(), "load data from persistentdb");
-        load_memdb(&self.path, &get_block_list(&self.path)?)
+        let mut retries = 0;
+        loop {
+            let manifest = Manifest::load(&self.path)?;
+            match load_memdb(
+                &self.path,
+                &manifest,
+                &get_block_list(&self.path)?,
+                &self.codec,
+                self.options.read_only,
+                target,
+            ) {
+                // a writer has replaced the snapshot and removed the files it covers
+                Err(PersistentDbError::Io(err))
+                    if self.options.read_only
+                        && err.kind() == ErrorKind::NotFound
+                        && retries < 3 =>
+                {
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
     }
 
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    #[inline]
+    pub fn durability(&self) -> Durability {
+        self.options.durability
+    }
+
+    /// How often [`compact`] should be called, see
+    /// [`PersistentDbOptions::compact_interval`].
+    ///
+    /// [`compact`]: PersistentDb::compact
+    #[inline]
+    pub fn compact_interval(&self) -> Duration {
+        self.options.compact_interval
+    }
+
+    /// Appends a record to the active block file.
+    ///
+    /// The record is not guaranteed to be on the disk until [`commit`] or
+    /// [`sync`] has been called.
+    ///
+    /// [`commit`]: PersistentDb::commit
+    /// [`sync`]: PersistentDb::sync
     pub fn append(
         &mut self,
         prefix: Option<&JsonPointer>,
         patch_records: &[JsonPatch],
-        flush: bool,
+    ) -> Result<RecordStamp, PersistentDbError> {
+        let stamp = RecordStamp {
+            seq: self.last_record.seq + 1,
+            timestamp: unix_millis(SystemTime::now()),
+        };
+        self.append_stamped(stamp, prefix, patch_records)?;
+        Ok(stamp)
+    }
+
+    /// Appends a change read from another database, keeping its sequence
+    /// number and timestamp.
+    ///
+    /// The sequence number must be larger than that of every record appended
+    /// before.
+    pub fn append_change(&mut self, change: &Change) -> Result<(), PersistentDbError> {
+        self.append_stamped(
+            RecordStamp {
+                seq: change.seq,
+                timestamp: change.timestamp,
+            },
+            change.prefix.as_ref(),
+            &change.patch,
+        )
+    }
+
+    fn append_stamped(
+        &mut self,
+        stamp: RecordStamp,
+        prefix: Option<&JsonPointer>,
+        patch_records: &[JsonPatch],
     ) -> Result<(), PersistentDbError> {
+        if self.options.read_only {
+            return Err(PersistentDbError::ReadOnly);
+        }
+
         let record = BlockRecordRef {
+            seq: stamp.seq,
+            timestamp: stamp.timestamp,
             prefix,
             patch_records,
         };
@@ -65,48 +556,283 @@ impl PersistentDb {
         let new_index = match &mut self.active_block {
             Some((index, block)) => match block.append(record) {
                 Ok(()) => {
-                    if flush {
-                        block.flush()?;
-                    }
+                    self.dirty = true;
+                    self.last_record = stamp;
                     return Ok(());
                 }
                 Err(PersistentDbError::BlockFileIsFull) => {
+                    // records in the full block file must not be left behind unsynced
+                    if self.options.durability != Durability::None {
+                        block.sync()?;
+                        self.synced_record = self.last_record;
+                    }
                     // create a new block file
                     *index + 1
                 }
                 Err(err) => return Err(err),
             },
-            None => 1,
+            None => self.base_block + 1,
         };
 
-        let mut block_file = ActiveBlockFile::open(self.path.join(format!("{}.block", new_index)))?;
+        let block_file = ActiveBlockFile::open(
+            self.path.join(format!("{}.block", new_index)),
+            new_index,
+            self.options.max_block_size,
+            self.codec.clone(),
+        )?;
+        if self.options.durability != Durability::None {
+            sync_dir(&self.path)?;
+        }
+        // a retry after a failed append must reuse the file to clean it up
+        let (_, block_file) = self.active_block.insert((new_index, block_file));
         block_file.append(record)?;
-        if flush {
-            block_file.flush()?;
+        self.dirty = true;
+        self.last_record = stamp;
+        Ok(())
+    }
+
+    /// Ends a batch of appends, syncing the active block file if the
+    /// configured [`Durability`] requires it.
+    pub fn commit(&mut self) -> Result<(), PersistentDbError> {
+        match self.options.durability {
+            Durability::None => Ok(()),
+            Durability::Periodic(interval) if self.last_sync.elapsed() < interval => Ok(()),
+            Durability::Periodic(_) | Durability::Batch => self.sync(),
+        }
+    }
+
+    /// Forces all appended records to the disk.
+    pub fn sync(&mut self) -> Result<(), PersistentDbError> {
+        if self.dirty {
+            if let Some((_, block)) = &mut self.active_block {
+                block.sync()?;
+            }
+            self.dirty = false;
+            self.synced_record = self.last_record;
         }
-        self.active_block = Some((new_index, block_file));
+        self.last_sync = Instant::now();
         Ok(())
     }
 
-    pub fn compact(&self) {
+    /// Returns how long [`commit`] may defer syncing, if the records should
+    /// be synced in the background when no more appends arrive.
+    ///
+    /// [`commit`]: PersistentDb::commit
+    pub fn pending_sync_deadline(&self) -> Option<Duration> {
+        match self.options.durability {
+            Durability::Periodic(interval) if self.dirty => {
+                Some(interval.saturating_sub(self.last_sync.elapsed()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes `snapshot` to the disk in the background if the block files not
+    /// covered by the last snapshot exceed the thresholds configured in
+    /// [`PersistentDbOptions`].
+    ///
+    /// `snapshot` must contain exactly the records appended so far, usually
+    /// it is taken from the [`MemDb`] returned by [`create_memdb`] which has
+    /// been updated with the same changes. The block files it covers are
+    /// removed once it has been written.
+    ///
+    /// [`create_memdb`]: PersistentDb::create_memdb
+    pub fn compact(&mut self, snapshot: Node) -> Result<(), PersistentDbError> {
+        if self.options.read_only {
+            return Err(PersistentDbError::ReadOnly);
+        }
+
         if self
             .compacting
             .compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire)
-            .is_ok()
+            .is_err()
         {
-            let compacting = self.compacting.clone();
-            let path = self.path.clone();
-            std::thread::spawn(move || {
-                if let Err(err) = do_compact(&path) {
-                    tracing::error!(error = %err, "failed to compact data");
+            return Ok(());
+        }
+
+        let last_record = self.last_record;
+        let last_block = match self.start_compact() {
+            Ok(Some(last_block)) => last_block,
+            res => {
+                self.compacting.store(false, Ordering::SeqCst);
+                return res.map(|_| ());
+            }
+        };
+
+        let compacting = self.compacting.clone();
+        let path = self.path.clone();
+        let options = self.options.clone();
+        let backups = self.backups.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = do_compact(
+                &path,
+                last_block,
+                last_record,
+                &snapshot,
+                &options,
+                &backups,
+            ) {
+                tracing::error!(error = %err, "failed to compact data");
+            }
+            compacting.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    /// Returns `true` while a snapshot is being written in the background.
+    #[inline]
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.load(Ordering::SeqCst)
+    }
+
+    /// Copies the records appended so far into the new directory `dest`, see
+    /// [`begin_backup`].
+    ///
+    /// [`begin_backup`]: PersistentDb::begin_backup
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<(), PersistentDbError> {
+        self.begin_backup()?.write_to(dest)
+    }
+
+    /// Collects the files which make up the records appended so far, to be
+    /// copied with [`Backup::write_to`] while appending continues.
+    pub fn begin_backup(&self) -> Result<Backup, PersistentDbError> {
+        // must be registered before the manifest is read, so a compaction
+        // finishing in between does not remove the files
+        let mut backup = Backup::new(self.path.clone(), self.backups.clone());
+        let manifest = Manifest::load(&self.path)?;
+
+        let mut blocks = Vec::new();
+        for block_id in get_block_list(&self.path)? {
+            if block_id <= manifest.last_block() {
+                continue;
+            }
+            match &self.active_block {
+                Some((index, block)) if *index == block_id => {
+                    blocks.push((block_id, block.size()));
+                    break;
                 }
-                compacting.store(false, Ordering::SeqCst);
-            });
+                _ => {
+                    let file_name = format!("{}.block", block_id);
+                    blocks.push((
+                        block_id,
+                        std::fs::metadata(self.path.join(file_name))?.len(),
+                    ));
+                }
+            }
+        }
+
+        backup.manifest = manifest;
+        backup.blocks = blocks;
+        Ok(backup)
+    }
+
+    /// Replaces the data directory `path` with a copy of the backup in
+    /// `backup`.
+    ///
+    /// The copy is checked by loading it completely before it is swapped in,
+    /// the previous data directory is kept as `<path>.old-<unix
+    /// milliseconds>`. Fails with [`PersistentDbError::Locked`] while the data
+    /// directory is in use.
+    pub fn restore(
+        backup: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+    ) -> Result<(), PersistentDbError> {
+        Self::restore_with_options(backup, path, &PersistentDbOptions::default())
+    }
+
+    /// Like [`PersistentDb::restore`], reading the backup with the encryption
+    /// key from `options`.
+    pub fn restore_with_options(
+        backup: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+        options: &PersistentDbOptions,
+    ) -> Result<(), PersistentDbError> {
+        let backup = backup.as_ref();
+        let path = path.as_ref();
+        let temp_path = temp_path(path, "restore");
+        tracing::info!(backup = %backup.display(), path = %path.display(), "restore start");
+
+        if temp_path.exists() {
+            std::fs::remove_dir_all(&temp_path)?;
+        }
+        copy_data_dir(backup, &temp_path)?;
+        validate_backup(&temp_path, &Codec::new(options))?;
+
+        if path.exists() {
+            let _lock = DirLock::acquire(path)?;
+            let old_path = old_data_dir_path(path);
+            std::fs::rename(path, &old_path)?;
+            tracing::info!(path = %old_path.display(), "keep previous data directory");
+        }
+        std::fs::rename(&temp_path, path)?;
+        sync_parent_dir(path)?;
+
+        tracing::info!("restore finish");
+        Ok(())
+    }
+
+    /// Closes the active block file if it should be folded into a snapshot,
+    /// and returns the index of the last block file the snapshot covers.
+    fn start_compact(&mut self) -> Result<Option<usize>, PersistentDbError> {
+        let manifest = Manifest::load(&self.path)?;
+        let last_block = match &self.active_block {
+            Some((index, _)) => *index,
+            None => self.base_block,
+        };
+        if !self.should_compact(&manifest, last_block)? {
+            return Ok(None);
+        }
+        self.close_active_block().map(Some)
+    }
+
+    /// Makes the next append start a new block file, and returns the index of
+    /// the last block file.
+    fn close_active_block(&mut self) -> Result<usize, PersistentDbError> {
+        // records in the closed block file must not be left behind unsynced
+        if self.options.durability != Durability::None {
+            self.sync()?;
+        }
+        if let Some((index, _)) = self.active_block.take() {
+            self.base_block = index;
+        }
+        Ok(self.base_block)
+    }
+
+    fn should_compact(
+        &self,
+        manifest: &Manifest,
+        last_block: usize,
+    ) -> Result<bool, PersistentDbError> {
+        let blocks = last_block.saturating_sub(manifest.last_block());
+        if blocks == 0 {
+            return Ok(false);
+        }
+        if blocks > self.options.compact_block_threshold {
+            return Ok(true);
+        }
+
+        match self.options.compact_size_ratio {
+            Some(ratio) => {
+                let mut log_size = 0;
+                for block_id in get_block_list(&self.path)? {
+                    if block_id > manifest.last_block() {
+                        log_size +=
+                            std::fs::metadata(self.path.join(format!("{}.block", block_id)))?.len();
+                    }
+                }
+                // without a snapshot any log is large enough
+                let snapshot_size = match &manifest.snapshot {
+                    Some(snapshot) => std::fs::metadata(self.path.join(&snapshot.file_name))?.len(),
+                    None => 0,
+                };
+                Ok(log_size as f64 > snapshot_size as f64 * ratio)
+            }
+            None => Ok(false),
         }
     }
 }
 
-fn get_block_list(path: &Path) -> Result<Vec<usize>, PersistentDbError> {
+pub(crate) fn get_block_list(path: &Path) -> Result<Vec<usize>, PersistentDbError> {
     let read_dir = path.read_dir()?;
     let mut blocks = Vec::new();
 
@@ -128,47 +854,940 @@ fn get_block_list(path: &Path) -> Result<Vec<usize>, PersistentDbError> {
     Ok(blocks)
 }
 
-fn load_memdb(path: &Path, blocks: &[usize]) -> Result<MemDb, PersistentDbError> {
-    let snapshot_path = path.join(SNAPSHOT_FILE_NAME);
-    let mut db = if snapshot_path.exists() {
-        let value = serde_json::from_slice(&std::fs::read(snapshot_path)?)?;
-        MemDb::new(value)
-    } else {
-        MemDb::default()
-    };
+/// Loads the snapshot and replays the blocks after it, up to `target` if
+/// there is one.
+///
+/// With `allow_torn_tail` a corrupted tail of the last block file, which is
+/// possibly still being written by another process, is ignored.
+fn load_memdb(
+    path: &Path,
+    manifest: &Manifest,
+    blocks: &[usize],
+    codec: &Codec,
+    allow_torn_tail: bool,
+    target: Option<RecoveryTarget>,
+) -> Result<MemDb, PersistentDbError> {
+    let included = |stamp| target.is_none_or(|target| target.includes(stamp));
+    if !included(manifest.last_record()) {
+        return Err(PersistentDbError::RecoveryTargetUnavailable {
+            target: target.unwrap(),
+        });
+    }
+
+    let mut db = load_snapshot(path, manifest, codec)?;
 
+    let mut reached_target = false;
     for block_id in blocks {
-        for res in InactiveBlockFile::open(path.join(format!("{}.block", block_id)))? {
-            let record = res?;
-            db.patch(record.prefix.as_ref(), record.patch_records)?;
+        if *block_id <= manifest.last_block() {
+            // already contained in the snapshot
+            continue;
+        }
+        if reached_target {
+            break;
+        }
+
+        let res =
+            InactiveBlockFile::open(path.join(format!("{}.block", block_id)), *block_id, codec)
+                .and_then(|block| {
+                    for res in block {
+                        let record = res?;
+                        if !included(record.stamp()) {
+                            reached_target = true;
+                            break;
+                        }
+                        db.patch(record.prefix.as_ref(), record.patch_records)?;
+                    }
+                    Ok(())
+                });
+        match res {
+            Err(PersistentDbError::CorruptedBlock { offset })
+                if allow_torn_tail && Some(block_id) == blocks.last() =>
+            {
+                tracing::warn!(block = block_id, offset, "ignore torn block tail");
+            }
+            res => res?,
         }
     }
 
     Ok(db)
 }
 
-fn do_compact(path: &Path) -> Result<(), PersistentDbError> {
+fn load_snapshot(
+    path: &Path,
+    manifest: &Manifest,
+    codec: &Codec,
+) -> Result<MemDb, PersistentDbError> {
+    match &manifest.snapshot {
+        Some(snapshot) => read_snapshot(path, snapshot, codec),
+        None => Ok(MemDb::default()),
+    }
+}
+
+fn read_snapshot(
+    path: &Path,
+    snapshot: &SnapshotMeta,
+    codec: &Codec,
+) -> Result<MemDb, PersistentDbError> {
+    let data = fs::read(&path.join(&snapshot.file_name))?;
+    let location = Location::Snapshot {
+        generation: snapshot.generation,
+    };
+    Ok(MemDb::new(decode_snapshot(
+        &codec.decode(&data, location)?,
+    )?))
+}
+
+fn old_data_dir_path(path: &Path) -> PathBuf {
+    temp_path(path, &format!("old-{}", unix_millis(SystemTime::now())))
+}
+
+/// Checks that `path` contains a data directory which loads without errors.
+fn validate_backup(path: &Path, codec: &Codec) -> Result<(), PersistentDbError> {
+    let manifest = Manifest::load(path)?;
     let blocks = get_block_list(path)?;
+    if manifest.snapshot.is_none() && blocks.is_empty() {
+        return Err(PersistentDbError::InvalidBackup {
+            reason: "neither a snapshot nor block files found".to_string(),
+        });
+    }
+    load_memdb(path, &manifest, &blocks, codec, false, None)?;
+    Ok(())
+}
 
-    if blocks.len() > 5 {
-        let blocks = &blocks[..blocks.len() - 1];
-        let db = load_memdb(path, blocks)?;
-        let now = Instant::now();
+/// Removes block files which are superseded by the snapshot referenced from
+/// the manifest, and snapshots which are not referenced at all.
+///
+/// A legacy snapshot ignored by [`Manifest::load`] is kept until the first
+/// new snapshot has been written.
+fn remove_obsolete_files(path: &Path, manifest: &Manifest) -> Result<(), PersistentDbError> {
+    for block_id in get_block_list(path)? {
+        if block_id <= manifest.last_block() {
+            tracing::debug!(block = block_id, "remove obsolete block file");
+            fs::remove_file(&path.join(format!("{}.block", block_id)))?;
+        }
+    }
 
-        tracing::info!(blocks = ?blocks, "compact start");
+    for res in path.read_dir()? {
+        let entry = res?;
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        if file_name == LEGACY_SNAPSHOT_FILE_NAME && manifest.snapshot.is_none() {
+            continue;
+        }
+        if file_name.starts_with("snapshot")
+            && !manifest
+                .snapshots()
+                .any(|snapshot| snapshot.file_name == file_name)
+        {
+            tracing::debug!(file_name, "remove obsolete snapshot file");
+            fs::remove_file(&entry.path())?;
+        }
+    }
 
-        let data = serde_json::to_vec(db.root())?;
-        std::fs::write(path.join(TEMP_SNAPSHOT_FILE_NAME), data)?;
-        std::fs::rename(
-            path.join(TEMP_SNAPSHOT_FILE_NAME),
-            path.join(SNAPSHOT_FILE_NAME),
-        )?;
+    Ok(())
+}
 
-        tracing::info!(
-            elapsed_seconds = now.elapsed().as_secs_f32(),
-            "compact finish"
-        );
+fn do_compact(
+    path: &Path,
+    last_block: usize,
+    last_record: RecordStamp,
+    root: &Node,
+    options: &PersistentDbOptions,
+    backups: &AtomicUsize,
+) -> Result<(), PersistentDbError> {
+    let now = Instant::now();
+    tracing::info!(last_block, "compact start");
+
+    let previous = Manifest::load(path)?;
+    let generation = previous.next_generation();
+    let file_name = format!("snapshot-{}.data", generation);
+    let data = Codec::new(options).encode(
+        encode_snapshot(root, options.snapshot_format)?,
+        Location::Snapshot { generation },
+    )?;
+    write_file_atomic(path, TEMP_SNAPSHOT_FILE_NAME, &file_name, &data)?;
+
+    let created_at = unix_millis(SystemTime::now());
+    let mut generations = previous
+        .snapshot
+        .into_iter()
+        .chain(previous.generations)
+        .collect::<Vec<_>>();
+    match options.snapshot_retention {
+        SnapshotRetention::Count(count) => generations.truncate(count.saturating_sub(1)),
+        SnapshotRetention::Hours(hours) => {
+            let min_created_at = created_at.saturating_sub(hours * 60 * 60 * 1000);
+            generations.retain(|snapshot| snapshot.created_at >= min_created_at);
+        }
+    }
+
+    // the new snapshot becomes visible only once the manifest is replaced
+    let manifest = Manifest {
+        snapshot: Some(SnapshotMeta {
+            file_name,
+            last_block,
+            last_seq: last_record.seq,
+            last_timestamp: last_record.timestamp,
+            generation,
+            created_at,
+        }),
+        generations,
+    };
+    manifest.store(path)?;
+    if backups.load(Ordering::SeqCst) == 0 {
+        remove_obsolete_files(path, &manifest)?;
+    } else {
+        tracing::debug!("keep obsolete files for an unfinished backup or reader");
     }
 
+    tracing::info!(
+        elapsed_seconds = now.elapsed().as_secs_f32(),
+        "compact finish"
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{Compression, EncryptionKey, SnapshotFormat};
+
+    fn append_item(path: &Path, block_id: usize, n: i32) {
+        ActiveBlockFile::open(
+            path.join(format!("{}.block", block_id)),
+            block_id,
+            u64::MAX,
+            Codec::default(),
+        )
+        .unwrap()
+        .append(BlockRecordRef {
+            seq: n as u64,
+            timestamp: 0,
+            prefix: None,
+            patch_records: &[JsonPatch::Add {
+                path: json_pointer!("/items/-"),
+                value: json!(n),
+            }],
+        })
+        .unwrap();
+    }
+
+    fn compact(pdb: &mut PersistentDb) {
+        let snapshot = pdb.create_memdb().unwrap().snapshot();
+        pdb.compact(snapshot).unwrap();
+        while pdb.is_compacting() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_compact_does_not_replay_snapshot_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/items"),
+                value: json!([]),
+            }],
+        )
+        .unwrap();
+        for n in 2..=7 {
+            append_item(dir.path(), n, n as i32);
+        }
+
+        drop(pdb);
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        compact(&mut pdb);
+        assert_eq!(Manifest::load(dir.path()).unwrap().last_block(), 7);
+        assert!(get_block_list(dir.path()).unwrap().is_empty());
+
+        // the closed block file is not appended to anymore
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/items/-"),
+                value: json!(8),
+            }],
+        )
+        .unwrap();
+        assert_eq!(get_block_list(dir.path()).unwrap(), vec![8]);
+
+        drop(pdb);
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        let expected = json!({ "items": [2, 3, 4, 5, 6, 7, 8] });
+        assert_eq!(pdb.create_memdb().unwrap().root(), expected);
+
+        // compacting again without enough new blocks must not change anything
+        compact(&mut pdb);
+        assert_eq!(Manifest::load(dir.path()).unwrap().last_block(), 7);
+        assert_eq!(pdb.create_memdb().unwrap().root(), expected);
+    }
+
+    #[test]
+    fn test_new_block_after_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/items"),
+                value: json!([1]),
+            }],
+        )
+        .unwrap();
+        drop(pdb);
+        for n in 2..=6 {
+            append_item(dir.path(), n, n as i32);
+        }
+        std::fs::write(dir.path().join("7.block"), b"").unwrap();
+        // older versions folded all but the last block into the snapshot and
+        // kept the blocks
+        std::fs::write(
+            dir.path().join("snapshot.data"),
+            br#"{"items":[1,2,3,4,5,6]}"#,
+        )
+        .unwrap();
+
+        // the blocks are replayed without the legacy snapshot, which is only
+        // removed once a new snapshot replaces it
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        assert!(dir.path().join("snapshot.data").exists());
+        assert_eq!(
+            pdb.create_memdb().unwrap().root(),
+            json!({ "items": [1, 2, 3, 4, 5, 6] })
+        );
+        compact(&mut pdb);
+        assert!(!dir.path().join("snapshot.data").exists());
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/items/-"),
+                value: json!(7),
+            }],
+        )
+        .unwrap();
+        assert_eq!(get_block_list(dir.path()).unwrap(), vec![8]);
+        assert_eq!(
+            pdb.create_memdb().unwrap().root(),
+            json!({ "items": [1, 2, 3, 4, 5, 6, 7] })
+        );
+    }
+
+    #[test]
+    fn test_synced_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = [JsonPatch::Add {
+            path: json_pointer!("/a"),
+            value: json!(1),
+        }];
+        let options = PersistentDbOptions::default()
+            .durability(Durability::Periodic(Duration::from_secs(3600)));
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        pdb.append(None, &patch).unwrap();
+        pdb.commit().unwrap();
+        assert_eq!(pdb.last_record().seq, 1);
+        assert_eq!(pdb.synced_record().seq, 0);
+        pdb.sync().unwrap();
+        assert_eq!(pdb.synced_record().seq, 1);
+        drop(pdb);
+
+        // nothing is ever synced before closing without durability
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        pdb.append(None, &patch).unwrap();
+        assert_eq!(pdb.synced_record().seq, 2);
+    }
+
+    #[test]
+    fn test_compact_size_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = PersistentDbOptions::default()
+            .max_block_size(1)
+            .compact_block_threshold(usize::MAX)
+            .compact_size_ratio(0.1);
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        // without a snapshot any log exceeds the ratio
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/a"),
+                value: json!("a".repeat(2000)),
+            }],
+        )
+        .unwrap();
+        compact(&mut pdb);
+        assert_eq!(Manifest::load(dir.path()).unwrap().last_block(), 1);
+
+        // each block file is smaller than a tenth of the snapshot
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/b"),
+                value: json!(1),
+            }],
+        )
+        .unwrap();
+        compact(&mut pdb);
+        assert_eq!(Manifest::load(dir.path()).unwrap().last_block(), 1);
+
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/b"),
+                value: json!(2),
+            }],
+        )
+        .unwrap();
+        compact(&mut pdb);
+        assert_eq!(Manifest::load(dir.path()).unwrap().last_block(), 3);
+        assert_eq!(
+            pdb.create_memdb()
+                .unwrap()
+                .get(json_pointer!("/b"))
+                .unwrap(),
+            json!(2)
+        );
+    }
+
+    #[test]
+    fn test_open_compressed_without_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = PersistentDbOptions::default()
+            .compression(Compression::Zstd(3))
+            .compact_block_threshold(0);
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        let value = json!(vec!["item"; 100]);
+        let add = |path| {
+            [JsonPatch::Add {
+                path,
+                value: value.clone(),
+            }]
+        };
+
+        // one compressed snapshot and one compressed block file
+        pdb.append(None, &add(json_pointer!("/a"))).unwrap();
+        compact(&mut pdb);
+        pdb.append(None, &add(json_pointer!("/b"))).unwrap();
+        assert!(std::fs::metadata(dir.path().join("2.block")).unwrap().len() < 200);
+
+        drop(pdb);
+        let pdb = PersistentDb::open(dir.path()).unwrap();
+        assert_eq!(
+            pdb.create_memdb().unwrap().root(),
+            json!({ "a": value, "b": value })
+        );
+    }
+
+    #[test]
+    fn test_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = EncryptionKey::new([1; 32]);
+        let options = PersistentDbOptions::default()
+            .compression(Compression::Zstd(3))
+            .encryption_key(key.clone())
+            .compact_block_threshold(0);
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options.clone()).unwrap();
+        let add = |path, name| {
+            [JsonPatch::Add {
+                path,
+                value: json!({ "customer": name }),
+            }]
+        };
+
+        // one encrypted snapshot and one encrypted block file
+        pdb.append(None, &add(json_pointer!("/a"), "alice"))
+            .unwrap();
+        compact(&mut pdb);
+        pdb.append(None, &add(json_pointer!("/b"), "bob")).unwrap();
+        drop(pdb);
+        for file_name in ["snapshot-1.data", "2.block"] {
+            let data = std::fs::read(dir.path().join(file_name)).unwrap();
+            assert!(!data.windows(8).any(|window| window == b"customer"));
+        }
+
+        let pdb = PersistentDb::open_with_options(dir.path(), options.clone()).unwrap();
+        assert_eq!(
+            pdb.create_memdb().unwrap().root(),
+            json!({ "a": { "customer": "alice" }, "b": { "customer": "bob" } })
+        );
+        drop(pdb);
+
+        // a wrong key must not be mistaken for a torn block tail
+        let block_len = std::fs::metadata(dir.path().join("2.block")).unwrap().len();
+        let wrong_key = PersistentDbOptions::default().encryption_key(EncryptionKey::new([2; 32]));
+        assert!(matches!(
+            PersistentDb::open_with_options(dir.path(), wrong_key),
+            Err(PersistentDbError::Decryption)
+        ));
+        assert!(matches!(
+            PersistentDb::open(dir.path()),
+            Err(PersistentDbError::MissingEncryptionKey)
+        ));
+        assert_eq!(
+            std::fs::metadata(dir.path().join("2.block")).unwrap().len(),
+            block_len
+        );
+
+        let snapshot_path = dir.path().join("snapshot-1.data");
+        let mut data = std::fs::read(&snapshot_path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        std::fs::write(&snapshot_path, data).unwrap();
+        let pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        assert!(matches!(
+            pdb.create_memdb(),
+            Err(PersistentDbError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_swapped_encrypted_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = PersistentDbOptions::default().encryption_key(EncryptionKey::new([1; 32]));
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options.clone()).unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Remove {
+                path: json_pointer!("/a"),
+            }],
+        )
+        .unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Remove {
+                path: json_pointer!("/b"),
+            }],
+        )
+        .unwrap();
+        drop(pdb);
+
+        // both records have the same length, so they can be swapped without
+        // breaking the framing
+        let block_path = dir.path().join("1.block");
+        let data = std::fs::read(&block_path).unwrap();
+        let (header, records) = data.split_at(8);
+        let (first, second) = records.split_at(records.len() / 2);
+        std::fs::write(&block_path, [header, second, first].concat()).unwrap();
+        assert!(matches!(
+            PersistentDb::open_with_options(dir.path(), options),
+            Err(PersistentDbError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_enable_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/a"),
+                value: json!(1),
+            }],
+        )
+        .unwrap();
+        drop(pdb);
+
+        // unencrypted data is only read with the explicit opt-in
+        let options = PersistentDbOptions::default()
+            .encryption_key(EncryptionKey::new([1; 32]))
+            .compact_block_threshold(0);
+        assert!(matches!(
+            PersistentDb::open_with_options(dir.path(), options.clone()),
+            Err(PersistentDbError::Decryption)
+        ));
+        let mut pdb =
+            PersistentDb::open_with_options(dir.path(), options.clone().allow_unencrypted(true))
+                .unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/b"),
+                value: json!(2),
+            }],
+        )
+        .unwrap();
+        compact(&mut pdb);
+        drop(pdb);
+
+        // the compaction replaced all unencrypted data
+        let pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        assert_eq!(
+            pdb.create_memdb().unwrap().root(),
+            json!({ "a": 1, "b": 2 })
+        );
+    }
+
+    #[test]
+    fn test_binary_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = PersistentDbOptions::default()
+            .snapshot_format(SnapshotFormat::Binary)
+            .compression(Compression::Zstd(3))
+            .compact_block_threshold(0);
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        let value = json!({ "a": [1, -1, 0.5, "b", null, true] });
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/a"),
+                value: value["a"].clone(),
+            }],
+        )
+        .unwrap();
+        compact(&mut pdb);
+        assert!(get_block_list(dir.path()).unwrap().is_empty());
+
+        // the format of the existing snapshot does not depend on the options
+        drop(pdb);
+        let pdb = PersistentDb::open(dir.path()).unwrap();
+        assert_eq!(pdb.create_memdb().unwrap().root(), value);
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdb = PersistentDb::open(dir.path()).unwrap();
+        assert!(matches!(
+            PersistentDb::open(dir.path()),
+            Err(PersistentDbError::Locked { .. })
+        ));
+
+        drop(pdb);
+        PersistentDb::open(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        for n in 0..2 {
+            pdb.append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/a"),
+                    value: json!(n),
+                }],
+            )
+            .unwrap();
+        }
+
+        // the writer is in the middle of appending a record
+        let block_path = dir.path().join("1.block");
+        let len = std::fs::metadata(&block_path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&block_path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let options = PersistentDbOptions::default().read_only(true);
+        let mut reader = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        assert_eq!(reader.create_memdb().unwrap().root(), json!({ "a": 0 }));
+        assert!(matches!(
+            reader.append(None, &[]),
+            Err(PersistentDbError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.compact(Default::default()),
+            Err(PersistentDbError::ReadOnly)
+        ));
+        assert_eq!(std::fs::metadata(&block_path).unwrap().len(), len - 3);
+    }
+
+    #[test]
+    fn test_recover_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        let set = |pdb: &mut PersistentDb, n: i32| {
+            pdb.append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/a"),
+                    value: json!(n),
+                }],
+            )
+            .unwrap();
+        };
+        for n in 1..=5 {
+            set(&mut pdb, n);
+        }
+
+        assert_eq!(
+            pdb.create_memdb_at(RecoveryTarget::Seq(3)).unwrap().root(),
+            json!({ "a": 3 })
+        );
+        assert_eq!(
+            pdb.create_memdb_at(RecoveryTarget::Timestamp(SystemTime::UNIX_EPOCH))
+                .unwrap()
+                .root(),
+            json!({})
+        );
+        assert_eq!(
+            pdb.create_memdb_at(RecoveryTarget::Timestamp(SystemTime::now()))
+                .unwrap()
+                .root(),
+            json!({ "a": 5 })
+        );
+
+        assert_eq!(
+            pdb.recover_to(RecoveryTarget::Seq(3)).unwrap().root(),
+            json!({ "a": 3 })
+        );
+        set(&mut pdb, 6);
+        drop(pdb);
+
+        // sequence numbers keep increasing after the discarded records
+        let pdb = PersistentDb::open(dir.path()).unwrap();
+        assert_eq!(pdb.last_record.seq, 6);
+        assert_eq!(pdb.create_memdb().unwrap().root(), json!({ "a": 6 }));
+        assert_eq!(
+            pdb.create_memdb_at(RecoveryTarget::Seq(5)).unwrap().root(),
+            json!({ "a": 3 })
+        );
+        assert!(matches!(
+            pdb.create_memdb_at(RecoveryTarget::Seq(2)),
+            Err(PersistentDbError::RecoveryTargetUnavailable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_inspect_and_compact_now() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pdb = PersistentDb::open(dir.path()).unwrap();
+        for n in 1..=3 {
+            pdb.append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/a"),
+                    value: json!(n),
+                }],
+            )
+            .unwrap();
+        }
+
+        let records = pdb
+            .log_records()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| (record.block, record.seq))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (1, 2), (1, 3)]
+        );
+        // the first record follows the block header
+        assert_eq!(records[0].offset, 8);
+        assert!(records[1].offset > records[0].offset);
+
+        // a single block file is not compacted unless forced
+        let snapshot = pdb.create_memdb().unwrap().snapshot();
+        pdb.compact_now(snapshot).unwrap();
+        let info = pdb.inspect().unwrap();
+        let snapshot = info.snapshot.unwrap();
+        assert_eq!(snapshot.last_block, 1);
+        assert_eq!(snapshot.last_seq, 3);
+        assert!(info.blocks.is_empty());
+        assert_eq!(pdb.log_records().unwrap().count(), 0);
+        assert_eq!(pdb.load_snapshot().unwrap().root(), json!({ "a": 3 }));
+    }
+
+    #[test]
+    fn test_snapshot_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let options =
+            PersistentDbOptions::default().snapshot_retention(SnapshotRetention::Count(3));
+        let mut pdb = PersistentDb::open_with_options(dir.path(), options.clone()).unwrap();
+        let mut db = MemDb::default();
+        for n in 1..=4 {
+            let patch = vec![JsonPatch::Add {
+                path: json_pointer!("/a"),
+                value: json!(n),
+            }];
+            pdb.append(None, &patch).unwrap();
+            db.patch(None, patch).unwrap();
+            pdb.compact_now(db.snapshot()).unwrap();
+        }
+
+        let generations = pdb.generations().unwrap();
+        assert_eq!(
+            generations
+                .iter()
+                .map(|snapshot| (snapshot.generation, snapshot.last_seq))
+                .collect::<Vec<_>>(),
+            vec![(4, 4), (3, 3), (2, 2)]
+        );
+        assert_eq!(pdb.inspect().unwrap().generations.len(), 2);
+        assert!(!dir.path().join("snapshot-1.data").exists());
+
+        assert!(matches!(
+            pdb.start_from_generation(1),
+            Err(PersistentDbError::GenerationNotFound { generation: 1 })
+        ));
+        let db = pdb.start_from_generation(2).unwrap();
+        assert_eq!(db.root(), json!({ "a": 2 }));
+        // the discarded records keep their sequence numbers
+        assert_eq!(pdb.last_record().seq, 4);
+
+        drop(pdb);
+        let pdb = PersistentDb::open_with_options(dir.path(), options).unwrap();
+        assert_eq!(pdb.create_memdb().unwrap().root(), json!({ "a": 2 }));
+        assert_eq!(
+            pdb.generations()
+                .unwrap()
+                .iter()
+                .map(|snapshot| snapshot.generation)
+                .collect::<Vec<_>>(),
+            vec![5, 4, 3]
+        );
+
+        // a backup keeps the older generations
+        let backup_dir = tempfile::tempdir().unwrap();
+        let backup_path = backup_dir.path().join("backup");
+        pdb.backup(&backup_path).unwrap();
+        let backup = PersistentDb::open(&backup_path).unwrap();
+        assert_eq!(backup.generations().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_replicate_changes() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let follower_dir = tempfile::tempdir().unwrap();
+        let mut primary = PersistentDb::open(primary_dir.path()).unwrap();
+        let set = |pdb: &mut PersistentDb, n: i32| {
+            pdb.append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/a"),
+                    value: json!(n),
+                }],
+            )
+            .unwrap()
+        };
+        for n in 1..=3 {
+            set(&mut primary, n);
+        }
+        let snapshot = primary.create_memdb().unwrap().snapshot();
+        primary.compact_now(snapshot).unwrap();
+        assert_eq!(set(&mut primary, 4).seq, 4);
+        set(&mut primary, 5);
+
+        // the changes folded into the snapshot are replaced by it
+        let changes = primary.changes_since(1).unwrap();
+        let lazy_snapshot = changes.snapshot.unwrap();
+        let stamp = lazy_snapshot.stamp;
+        let snapshot = lazy_snapshot.load().unwrap();
+        assert_eq!(stamp.seq, 3);
+        assert_eq!(snapshot, json!({ "a": 3 }));
+        let changes = changes.changes.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            changes.iter().map(|change| change.seq).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+
+        let mut follower = PersistentDb::open(follower_dir.path()).unwrap();
+        follower.reset(snapshot, stamp).unwrap();
+        follower.append_change(&changes[0]).unwrap();
+        drop(follower);
+
+        // a follower resumes from its last change
+        let mut follower = PersistentDb::open(follower_dir.path()).unwrap();
+        assert_eq!(follower.last_record().seq, 4);
+        let changes = primary.changes_since(4).unwrap();
+        assert!(changes.snapshot.is_none());
+        for change in changes.changes {
+            follower.append_change(&change.unwrap()).unwrap();
+        }
+        assert_eq!(follower.last_record(), primary.last_record());
+        assert_eq!(
+            follower.create_memdb().unwrap().root(),
+            primary.create_memdb().unwrap().root()
+        );
+
+        // compaction keeps the files the changes are read from
+        let changes = primary.changes_since(4).unwrap();
+        set(&mut primary, 6);
+        let snapshot = primary.create_memdb().unwrap().snapshot();
+        primary.compact_now(snapshot).unwrap();
+        assert_eq!(
+            changes
+                .changes
+                .map(|change| change.unwrap().seq)
+                .collect::<Vec<_>>(),
+            vec![5, 6]
+        );
+        assert!(primary
+            .inspect()
+            .unwrap()
+            .blocks
+            .iter()
+            .any(|block| block.obsolete));
+
+        assert!(matches!(
+            primary.changes_since(7),
+            Err(PersistentDbError::AheadOfLog {
+                seq: 7,
+                last_seq: 6
+            })
+        ));
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        let backup_dir = dir.path().join("backup");
+        let options = PersistentDbOptions::default().compact_block_threshold(0);
+        let mut pdb = PersistentDb::open_with_options(&data_dir, options).unwrap();
+        let set = |pdb: &mut PersistentDb, n: i32| {
+            pdb.append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/a"),
+                    value: json!(n),
+                }],
+            )
+            .unwrap();
+        };
+        set(&mut pdb, 1);
+        compact(&mut pdb);
+        set(&mut pdb, 2);
+
+        // neither new records nor compaction affect an unfinished backup
+        let backup = pdb.begin_backup().unwrap();
+        set(&mut pdb, 3);
+        compact(&mut pdb);
+        set(&mut pdb, 4);
+        backup.write_to(&backup_dir).unwrap();
+        assert!(backup.write_to(&backup_dir).is_err());
+        drop(backup);
+
+        let backup_pdb = PersistentDb::open(&backup_dir).unwrap();
+        assert_eq!(backup_pdb.create_memdb().unwrap().root(), json!({ "a": 2 }));
+        drop(backup_pdb);
+
+        assert!(matches!(
+            PersistentDb::restore(&backup_dir, &data_dir),
+            Err(PersistentDbError::Locked { .. })
+        ));
+        drop(pdb);
+        PersistentDb::restore(&backup_dir, &data_dir).unwrap();
+        let pdb = PersistentDb::open(&data_dir).unwrap();
+        assert_eq!(pdb.create_memdb().unwrap().root(), json!({ "a": 2 }));
+
+        let empty_dir = dir.path().join("empty");
+        std::fs::create_dir(&empty_dir).unwrap();
+        assert!(matches!(
+            PersistentDb::restore(&empty_dir, dir.path().join("other")),
+            Err(PersistentDbError::InvalidBackup { .. })
+        ));
+    }
+}
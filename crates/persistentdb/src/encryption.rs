@@ -0,0 +1,252 @@
+use std::{
+    borrow::Cow,
+    fmt::{self, Debug, Formatter},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::PersistentDbError;
+
+/// The magic number at the start of encrypted data, neither JSON, binary
+/// snapshots nor zstd frames start with these bytes.
+const ENCRYPTION_MAGIC: &[u8; 4] = b"BJEC";
+const NONCE_SIZE: usize = 24;
+const KEY_SIZE: usize = 32;
+
+/// A 256-bit key for encrypting snapshots and block records with
+/// XChaCha20-Poly1305.
+#[derive(Clone, Eq, PartialEq)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_SIZE]) -> Self {
+        Self(key)
+    }
+
+    /// Parses a key from 64 hexadecimal digits.
+    pub fn from_hex(s: &str) -> Result<Self, PersistentDbError> {
+        let s = s.trim();
+        let invalid = || PersistentDbError::InvalidEncryptionKey {
+            reason: format!("expected {} hexadecimal digits", KEY_SIZE * 2),
+        };
+        if s.len() != KEY_SIZE * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let mut key = [0; KEY_SIZE];
+        for (n, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[n * 2..n * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(key))
+    }
+
+    /// Reads a key from a file containing either the 32 raw bytes of the key
+    /// or 64 hexadecimal digits.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PersistentDbError> {
+        let data = std::fs::read(path)?;
+        match <[u8; KEY_SIZE]>::try_from(data.as_slice()) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => Self::from_hex(std::str::from_utf8(&data).map_err(|_| {
+                PersistentDbError::InvalidEncryptionKey {
+                    reason: "key file is neither 32 bytes nor hexadecimal text".to_string(),
+                }
+            })?),
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+/// Where encrypted data is stored.
+///
+/// The location is authenticated along with the data, so data moved to
+/// another place, like a record copied into another block file or replayed
+/// at another offset, fails to decrypt.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Location {
+    Snapshot { generation: u64 },
+    Record { block: usize, offset: u64 },
+}
+
+impl Location {
+    fn associated_data(self) -> Vec<u8> {
+        let mut aad = ENCRYPTION_MAGIC.to_vec();
+        match self {
+            Location::Snapshot { generation } => {
+                aad.push(b'S');
+                aad.extend_from_slice(&generation.to_le_bytes());
+            }
+            Location::Record { block, offset } => {
+                aad.push(b'R');
+                aad.extend_from_slice(&(block as u64).to_le_bytes());
+                aad.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+        aad
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Encrypts `data` stored at `location` with a random nonce if there is a
+/// key.
+pub(crate) fn encrypt(data: Vec<u8>, key: Option<&EncryptionKey>, location: Location) -> Vec<u8> {
+    let key = match key {
+        Some(key) => key,
+        None => return data,
+    };
+
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = key
+        .cipher()
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &data,
+                aad: &location.associated_data(),
+            },
+        )
+        .expect("encrypting in memory cannot fail");
+
+    let mut encrypted = Vec::with_capacity(ENCRYPTION_MAGIC.len() + NONCE_SIZE + ciphertext.len());
+    encrypted.extend_from_slice(ENCRYPTION_MAGIC);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    encrypted
+}
+
+/// Decrypts `data` read from `location` if it has been encrypted by
+/// [`encrypt`].
+///
+/// With a key, unencrypted data fails with [`PersistentDbError::Decryption`]
+/// unless `allow_unencrypted` is set, since it could have been swapped in by
+/// anyone able to write to the data directory.
+pub(crate) fn decrypt<'a>(
+    data: &'a [u8],
+    key: Option<&EncryptionKey>,
+    location: Location,
+    allow_unencrypted: bool,
+) -> Result<Cow<'a, [u8]>, PersistentDbError> {
+    let encrypted = match data.strip_prefix(ENCRYPTION_MAGIC) {
+        Some(encrypted) => encrypted,
+        None if key.is_none() || allow_unencrypted => return Ok(Cow::Borrowed(data)),
+        None => return Err(PersistentDbError::Decryption),
+    };
+    let key = key.ok_or(PersistentDbError::MissingEncryptionKey)?;
+    if encrypted.len() < NONCE_SIZE {
+        return Err(PersistentDbError::Decryption);
+    }
+
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+    key.cipher()
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &location.associated_data(),
+            },
+        )
+        .map(Cow::Owned)
+        .map_err(|_| PersistentDbError::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCATION: Location = Location::Record {
+        block: 1,
+        offset: 8,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let key = EncryptionKey::new([7; KEY_SIZE]);
+        let data = br#"{"customer":"alice"}"#.to_vec();
+
+        let encrypted = encrypt(data.clone(), Some(&key), LOCATION);
+        assert!(!encrypted
+            .windows(b"alice".len())
+            .any(|window| window == b"alice"));
+        assert_eq!(
+            decrypt(&encrypted, Some(&key), LOCATION, false).unwrap(),
+            &data[..]
+        );
+
+        // unencrypted data is only read as it is if allowed
+        assert!(matches!(
+            decrypt(&data, Some(&key), LOCATION, false),
+            Err(PersistentDbError::Decryption)
+        ));
+        assert_eq!(
+            decrypt(&data, Some(&key), LOCATION, true).unwrap(),
+            &data[..]
+        );
+        assert_eq!(decrypt(&data, None, LOCATION, false).unwrap(), &data[..]);
+
+        assert!(matches!(
+            decrypt(&encrypted, None, LOCATION, false),
+            Err(PersistentDbError::MissingEncryptionKey)
+        ));
+        assert!(matches!(
+            decrypt(
+                &encrypted,
+                Some(&EncryptionKey::new([8; KEY_SIZE])),
+                LOCATION,
+                false
+            ),
+            Err(PersistentDbError::Decryption)
+        ));
+
+        let mut tampered = encrypted;
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decrypt(&tampered, Some(&key), LOCATION, false),
+            Err(PersistentDbError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_moved() {
+        let key = EncryptionKey::new([7; KEY_SIZE]);
+        let encrypted = encrypt(b"{}".to_vec(), Some(&key), LOCATION);
+        for location in [
+            Location::Record {
+                block: 2,
+                offset: 8,
+            },
+            Location::Record {
+                block: 1,
+                offset: 40,
+            },
+            Location::Snapshot { generation: 1 },
+        ] {
+            assert!(matches!(
+                decrypt(&encrypted, Some(&key), location, false),
+                Err(PersistentDbError::Decryption)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let key = EncryptionKey::from_hex(hex).unwrap();
+        assert_eq!(key.0[1], 1);
+        assert_eq!(key.0[31], 0x1f);
+        assert_eq!(EncryptionKey::from_hex(&hex.to_uppercase()).unwrap(), key);
+
+        assert!(EncryptionKey::from_hex(&hex[2..]).is_err());
+        assert!(EncryptionKey::from_hex(&hex.replace('0', "g")).is_err());
+    }
+}
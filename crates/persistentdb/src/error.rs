@@ -1,11 +1,56 @@
+use std::path::PathBuf;
+
 #[derive(Debug, thiserror::Error)]
 pub enum PersistentDbError {
     #[error("block file is full")]
     BlockFileIsFull,
+    #[error("corrupted block record at offset {offset}")]
+    CorruptedBlock { offset: u64 },
+    #[error("unsupported block file version: {version}")]
+    UnsupportedBlockVersion { version: u32 },
+    #[error("data directory is already in use: {}", path.display())]
+    Locked { path: PathBuf },
+    #[error("recovery target {target} is before the snapshot")]
+    RecoveryTargetUnavailable { target: crate::RecoveryTarget },
+    #[error("data directory already contains data: {}", path.display())]
+    NotEmpty { path: PathBuf },
+    #[error("invalid seed file at line {line}: {reason}")]
+    InvalidSeed { line: usize, reason: String },
+    #[error("snapshot generation {generation} not found")]
+    GenerationNotFound { generation: u64 },
+    #[error("invalid backup: {reason}")]
+    InvalidBackup { reason: String },
+    #[error("invalid encryption key: {reason}")]
+    InvalidEncryptionKey { reason: String },
+    #[error("data is encrypted but no encryption key is configured")]
+    MissingEncryptionKey,
+    #[error(
+        "failed to decrypt data, the encryption key is wrong or the data has been tampered with"
+    )]
+    Decryption,
+    #[error("position {seq} is after the last record at {last_seq}")]
+    AheadOfLog { seq: u64, last_seq: u64 },
+    #[error("{operation} is not supported by this storage")]
+    Unsupported { operation: &'static str },
+    #[error("the background thread of the database has stopped")]
+    Closed,
+    #[error("failed to sync data, the write may or may not have been persisted: {reason}")]
+    SyncFailed { reason: String },
+    #[error("the database has stopped writing after a failed sync and must be reopened")]
+    Poisoned,
+    #[error("database is opened read-only")]
+    ReadOnly,
+    #[error("corrupted snapshot")]
+    CorruptedSnapshot,
+    #[error("document is nested deeper than {max_depth} levels")]
+    TooDeep { max_depth: usize },
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Json(#[from] serde_json::Error),
     #[error(transparent)]
     MemDB(#[from] memdb::MemDbError),
+    /// An error of a [`Storage`](crate::Storage) backend.
+    #[error(transparent)]
+    Storage(Box<dyn std::error::Error + Send + Sync>),
 }
@@ -0,0 +1,560 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// A file in the data directory opened for writing.
+///
+/// Every step which changes the data directory goes through this module, so
+/// the crash tests can make any single one of them fail.
+pub(crate) struct DataFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl DataFile {
+    /// Creates or truncates the file.
+    pub(crate) fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        check(&path, Op::Create)?;
+        Ok(Self {
+            file: File::create(&path)?,
+            path,
+        })
+    }
+
+    /// Opens the file for appending, creating it if it does not exist.
+    pub(crate) fn open_append(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        check(&path, Op::Create)?;
+        Ok(Self {
+            file: OpenOptions::new().append(true).create(true).open(&path)?,
+            path,
+        })
+    }
+
+    /// Opens an existing file for truncating it.
+    pub(crate) fn open_write(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        check(&path, Op::Open)?;
+        Ok(Self {
+            file: OpenOptions::new().write(true).open(&path)?,
+            path,
+        })
+    }
+
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub(crate) fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        unsynced_from(&self.path, self.len()?);
+        if let Some(fault) = fault(&self.path, Op::Write) {
+            if fault == Fault::ShortWrite {
+                self.file.write_all(&data[..data.len() / 2])?;
+            }
+            return Err(injected());
+        }
+        self.file.write_all(data)
+    }
+
+    pub(crate) fn set_len(&self, len: u64) -> io::Result<()> {
+        check(&self.path, Op::Truncate)?;
+        self.file.set_len(len)?;
+        synced(&self.path, Some(len));
+        Ok(())
+    }
+
+    pub(crate) fn sync_data(&self) -> io::Result<()> {
+        check(&self.path, Op::Sync)?;
+        self.file.sync_data()?;
+        synced(&self.path, None);
+        Ok(())
+    }
+
+    pub(crate) fn sync_all(&self) -> io::Result<()> {
+        check(&self.path, Op::Sync)?;
+        self.file.sync_all()?;
+        synced(&self.path, None);
+        Ok(())
+    }
+}
+
+/// Opens an existing file for reading.
+pub(crate) fn open(path: &Path) -> io::Result<File> {
+    check(path, Op::Open)?;
+    File::open(path)
+}
+
+pub(crate) fn read(path: &Path) -> io::Result<Vec<u8>> {
+    check(path, Op::Open)?;
+    std::fs::read(path)
+}
+
+pub(crate) fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    check(from, Op::Rename)?;
+    std::fs::rename(from, to)
+}
+
+pub(crate) fn remove_file(path: &Path) -> io::Result<()> {
+    check(path, Op::Remove)?;
+    std::fs::remove_file(path)
+}
+
+/// Syncs the entries of the directory `path`.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    check(path, Op::Sync)?;
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// A step of a file operation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Op {
+    Create,
+    Open,
+    Write,
+    Truncate,
+    Sync,
+    Rename,
+    Remove,
+}
+
+/// What happens to a step which has been made to fail.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(not(test), allow(dead_code))]
+enum Fault {
+    Fail,
+    /// Writes only part of the data before failing.
+    ShortWrite,
+}
+
+fn check(path: &Path, op: Op) -> io::Result<()> {
+    match fault(path, op) {
+        Some(_) => Err(injected()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+fn fault(_path: &Path, _op: Op) -> Option<Fault> {
+    None
+}
+
+#[cfg(test)]
+fn fault(path: &Path, op: Op) -> Option<Fault> {
+    faults::fault(path, op)
+}
+
+/// Records that the data of the file `path` after `len` is about to change
+/// and may be lost by a crash until it is synced.
+#[cfg(not(test))]
+#[inline(always)]
+fn unsynced_from(_path: &Path, _len: u64) {}
+
+#[cfg(test)]
+fn unsynced_from(path: &Path, len: u64) {
+    faults::unsynced_from(path, len)
+}
+
+/// Records that the data of the file `path` has been synced, or that it has
+/// been truncated to `len`.
+#[cfg(not(test))]
+#[inline(always)]
+fn synced(_path: &Path, _len: Option<u64>) {}
+
+#[cfg(test)]
+fn synced(path: &Path, len: Option<u64>) {
+    faults::synced(path, len)
+}
+
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+/// Fault injection for the crash tests.
+#[cfg(test)]
+pub(crate) mod faults {
+    use std::{
+        collections::HashMap,
+        fs::OpenOptions,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::{Fault, Op};
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub(crate) enum FaultKind {
+        /// The step fails without any effect, later steps succeed.
+        Error,
+        /// The step fails, and writes only part of the data if it is a
+        /// write. Later steps succeed.
+        ShortWrite,
+        /// The process stops at the step, which writes only part of the data
+        /// if it is a write. The step and all later ones fail, and once the
+        /// guard is dropped every file loses the data not synced before.
+        Crash,
+    }
+
+    struct Plan {
+        dir: PathBuf,
+        fail_at: usize,
+        kind: FaultKind,
+        steps: AtomicUsize,
+        hit: AtomicBool,
+        /// Length of the synced data of every file written to, files which
+        /// have not been written to count as synced.
+        synced_lens: Mutex<HashMap<PathBuf, u64>>,
+    }
+
+    /// Plans of the running tests, each for its own directory.
+    static PLANS: Mutex<Vec<Arc<Plan>>> = Mutex::new(Vec::new());
+
+    /// Makes the step with the index `fail_at` among the file operations
+    /// below `dir` fail, until the guard is dropped.
+    pub(crate) fn inject(dir: &Path, fail_at: usize, kind: FaultKind) -> FaultGuard {
+        let plan = Arc::new(Plan {
+            dir: dir.to_path_buf(),
+            fail_at,
+            kind,
+            steps: AtomicUsize::new(0),
+            hit: AtomicBool::new(false),
+            synced_lens: Mutex::new(HashMap::new()),
+        });
+        PLANS.lock().unwrap().push(plan.clone());
+        FaultGuard { plan }
+    }
+
+    pub(crate) struct FaultGuard {
+        plan: Arc<Plan>,
+    }
+
+    impl FaultGuard {
+        /// Whether the step to fail has been reached.
+        pub(crate) fn hit(&self) -> bool {
+            self.plan.hit.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Drop for FaultGuard {
+        fn drop(&mut self) {
+            PLANS
+                .lock()
+                .unwrap()
+                .retain(|plan| !Arc::ptr_eq(plan, &self.plan));
+
+            if self.plan.kind == FaultKind::Crash && self.hit() {
+                for (path, synced_len) in self.plan.synced_lens.lock().unwrap().drain() {
+                    // removed files stay removed
+                    if let Ok(file) = OpenOptions::new().write(true).open(&path) {
+                        if file.metadata().unwrap().len() > synced_len {
+                            file.set_len(synced_len).unwrap();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn plan(path: &Path) -> Option<Arc<Plan>> {
+        PLANS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|plan| path.starts_with(&plan.dir))
+            .cloned()
+    }
+
+    pub(super) fn unsynced_from(path: &Path, len: u64) {
+        if let Some(plan) = plan(path) {
+            let mut synced_lens = plan.synced_lens.lock().unwrap();
+            let synced_len = synced_lens.entry(path.to_path_buf()).or_insert(len);
+            *synced_len = (*synced_len).min(len);
+        }
+    }
+
+    pub(super) fn synced(path: &Path, len: Option<u64>) {
+        if let Some(plan) = plan(path) {
+            let mut synced_lens = plan.synced_lens.lock().unwrap();
+            match len {
+                Some(len) => {
+                    if let Some(synced_len) = synced_lens.get_mut(path) {
+                        *synced_len = (*synced_len).min(len);
+                    }
+                }
+                None => {
+                    synced_lens.remove(path);
+                }
+            }
+        }
+    }
+
+    pub(super) fn fault(path: &Path, op: Op) -> Option<Fault> {
+        let plan = plan(path)?;
+        let step = plan.steps.fetch_add(1, Ordering::SeqCst);
+        let fails = match plan.kind {
+            FaultKind::Crash => step >= plan.fail_at,
+            FaultKind::Error | FaultKind::ShortWrite => step == plan.fail_at,
+        };
+        if !fails {
+            return None;
+        }
+        plan.hit.store(true, Ordering::SeqCst);
+
+        match plan.kind {
+            FaultKind::Error => Some(Fault::Fail),
+            FaultKind::ShortWrite => Some(Fault::ShortWrite),
+            // a crashed process does not write anything after the crash
+            FaultKind::Crash if step == plan.fail_at && op == Op::Write => Some(Fault::ShortWrite),
+            FaultKind::Crash => Some(Fault::Fail),
+        }
+    }
+}
+
+/// Crash-consistency tests: every step of the file operations of a workload is
+/// made to fail in turn, and reopening the data directory must then yield a
+/// prefix of the history containing at least every acknowledged change.
+///
+/// A crash stops the process and loses everything not synced before it, the
+/// data appended to a file after its last sync and the files never synced.
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use json_patch::JsonPatch;
+    use json_pointer::json_pointer;
+    use memdb::MemDb;
+    use serde_json::{json, Value};
+
+    use super::faults::{inject, FaultKind};
+    use crate::{Durability, PersistentDb, PersistentDbError, PersistentDbOptions};
+
+    /// Number of items appended by the workload.
+    const OPS: u64 = 8;
+
+    /// Number of times a failed step is retried before the process is
+    /// considered to have crashed.
+    const RETRIES: usize = 3;
+
+    struct Outcome {
+        /// Items whose append and commit both succeeded.
+        acknowledged: u64,
+        /// Items whose append has been started.
+        attempted: u64,
+    }
+
+    fn options() -> PersistentDbOptions {
+        PersistentDbOptions::default()
+            .durability(Durability::Batch)
+            // about one record per block file
+            .max_block_size(64)
+            // a compaction in the background is never skipped
+            .compact_block_threshold(0)
+    }
+
+    fn add_item(n: u64) -> [JsonPatch; 1] {
+        [JsonPatch::Add {
+            path: json_pointer!("/items/-"),
+            value: json!(n),
+        }]
+    }
+
+    fn setup(path: &Path) -> PersistentDb {
+        let mut pdb = PersistentDb::open_with_options(path, options()).unwrap();
+        pdb.append(
+            None,
+            &[JsonPatch::Add {
+                path: json_pointer!("/items"),
+                value: json!([]),
+            }],
+        )
+        .unwrap();
+        pdb.commit().unwrap();
+        pdb
+    }
+
+    fn retry<T>(mut f: impl FnMut() -> Result<T, PersistentDbError>) -> Option<T> {
+        (0..RETRIES).find_map(|_| f().ok())
+    }
+
+    /// Appends the items one by one like the server does, retrying failed
+    /// appends and commits, and compacts after every third item, in the
+    /// `background` while appending continues or else right away.
+    fn run_workload(pdb: &mut PersistentDb, background: bool) -> Outcome {
+        // the document written by `setup`
+        let mut memdb = MemDb::new(json!({ "items": [] }));
+        for n in 0..OPS {
+            let patch = add_item(n);
+            let crashed = Outcome {
+                acknowledged: n,
+                attempted: n + 1,
+            };
+            if retry(|| pdb.append(None, &patch)).is_none() {
+                return crashed;
+            }
+            memdb.patch(None, patch.to_vec()).unwrap();
+            if retry(|| pdb.commit()).is_none() {
+                return crashed;
+            }
+
+            if n % 3 == 2 {
+                // a failed compaction is not retried
+                let _ = match background {
+                    true => pdb.compact(memdb.snapshot()),
+                    false => pdb.compact_now(memdb.snapshot()),
+                };
+            }
+        }
+        Outcome {
+            acknowledged: OPS,
+            attempted: OPS,
+        }
+    }
+
+    fn load_items(pdb: &PersistentDb) -> Vec<Value> {
+        match pdb.create_memdb().unwrap().root() {
+            Value::Object(mut root) => match root.remove("items") {
+                Some(Value::Array(items)) => items,
+                items => panic!("unexpected items {:?}", items),
+            },
+            root => panic!("unexpected document {}", root),
+        }
+    }
+
+    /// Reopens the data directory, checks that it contains the first items
+    /// without gaps or duplicates and that appending continues after them.
+    fn check_recovered(path: &Path, outcome: &Outcome, context: &str) {
+        let mut pdb = PersistentDb::open_with_options(path, options())
+            .unwrap_or_else(|err| panic!("{}: failed to reopen: {}", context, err));
+        let items = load_items(&pdb);
+        let len = items.len() as u64;
+        assert!(
+            outcome.acknowledged <= len && len <= outcome.attempted,
+            "{}: recovered {} items, acknowledged {}, attempted {}",
+            context,
+            len,
+            outcome.acknowledged,
+            outcome.attempted
+        );
+        assert_eq!(
+            items,
+            (0..len).map(|n| json!(n)).collect::<Vec<_>>(),
+            "{}",
+            context
+        );
+        assert_eq!(pdb.last_record().seq, len + 1, "{}", context);
+
+        let seqs = pdb
+            .log_records()
+            .unwrap()
+            .map(|record| record.unwrap().seq)
+            .collect::<Vec<_>>();
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]), "{}", context);
+
+        pdb.append(None, &add_item(len)).unwrap();
+        pdb.sync().unwrap();
+        drop(pdb);
+        let pdb = PersistentDb::open_with_options(path, options()).unwrap();
+        assert_eq!(load_items(&pdb).len() as u64, len + 1, "{}", context);
+    }
+
+    /// Runs the workload in a new data directory with the step `fail_at`
+    /// failing, returns `None` if the workload has fewer steps.
+    fn run_with_fault(
+        path: &Path,
+        fail_at: usize,
+        kind: FaultKind,
+        background: bool,
+    ) -> Option<Outcome> {
+        let mut pdb = setup(path);
+        let guard = inject(path, fail_at, kind);
+        let outcome = run_workload(&mut pdb, background);
+        // the process stops only after the compaction thread
+        while pdb.is_compacting() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(pdb);
+        guard.hit().then_some(outcome)
+    }
+
+    #[test]
+    fn test_failed_steps() {
+        for kind in [FaultKind::Error, FaultKind::ShortWrite] {
+            for fail_at in 0.. {
+                let dir = tempfile::tempdir().unwrap();
+                let outcome = match run_with_fault(dir.path(), fail_at, kind, false) {
+                    Some(outcome) => outcome,
+                    None => break,
+                };
+                let context = format!("{:?} at step {}", kind, fail_at);
+                // the retries make up for a single failed step
+                assert_eq!(outcome.acknowledged, OPS, "{}", context);
+                check_recovered(dir.path(), &outcome, &context);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crash_at_every_step() {
+        for fail_at in 0.. {
+            let dir = tempfile::tempdir().unwrap();
+            let outcome = match run_with_fault(dir.path(), fail_at, FaultKind::Crash, false) {
+                Some(outcome) => outcome,
+                None => break,
+            };
+            check_recovered(dir.path(), &outcome, &format!("crash at step {}", fail_at));
+        }
+    }
+
+    #[test]
+    fn test_crash_during_background_compaction() {
+        // the steps of the compaction thread interleave differently with the
+        // appends in every run
+        for fail_at in 0.. {
+            let dir = tempfile::tempdir().unwrap();
+            let outcome = match run_with_fault(dir.path(), fail_at, FaultKind::Crash, true) {
+                Some(outcome) => outcome,
+                None => break,
+            };
+            check_recovered(
+                dir.path(),
+                &outcome,
+                &format!("crash at step {} with background compaction", fail_at),
+            );
+        }
+    }
+
+    #[test]
+    fn test_crash_during_recovery() {
+        for fail_at in 0.. {
+            let dir = tempfile::tempdir().unwrap();
+            let outcome = match run_with_fault(dir.path(), fail_at, FaultKind::Crash, false) {
+                Some(outcome) => outcome,
+                None => break,
+            };
+
+            // crash again at every step of the recovery, each time continuing
+            // from what the previous attempt left behind
+            for recovery_fail_at in 0.. {
+                let guard = inject(dir.path(), recovery_fail_at, FaultKind::Crash);
+                let _ = PersistentDb::open_with_options(dir.path(), options())
+                    .and_then(|pdb| pdb.create_memdb());
+                if !guard.hit() {
+                    break;
+                }
+            }
+            check_recovered(
+                dir.path(),
+                &outcome,
+                &format!("crash at step {} and during recovery", fail_at),
+            );
+        }
+    }
+}
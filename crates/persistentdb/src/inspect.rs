@@ -0,0 +1,137 @@
+use std::{path::PathBuf, vec::IntoIter};
+
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use serde::Serialize;
+
+use crate::{block_file::InactiveBlockFile, codec::Codec, PersistentDbError};
+
+/// The files making up a data directory, see [`PersistentDb::inspect`].
+///
+/// [`PersistentDb::inspect`]: crate::PersistentDb::inspect
+#[derive(Debug, Clone, Serialize)]
+pub struct DataDirInfo {
+    pub snapshot: Option<SnapshotInfo>,
+    /// Older snapshots kept as restore points, newest first.
+    pub generations: Vec<SnapshotInfo>,
+    pub blocks: Vec<BlockInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub file_name: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Index of the last block file folded into the snapshot.
+    pub last_block: usize,
+    /// Sequence number of the last record folded into the snapshot.
+    pub last_seq: u64,
+    /// Timestamp of the last record folded into the snapshot, in milliseconds
+    /// since the UNIX epoch.
+    pub last_timestamp: u64,
+    /// Increases with every snapshot, `0` if written by an older version.
+    pub generation: u64,
+    /// Milliseconds since the UNIX epoch when the snapshot was written, `0`
+    /// if written by an older version.
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockInfo {
+    pub id: usize,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Whether the block file has been folded into the snapshot and is only
+    /// kept for an unfinished backup.
+    pub obsolete: bool,
+}
+
+/// A record of the log together with its position in the data directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    pub block: usize,
+    /// Offset of the record in the block file.
+    pub offset: u64,
+    pub seq: u64,
+    /// Milliseconds since the UNIX epoch when the record was appended.
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<JsonPointer>,
+    pub patch: Vec<JsonPatch>,
+}
+
+/// Iterates over the records not folded into the snapshot, see
+/// [`PersistentDb::log_records`].
+///
+/// [`PersistentDb::log_records`]: crate::PersistentDb::log_records
+pub struct LogRecords {
+    path: PathBuf,
+    codec: Codec,
+    blocks: IntoIter<usize>,
+    block_id: Option<usize>,
+    block: Option<InactiveBlockFile>,
+    failed: bool,
+}
+
+impl LogRecords {
+    pub(crate) fn new(path: PathBuf, codec: Codec, blocks: Vec<usize>) -> Self {
+        Self {
+            path,
+            codec,
+            blocks: blocks.into_iter(),
+            block_id: None,
+            block: None,
+            failed: false,
+        }
+    }
+
+    /// Index of the block file read last, the one an error refers to.
+    pub fn block(&self) -> Option<usize> {
+        self.block_id
+    }
+
+    fn next_record(&mut self) -> Result<Option<LogRecord>, PersistentDbError> {
+        loop {
+            if let (Some(block_id), Some(block)) = (self.block_id, &mut self.block) {
+                let offset = block.valid_len();
+                if let Some(record) = block.next().transpose()? {
+                    return Ok(Some(LogRecord {
+                        block: block_id,
+                        offset,
+                        seq: record.seq,
+                        timestamp: record.timestamp,
+                        prefix: record.prefix,
+                        patch: record.patch_records,
+                    }));
+                }
+            }
+
+            match self.blocks.next() {
+                Some(block_id) => {
+                    self.block_id = Some(block_id);
+                    self.block = Some(InactiveBlockFile::open(
+                        self.path.join(format!("{}.block", block_id)),
+                        block_id,
+                        &self.codec,
+                    )?);
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for LogRecords {
+    type Item = Result<LogRecord, PersistentDbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let res = self.next_record().transpose();
+        // nothing after an error can be trusted
+        self.failed = matches!(res, Some(Err(_)));
+        res
+    }
+}
@@ -1,6 +1,31 @@
+mod async_db;
+mod backup;
 mod block_file;
+mod codec;
+mod compression;
 mod db;
+mod encryption;
 mod error;
+mod fs;
+mod inspect;
+mod lock;
+mod manifest;
+mod options;
+mod recovery;
+mod seed;
+mod snapshot;
+mod storage;
 
+pub use async_db::{AsyncMetrics, AsyncPersistentDb};
+pub use backup::Backup;
+pub use block_file::RecordStamp;
 pub use db::PersistentDb;
+pub use encryption::EncryptionKey;
 pub use error::PersistentDbError;
+pub use inspect::{BlockInfo, DataDirInfo, LogRecord, LogRecords, SnapshotInfo};
+pub use options::{
+    Compression, Durability, PersistentDbOptions, SnapshotFormat, SnapshotRetention,
+};
+pub use recovery::RecoveryTarget;
+pub use seed::SeedFormat;
+pub use storage::{Change, Changes, LazySnapshot, MemoryStorage, Storage, StorageBackup};
@@ -0,0 +1,31 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+};
+
+use crate::PersistentDbError;
+
+const LOCK_FILE_NAME: &str = "LOCK";
+
+/// An advisory lock which keeps other processes from writing to the same
+/// data directory, released when it is dropped.
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    pub(crate) fn acquire(path: &Path) -> Result<Self, PersistentDbError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE_NAME))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(PersistentDbError::Locked {
+                path: path.to_path_buf(),
+            }),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::{io::ErrorKind, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block_file::RecordStamp,
+    db::get_block_list,
+    fs::{self, DataFile},
+    PersistentDbError,
+};
+
+pub(crate) const MANIFEST_FILE_NAME: &str = "manifest.json";
+const TEMP_MANIFEST_FILE_NAME: &str = "manifest.temp";
+pub(crate) const LEGACY_SNAPSHOT_FILE_NAME: &str = "snapshot.data";
+
+/// Describes which files make up the current state of a data directory.
+///
+/// The manifest is replaced atomically after a snapshot has been written, so
+/// a snapshot never becomes visible without the block position it covers.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) snapshot: Option<SnapshotMeta>,
+    /// Older snapshots kept by the [`SnapshotRetention`], newest first.
+    ///
+    /// [`SnapshotRetention`]: crate::SnapshotRetention
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) generations: Vec<SnapshotMeta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotMeta {
+    pub(crate) file_name: String,
+    /// Index of the last block file folded into the snapshot.
+    pub(crate) last_block: usize,
+    /// Sequence number of the last record folded into the snapshot.
+    #[serde(default)]
+    pub(crate) last_seq: u64,
+    /// Timestamp of the last record folded into the snapshot.
+    #[serde(default)]
+    pub(crate) last_timestamp: u64,
+    /// Increases with every snapshot, `0` if written by an older version.
+    #[serde(default)]
+    pub(crate) generation: u64,
+    /// Milliseconds since the UNIX epoch when the snapshot was written, `0`
+    /// if written by an older version.
+    #[serde(default)]
+    pub(crate) created_at: u64,
+}
+
+impl Manifest {
+    pub(crate) fn load(path: &Path) -> Result<Self, PersistentDbError> {
+        match fs::read(&path.join(MANIFEST_FILE_NAME)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                if !path.join(LEGACY_SNAPSHOT_FILE_NAME).exists() {
+                    Ok(Self::default())
+                } else if !get_block_list(path)?.is_empty() {
+                    // written by an older version which did not record the
+                    // covered blocks and never removed them, so the blocks
+                    // alone describe the whole document
+                    tracing::warn!(
+                        path = %path.display(),
+                        "snapshot without manifest, replaying all blocks instead"
+                    );
+                    Ok(Self::default())
+                } else {
+                    Ok(Self {
+                        snapshot: Some(SnapshotMeta {
+                            file_name: LEGACY_SNAPSHOT_FILE_NAME.to_string(),
+                            last_block: 0,
+                            last_seq: 0,
+                            last_timestamp: 0,
+                            generation: 0,
+                            created_at: 0,
+                        }),
+                        generations: Vec::new(),
+                    })
+                }
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub(crate) fn store(&self, path: &Path) -> Result<(), PersistentDbError> {
+        write_file_atomic(
+            path,
+            TEMP_MANIFEST_FILE_NAME,
+            MANIFEST_FILE_NAME,
+            &serde_json::to_vec(self)?,
+        )
+    }
+
+    /// The current snapshot followed by the older generations.
+    pub(crate) fn snapshots(&self) -> impl Iterator<Item = &SnapshotMeta> {
+        self.snapshot.iter().chain(&self.generations)
+    }
+
+    /// Number of the next snapshot.
+    pub(crate) fn next_generation(&self) -> u64 {
+        self.snapshots()
+            .map(|snapshot| match snapshot.generation {
+                // older versions named the snapshot after its last block, so
+                // a new generation must not reuse that name
+                0 => snapshot.last_block as u64,
+                generation => generation,
+            })
+            .max()
+            .unwrap_or_default()
+            + 1
+    }
+
+    /// Index of the last block file already contained in the snapshot, `0`
+    /// if there is no snapshot.
+    #[inline]
+    pub(crate) fn last_block(&self) -> usize {
+        self.snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.last_block)
+            .unwrap_or_default()
+    }
+
+    /// Stamp of the last record already contained in the snapshot.
+    #[inline]
+    pub(crate) fn last_record(&self) -> RecordStamp {
+        self.snapshot
+            .as_ref()
+            .map(|snapshot| RecordStamp {
+                seq: snapshot.last_seq,
+                timestamp: snapshot.last_timestamp,
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Writes `data` to `temp_name`, syncs it and renames it to `name`.
+pub(crate) fn write_file_atomic(
+    path: &Path,
+    temp_name: &str,
+    name: &str,
+    data: &[u8],
+) -> Result<(), PersistentDbError> {
+    let mut file = DataFile::create(path.join(temp_name))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&path.join(temp_name), &path.join(name))?;
+    Ok(fs::sync_dir(path)?)
+}
@@ -0,0 +1,442 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::EncryptionKey;
+
+/// When appended records are forced to the disk with `fsync`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Durability {
+    /// Never sync explicitly, leaving it to the operating system.
+    #[default]
+    None,
+    /// Sync the active block file at most once per interval.
+    Periodic(Duration),
+    /// Sync once for every batch of appends, see [`PersistentDb::commit`].
+    ///
+    /// [`PersistentDb::commit`]: crate::PersistentDb::commit
+    Batch,
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::None => f.write_str("none"),
+            Durability::Periodic(interval) => write!(f, "periodic:{}", interval.as_millis()),
+            Durability::Batch => f.write_str("batch"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "batch" => Ok(Durability::Batch),
+            _ => s
+                .strip_prefix("periodic:")
+                .and_then(|millis| millis.parse::<u64>().ok())
+                .filter(|millis| *millis > 0)
+                .map(|millis| Durability::Periodic(Duration::from_millis(millis)))
+                .ok_or_else(|| {
+                    format!(
+                        "invalid durability `{}`, expected `none`, `batch` or `periodic:<milliseconds>`",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+impl TryFrom<String> for Durability {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Durability> for String {
+    fn from(durability: Durability) -> Self {
+        durability.to_string()
+    }
+}
+
+/// How snapshots and block records are compressed when they are written.
+///
+/// Compressed data is detected when it is read, so a data directory may mix
+/// compressed and uncompressed files.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard with the given compression level.
+    Zstd(i32),
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+            _ => s
+                .strip_prefix("zstd:")
+                .and_then(|level| level.parse::<i32>().ok())
+                .filter(|level| zstd::compression_level_range().contains(level))
+                .map(Compression::Zstd)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid compression `{}`, expected `none`, `zstd` or `zstd:<level>`",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Compression> for String {
+    fn from(compression: Compression) -> Self {
+        compression.to_string()
+    }
+}
+
+/// Encoding of newly written snapshots.
+///
+/// The encoding is detected when a snapshot is read, so changing it only
+/// affects the next snapshot.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotFormat {
+    #[default]
+    Json,
+    /// A compact tagged binary encoding which is much faster to load.
+    Binary,
+}
+
+impl Display for SnapshotFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotFormat::Json => f.write_str("json"),
+            SnapshotFormat::Binary => f.write_str("binary"),
+        }
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(SnapshotFormat::Json),
+            "binary" => Ok(SnapshotFormat::Binary),
+            _ => Err(format!(
+                "invalid snapshot format `{}`, expected `json` or `binary`",
+                s
+            )),
+        }
+    }
+}
+
+/// Which snapshots are kept as restore points when a new one is written, see
+/// [`PersistentDb::generations`].
+///
+/// [`PersistentDb::generations`]: crate::PersistentDb::generations
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SnapshotRetention {
+    /// Keep this many snapshots, including the current one.
+    Count(usize),
+    /// Keep the snapshots written within this many hours, besides the
+    /// current one.
+    Hours(u64),
+}
+
+impl Default for SnapshotRetention {
+    fn default() -> Self {
+        SnapshotRetention::Count(1)
+    }
+}
+
+impl Display for SnapshotRetention {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotRetention::Count(count) => write!(f, "count:{}", count),
+            SnapshotRetention::Hours(hours) => write!(f, "hours:{}", hours),
+        }
+    }
+}
+
+impl FromStr for SnapshotRetention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(count) = s
+            .strip_prefix("count:")
+            .and_then(|count| count.parse().ok())
+            .filter(|count| *count > 0)
+        {
+            Ok(SnapshotRetention::Count(count))
+        } else if let Some(hours) = s
+            .strip_prefix("hours:")
+            .and_then(|hours| hours.parse().ok())
+        {
+            Ok(SnapshotRetention::Hours(hours))
+        } else {
+            Err(format!(
+                "invalid snapshot retention `{}`, expected `count:<snapshots>` or `hours:<hours>`",
+                s
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for SnapshotRetention {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SnapshotRetention> for String {
+    fn from(retention: SnapshotRetention) -> Self {
+        retention.to_string()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PersistentDbOptions {
+    pub(crate) read_only: bool,
+    pub(crate) durability: Durability,
+    pub(crate) compression: Compression,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) allow_unencrypted: bool,
+    pub(crate) snapshot_format: SnapshotFormat,
+    pub(crate) snapshot_retention: SnapshotRetention,
+    pub(crate) max_block_size: u64,
+    pub(crate) compact_block_threshold: usize,
+    pub(crate) compact_size_ratio: Option<f64>,
+    pub(crate) compact_interval: Duration,
+}
+
+impl Default for PersistentDbOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            durability: Durability::None,
+            compression: Compression::None,
+            encryption_key: None,
+            allow_unencrypted: false,
+            snapshot_format: SnapshotFormat::Json,
+            snapshot_retention: SnapshotRetention::Count(1),
+            max_block_size: 1024 * 1024 * 256,
+            compact_block_threshold: 5,
+            compact_size_ratio: None,
+            compact_interval: Duration::from_secs(60 * 30),
+        }
+    }
+}
+
+impl PersistentDbOptions {
+    /// Opens the database without taking the lock on the data directory, so
+    /// it can be inspected while another process writes to it.
+    ///
+    /// Nothing is written to the data directory, [`PersistentDb::append`] and
+    /// [`PersistentDb::compact`] fail with [`PersistentDbError::ReadOnly`].
+    ///
+    /// [`PersistentDb::append`]: crate::PersistentDb::append
+    /// [`PersistentDb::compact`]: crate::PersistentDb::compact
+    /// [`PersistentDbError::ReadOnly`]: crate::PersistentDbError::ReadOnly
+    #[must_use]
+    pub fn read_only(self, read_only: bool) -> Self {
+        Self { read_only, ..self }
+    }
+
+    #[must_use]
+    pub fn durability(self, durability: Durability) -> Self {
+        Self { durability, ..self }
+    }
+
+    #[must_use]
+    pub fn compression(self, compression: Compression) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    /// Encrypts newly written snapshots and block records with `key`.
+    ///
+    /// Reading fails with [`PersistentDbError::Decryption`] if `key` is
+    /// wrong, the data has been modified or moved, or it is not encrypted at
+    /// all, see [`PersistentDbOptions::allow_unencrypted`].
+    ///
+    /// [`PersistentDbError::Decryption`]: crate::PersistentDbError::Decryption
+    #[must_use]
+    pub fn encryption_key(self, key: EncryptionKey) -> Self {
+        Self {
+            encryption_key: Some(key),
+            ..self
+        }
+    }
+
+    /// Also reads unencrypted data when there is an encryption key, to enable
+    /// encryption for an existing data directory. Its data gets encrypted as
+    /// the next compaction replaces it with a snapshot, after which this
+    /// should be turned off again.
+    #[must_use]
+    pub fn allow_unencrypted(self, allow_unencrypted: bool) -> Self {
+        Self {
+            allow_unencrypted,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot_format(self, snapshot_format: SnapshotFormat) -> Self {
+        Self {
+            snapshot_format,
+            ..self
+        }
+    }
+
+    /// Which older snapshots are kept when a new one is written, by default
+    /// only the current one.
+    ///
+    /// Older snapshots are restore points for
+    /// [`PersistentDb::start_from_generation`], the block files between them
+    /// are removed regardless.
+    ///
+    /// [`PersistentDb::start_from_generation`]: crate::PersistentDb::start_from_generation
+    #[must_use]
+    pub fn snapshot_retention(self, snapshot_retention: SnapshotRetention) -> Self {
+        Self {
+            snapshot_retention,
+            ..self
+        }
+    }
+
+    /// Size in bytes after which a new block file is started.
+    ///
+    /// A single record larger than this still gets a block file of its own.
+    #[must_use]
+    pub fn max_block_size(self, max_block_size: u64) -> Self {
+        Self {
+            max_block_size,
+            ..self
+        }
+    }
+
+    /// Compact once more than this many block files are not covered by the
+    /// snapshot.
+    #[must_use]
+    pub fn compact_block_threshold(self, compact_block_threshold: usize) -> Self {
+        Self {
+            compact_block_threshold,
+            ..self
+        }
+    }
+
+    /// Also compact once the block files not covered by the snapshot are
+    /// larger than `ratio` times the snapshot.
+    #[must_use]
+    pub fn compact_size_ratio(self, ratio: f64) -> Self {
+        Self {
+            compact_size_ratio: Some(ratio),
+            ..self
+        }
+    }
+
+    /// How often the owner of the database should call
+    /// [`PersistentDb::compact`].
+    ///
+    /// [`PersistentDb::compact`]: crate::PersistentDb::compact
+    #[must_use]
+    pub fn compact_interval(self, compact_interval: Duration) -> Self {
+        Self {
+            compact_interval,
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_durability() {
+        assert_eq!("none".parse(), Ok(Durability::None));
+        assert_eq!("batch".parse(), Ok(Durability::Batch));
+        assert_eq!(
+            "periodic:250".parse(),
+            Ok(Durability::Periodic(Duration::from_millis(250)))
+        );
+        assert!("periodic:0".parse::<Durability>().is_err());
+        assert!("periodic".parse::<Durability>().is_err());
+        assert!("always".parse::<Durability>().is_err());
+
+        for durability in [
+            Durability::None,
+            Durability::Batch,
+            Durability::Periodic(Duration::from_millis(100)),
+        ] {
+            assert_eq!(durability.to_string().parse(), Ok(durability));
+        }
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!("none".parse(), Ok(Compression::None));
+        assert_eq!(
+            "zstd".parse(),
+            Ok(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL))
+        );
+        assert_eq!("zstd:19".parse(), Ok(Compression::Zstd(19)));
+        assert!("zstd:100".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+
+        for compression in [Compression::None, Compression::Zstd(7)] {
+            assert_eq!(compression.to_string().parse(), Ok(compression));
+        }
+    }
+
+    #[test]
+    fn test_parse_snapshot_retention() {
+        assert_eq!("count:3".parse(), Ok(SnapshotRetention::Count(3)));
+        assert_eq!("hours:24".parse(), Ok(SnapshotRetention::Hours(24)));
+        assert!("count:0".parse::<SnapshotRetention>().is_err());
+        assert!("days:1".parse::<SnapshotRetention>().is_err());
+
+        for retention in [SnapshotRetention::Count(1), SnapshotRetention::Hours(0)] {
+            assert_eq!(retention.to_string().parse(), Ok(retention));
+        }
+    }
+}
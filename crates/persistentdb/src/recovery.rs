@@ -0,0 +1,105 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block_file::RecordStamp;
+
+/// The last change to include when rebuilding the document from the log.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RecoveryTarget {
+    /// Include the records up to this sequence number.
+    Seq(u64),
+    /// Include the records appended up to this time.
+    Timestamp(SystemTime),
+}
+
+impl RecoveryTarget {
+    pub(crate) fn includes(&self, stamp: RecordStamp) -> bool {
+        match self {
+            RecoveryTarget::Seq(seq) => stamp.seq <= *seq,
+            RecoveryTarget::Timestamp(time) => stamp.timestamp <= unix_millis(*time),
+        }
+    }
+}
+
+/// Milliseconds since the UNIX epoch, `0` for earlier times.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl Display for RecoveryTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryTarget::Seq(seq) => write!(f, "seq:{}", seq),
+            RecoveryTarget::Timestamp(time) => write!(f, "timestamp:{}", unix_millis(*time)),
+        }
+    }
+}
+
+impl FromStr for RecoveryTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(seq) = s.strip_prefix("seq:").and_then(|seq| seq.parse().ok()) {
+            Ok(RecoveryTarget::Seq(seq))
+        } else if let Some(millis) = s
+            .strip_prefix("timestamp:")
+            .and_then(|millis| millis.parse().ok())
+        {
+            Ok(RecoveryTarget::Timestamp(
+                UNIX_EPOCH + Duration::from_millis(millis),
+            ))
+        } else {
+            Err(format!(
+                "invalid recovery target `{}`, expected `seq:<sequence number>` or `timestamp:<unix milliseconds>`",
+                s
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for RecoveryTarget {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RecoveryTarget> for String {
+    fn from(target: RecoveryTarget) -> Self {
+        target.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recovery_target() {
+        assert_eq!("seq:42".parse(), Ok(RecoveryTarget::Seq(42)));
+        assert_eq!(
+            "timestamp:1500".parse(),
+            Ok(RecoveryTarget::Timestamp(
+                UNIX_EPOCH + Duration::from_millis(1500)
+            ))
+        );
+        assert!("seq:-1".parse::<RecoveryTarget>().is_err());
+        assert!("42".parse::<RecoveryTarget>().is_err());
+
+        for target in [
+            RecoveryTarget::Seq(7),
+            RecoveryTarget::Timestamp(UNIX_EPOCH + Duration::from_millis(123)),
+        ] {
+            assert_eq!(target.to_string().parse(), Ok(target));
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use memdb::MemDb;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::PersistentDbError;
+
+/// Format of a file to seed a data directory from, see
+/// [`PersistentDb::seed`].
+///
+/// [`PersistentDb::seed`]: crate::PersistentDb::seed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SeedFormat {
+    /// A single JSON document.
+    Json,
+    /// One JSON patch per line, applied to an empty document in order. A line
+    /// may also be an object with a `patch` and an optional `prefix`, like
+    /// the records printed by `bigjson dump`.
+    Ndjson,
+}
+
+impl SeedFormat {
+    /// Guesses the format from the extension of `path`, `.ndjson` and
+    /// `.jsonl` are NDJSON and everything else is JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ndjson" | "jsonl") => SeedFormat::Ndjson,
+            _ => SeedFormat::Json,
+        }
+    }
+}
+
+impl Display for SeedFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedFormat::Json => f.write_str("json"),
+            SeedFormat::Ndjson => f.write_str("ndjson"),
+        }
+    }
+}
+
+impl FromStr for SeedFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(SeedFormat::Json),
+            "ndjson" => Ok(SeedFormat::Ndjson),
+            _ => Err(format!(
+                "invalid seed format `{}`, expected `json` or `ndjson`",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for SeedFormat {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SeedFormat> for String {
+    fn from(format: SeedFormat) -> Self {
+        format.to_string()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SeedLine {
+    Patch(Vec<JsonPatch>),
+    Record {
+        #[serde(default)]
+        prefix: Option<JsonPointer>,
+        patch: Vec<JsonPatch>,
+    },
+}
+
+/// Reads the document from `source` without loading the whole file into
+/// memory first.
+pub(crate) fn read_seed(source: &Path, format: SeedFormat) -> Result<MemDb, PersistentDbError> {
+    let reader = BufReader::new(File::open(source)?);
+    match format {
+        SeedFormat::Json => Ok(MemDb::new(serde_json::from_reader::<_, Value>(reader)?)),
+        SeedFormat::Ndjson => {
+            let mut db = MemDb::default();
+            for (index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let invalid = |reason: String| PersistentDbError::InvalidSeed {
+                    line: index + 1,
+                    reason,
+                };
+                let (prefix, patch) = match serde_json::from_str(&line) {
+                    Ok(SeedLine::Patch(patch)) => (None, patch),
+                    Ok(SeedLine::Record { prefix, patch }) => (prefix, patch),
+                    Err(err) => return Err(invalid(err.to_string())),
+                };
+                db.patch(prefix.as_ref(), patch)
+                    .map_err(|err| invalid(err.to_string()))?;
+            }
+            Ok(db)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::PersistentDb;
+
+    #[test]
+    fn test_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("seed.json");
+        std::fs::write(&json_path, br#"{"items": [1, 2]}"#).unwrap();
+        let ndjson_path = dir.path().join("seed.ndjson");
+        std::fs::write(
+            &ndjson_path,
+            concat!(
+                r#"[{"op": "add", "path": "/items", "value": []}]"#,
+                "\n\n",
+                r#"{"seq": 2, "prefix": "/items", "patch": [{"op": "add", "path": "/-", "value": 3}]}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        assert_eq!(SeedFormat::from_path(&ndjson_path), SeedFormat::Ndjson);
+
+        let data_dir = dir.path().join("data");
+        let mut pdb = PersistentDb::open(&data_dir).unwrap();
+        let db = pdb.seed(&json_path, SeedFormat::Json, false).unwrap();
+        assert_eq!(db.root(), json!({ "items": [1, 2] }));
+
+        // a data directory with data is only replaced if forced
+        assert!(matches!(
+            pdb.seed(&ndjson_path, SeedFormat::Ndjson, false),
+            Err(PersistentDbError::NotEmpty { .. })
+        ));
+        pdb.seed(&ndjson_path, SeedFormat::Ndjson, true).unwrap();
+
+        drop(pdb);
+        let pdb = PersistentDb::open(&data_dir).unwrap();
+        assert_eq!(pdb.create_memdb().unwrap().root(), json!({ "items": [3] }));
+
+        std::fs::write(
+            &ndjson_path,
+            "[]\n[{\"op\": \"remove\", \"path\": \"/a\"}]\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            read_seed(&ndjson_path, SeedFormat::Ndjson),
+            Err(PersistentDbError::InvalidSeed { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+use memdb::Node;
+use serde_json::{Map, Number, Value};
+
+use crate::{PersistentDbError, SnapshotFormat};
+
+/// Start of a snapshot in the binary format, JSON never starts with these
+/// bytes.
+const BINARY_MAGIC: &[u8; 4] = b"BJSN";
+const BINARY_VERSION: u8 = 1;
+
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_U64: u8 = 3;
+const TAG_I64: u8 = 4;
+const TAG_F64: u8 = 5;
+const TAG_STRING: u8 = 6;
+const TAG_ARRAY: u8 = 7;
+const TAG_OBJECT: u8 = 8;
+
+/// How deep arrays and objects may be nested in a snapshot, the most which
+/// `serde_json` parses. Deeper documents are rejected rather than overflowing
+/// the stack, or writing a snapshot which can not be loaded.
+const MAX_DEPTH: usize = 127;
+
+/// Returns the depth inside one more container, failing beyond [`MAX_DEPTH`].
+fn nested(depth: usize) -> Result<usize, PersistentDbError> {
+    if depth < MAX_DEPTH {
+        Ok(depth + 1)
+    } else {
+        Err(PersistentDbError::TooDeep {
+            max_depth: MAX_DEPTH,
+        })
+    }
+}
+
+pub(crate) fn encode_snapshot(
+    root: &Node,
+    format: SnapshotFormat,
+) -> Result<Vec<u8>, PersistentDbError> {
+    match format {
+        SnapshotFormat::Json => {
+            check_depth(root, 0)?;
+            Ok(serde_json::to_vec(root)?)
+        }
+        SnapshotFormat::Binary => {
+            let mut data = Vec::new();
+            data.extend_from_slice(BINARY_MAGIC);
+            data.push(BINARY_VERSION);
+            encode_node(&mut data, root, 0)?;
+            Ok(data)
+        }
+    }
+}
+
+/// Decodes a snapshot written in any [`SnapshotFormat`].
+pub(crate) fn decode_snapshot(data: &[u8]) -> Result<Value, PersistentDbError> {
+    match data.strip_prefix(BINARY_MAGIC) {
+        Some([BINARY_VERSION, data @ ..]) => {
+            let mut decoder = Decoder { data, depth: 0 };
+            let value = decoder.value()?;
+            if !decoder.data.is_empty() {
+                return Err(PersistentDbError::CorruptedSnapshot);
+            }
+            Ok(value)
+        }
+        Some(_) => Err(PersistentDbError::CorruptedSnapshot),
+        None => Ok(serde_json::from_slice(data)?),
+    }
+}
+
+fn encode_len(data: &mut Vec<u8>, mut len: usize) {
+    // LEB128
+    while len >= 0x80 {
+        data.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    data.push(len as u8);
+}
+
+fn encode_str(data: &mut Vec<u8>, s: &str) {
+    encode_len(data, s.len());
+    data.extend_from_slice(s.as_bytes());
+}
+
+fn check_depth(node: &Node, depth: usize) -> Result<(), PersistentDbError> {
+    match node {
+        Node::Array(array) => {
+            let depth = nested(depth)?;
+            array.iter().try_for_each(|node| check_depth(node, depth))
+        }
+        Node::Object(obj) => {
+            let depth = nested(depth)?;
+            obj.values().try_for_each(|node| check_depth(node, depth))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn encode_node(data: &mut Vec<u8>, node: &Node, depth: usize) -> Result<(), PersistentDbError> {
+    match node {
+        Node::Null => data.push(TAG_NULL),
+        Node::Bool(false) => data.push(TAG_FALSE),
+        Node::Bool(true) => data.push(TAG_TRUE),
+        Node::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                data.push(TAG_U64);
+                data.extend_from_slice(&n.to_le_bytes());
+            } else if let Some(n) = n.as_i64() {
+                data.push(TAG_I64);
+                data.extend_from_slice(&n.to_le_bytes());
+            } else {
+                // serde_json numbers are either integers or finite floats
+                data.push(TAG_F64);
+                data.extend_from_slice(&n.as_f64().unwrap_or_default().to_le_bytes());
+            }
+        }
+        Node::String(s) => {
+            data.push(TAG_STRING);
+            encode_str(data, s);
+        }
+        Node::Array(array) => {
+            let depth = nested(depth)?;
+            data.push(TAG_ARRAY);
+            encode_len(data, array.len());
+            for node in array.iter() {
+                encode_node(data, node, depth)?;
+            }
+        }
+        Node::Object(obj) => {
+            let depth = nested(depth)?;
+            data.push(TAG_OBJECT);
+            encode_len(data, obj.len());
+            for (key, node) in obj.iter() {
+                encode_str(data, key);
+                encode_node(data, node, depth)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    /// Number of containers the decoder is inside.
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PersistentDbError> {
+        if self.data.len() < len {
+            return Err(PersistentDbError::CorruptedSnapshot);
+        }
+        let (bytes, data) = self.data.split_at(len);
+        self.data = data;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], PersistentDbError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn len(&mut self) -> Result<usize, PersistentDbError> {
+        let mut len = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let [byte] = self.array()?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                // every element takes at least one byte
+                if len > self.data.len() {
+                    return Err(PersistentDbError::CorruptedSnapshot);
+                }
+                return Ok(len);
+            }
+        }
+        Err(PersistentDbError::CorruptedSnapshot)
+    }
+
+    fn string(&mut self) -> Result<String, PersistentDbError> {
+        let len = self.len()?;
+        std::str::from_utf8(self.bytes(len)?)
+            .map(ToString::to_string)
+            .map_err(|_| PersistentDbError::CorruptedSnapshot)
+    }
+
+    fn value(&mut self) -> Result<Value, PersistentDbError> {
+        let [tag] = self.array()?;
+        match tag {
+            TAG_NULL => Ok(Value::Null),
+            TAG_FALSE => Ok(Value::Bool(false)),
+            TAG_TRUE => Ok(Value::Bool(true)),
+            TAG_U64 => Ok(u64::from_le_bytes(self.array()?).into()),
+            TAG_I64 => Ok(i64::from_le_bytes(self.array()?).into()),
+            TAG_F64 => Number::from_f64(f64::from_le_bytes(self.array()?))
+                .map(Value::Number)
+                .ok_or(PersistentDbError::CorruptedSnapshot),
+            TAG_STRING => Ok(Value::String(self.string()?)),
+            TAG_ARRAY => {
+                let len = self.len()?;
+                self.depth = nested(self.depth)?;
+                let mut array = Vec::with_capacity(len);
+                for _ in 0..len {
+                    array.push(self.value()?);
+                }
+                self.depth -= 1;
+                Ok(Value::Array(array))
+            }
+            TAG_OBJECT => {
+                let len = self.len()?;
+                self.depth = nested(self.depth)?;
+                let mut obj = Map::new();
+                for _ in 0..len {
+                    let key = self.string()?;
+                    obj.insert(key, self.value()?);
+                }
+                self.depth -= 1;
+                Ok(Value::Object(obj))
+            }
+            _ => Err(PersistentDbError::CorruptedSnapshot),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let value = json!({
+            "null": null,
+            "bool": [true, false],
+            "numbers": [0, 1, u64::MAX, -1, i64::MIN, 0.5, -1e300],
+            "string": "abc\u{1f600}",
+            "nested": { "": [[], {}], "long": "x".repeat(300) },
+        });
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let data = encode_snapshot(&Node::from(value.clone()), format).unwrap();
+            assert_eq!(decode_snapshot(&data).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let data = encode_snapshot(
+            &Node::from(json!({ "a": [1, 2, 3] })),
+            SnapshotFormat::Binary,
+        )
+        .unwrap();
+        for len in BINARY_MAGIC.len()..data.len() {
+            assert!(matches!(
+                decode_snapshot(&data[..len]),
+                Err(PersistentDbError::CorruptedSnapshot)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_too_deep() {
+        let nest = |depth| {
+            (0..depth).fold(Node::Null, |node, _| {
+                Node::Array(std::sync::Arc::new(vec![node]))
+            })
+        };
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let data = encode_snapshot(&nest(MAX_DEPTH), format).unwrap();
+            assert_eq!(decode_snapshot(&data).unwrap(), nest(MAX_DEPTH));
+            assert!(matches!(
+                encode_snapshot(&nest(MAX_DEPTH + 1), format),
+                Err(PersistentDbError::TooDeep { .. })
+            ));
+        }
+
+        // a crafted snapshot fails instead of overflowing the stack
+        let mut data = BINARY_MAGIC.to_vec();
+        data.push(BINARY_VERSION);
+        for _ in 0..100_000 {
+            data.extend_from_slice(&[TAG_ARRAY, 1]);
+        }
+        data.push(TAG_NULL);
+        assert!(matches!(
+            decode_snapshot(&data),
+            Err(PersistentDbError::TooDeep { .. })
+        ));
+    }
+}
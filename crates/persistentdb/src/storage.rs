@@ -0,0 +1,458 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use memdb::{MemDb, Node};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    recovery::unix_millis, Backup, PersistentDb, PersistentDbError, PersistentDbOptions,
+    RecordStamp,
+};
+
+/// A change together with its position in the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    /// Milliseconds since the UNIX epoch when the change was appended.
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<JsonPointer>,
+    pub patch: Vec<JsonPatch>,
+}
+
+/// The changes after a position in the log, see [`Storage::changes_since`].
+///
+/// The data they are read from stays available until they are dropped, even
+/// if the storage is compacted meanwhile.
+pub struct Changes {
+    /// The document after the change with the given stamp, present if the
+    /// changes up to there are no longer available.
+    pub snapshot: Option<LazySnapshot>,
+    /// The changes after the position, or after the snapshot if there is one.
+    pub changes: Box<dyn Iterator<Item = Result<Change, PersistentDbError>> + Send>,
+}
+
+impl Debug for Changes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Changes")
+            .field("snapshot", &self.snapshot)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A snapshot which is only read by [`LazySnapshot::load`], so that the
+/// storage does not have to stay locked while it is decoded.
+pub struct LazySnapshot {
+    /// Stamp of the last change contained in the snapshot.
+    pub stamp: RecordStamp,
+    load: Box<dyn FnOnce() -> Result<Node, PersistentDbError> + Send>,
+}
+
+impl LazySnapshot {
+    pub fn new(
+        stamp: RecordStamp,
+        load: impl FnOnce() -> Result<Node, PersistentDbError> + Send + 'static,
+    ) -> Self {
+        Self {
+            stamp,
+            load: Box::new(load),
+        }
+    }
+
+    pub fn load(self) -> Result<Node, PersistentDbError> {
+        (self.load)()
+    }
+}
+
+impl Debug for LazySnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazySnapshot")
+            .field("stamp", &self.stamp)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where a document and the changes applied to it are persisted.
+///
+/// [`PersistentDb`] with its snapshot and block files is the default
+/// implementation, [`MemoryStorage`] keeps everything in memory. Backends
+/// report their own errors as [`PersistentDbError::Storage`].
+pub trait Storage: Send + 'static {
+    /// Loads the document with every change appended so far.
+    fn create_memdb(&self) -> Result<MemDb, PersistentDbError>;
+
+    /// Appends a change which has been applied to the document at `prefix`,
+    /// and returns its position.
+    fn append(
+        &mut self,
+        prefix: Option<&JsonPointer>,
+        patch: &[JsonPatch],
+    ) -> Result<RecordStamp, PersistentDbError>;
+
+    /// Position of the last appended change.
+    fn last_record(&self) -> RecordStamp;
+
+    /// Position of the last change which survives a crash, the last
+    /// appended one unless the storage defers syncing.
+    fn synced_record(&self) -> RecordStamp {
+        self.last_record()
+    }
+
+    /// Appends a change read from another storage, keeping its position.
+    fn append_change(&mut self, change: &Change) -> Result<(), PersistentDbError> {
+        let _ = change;
+        Err(PersistentDbError::Unsupported {
+            operation: "replication",
+        })
+    }
+
+    /// Replaces all data with `snapshot`, the document after the change at
+    /// `stamp` in another storage.
+    fn reset(&mut self, snapshot: Node, stamp: RecordStamp) -> Result<(), PersistentDbError> {
+        let _ = (snapshot, stamp);
+        Err(PersistentDbError::Unsupported {
+            operation: "replication",
+        })
+    }
+
+    /// Returns the changes after the one with the sequence number `seq`.
+    ///
+    /// Fails with [`PersistentDbError::AheadOfLog`] if `seq` is after the
+    /// last appended change.
+    fn changes_since(&self, seq: u64) -> Result<Changes, PersistentDbError> {
+        let _ = seq;
+        Err(PersistentDbError::Unsupported {
+            operation: "replication",
+        })
+    }
+
+    /// Ends a batch of appends.
+    fn commit(&mut self) -> Result<(), PersistentDbError> {
+        Ok(())
+    }
+
+    /// Forces all appended changes to durable storage.
+    fn sync(&mut self) -> Result<(), PersistentDbError> {
+        Ok(())
+    }
+
+    /// How long [`Storage::commit`] may be delayed while no changes arrive.
+    fn pending_sync_deadline(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Replaces the changes appended so far with `snapshot`, the document
+    /// after the last of them, if that is worthwhile.
+    fn compact(&mut self, snapshot: Node) -> Result<(), PersistentDbError>;
+
+    /// How often [`Storage::compact`] should be called.
+    fn compact_interval(&self) -> Duration {
+        PersistentDbOptions::default().compact_interval
+    }
+
+    /// Starts a backup of everything appended so far.
+    fn begin_backup(&self) -> Result<Box<dyn StorageBackup>, PersistentDbError> {
+        Err(PersistentDbError::Unsupported {
+            operation: "backup",
+        })
+    }
+}
+
+/// A backup started with [`Storage::begin_backup`].
+pub trait StorageBackup: Send + 'static {
+    /// Writes the backup to the directory `dest`, which must not exist yet.
+    fn write_to(&self, dest: &Path) -> Result<(), PersistentDbError>;
+}
+
+impl Storage for PersistentDb {
+    fn create_memdb(&self) -> Result<MemDb, PersistentDbError> {
+        PersistentDb::create_memdb(self)
+    }
+
+    fn append(
+        &mut self,
+        prefix: Option<&JsonPointer>,
+        patch: &[JsonPatch],
+    ) -> Result<RecordStamp, PersistentDbError> {
+        PersistentDb::append(self, prefix, patch)
+    }
+
+    fn last_record(&self) -> RecordStamp {
+        PersistentDb::last_record(self)
+    }
+
+    fn synced_record(&self) -> RecordStamp {
+        PersistentDb::synced_record(self)
+    }
+
+    fn append_change(&mut self, change: &Change) -> Result<(), PersistentDbError> {
+        PersistentDb::append_change(self, change)
+    }
+
+    fn reset(&mut self, snapshot: Node, stamp: RecordStamp) -> Result<(), PersistentDbError> {
+        PersistentDb::reset(self, snapshot, stamp)
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<Changes, PersistentDbError> {
+        PersistentDb::changes_since(self, seq)
+    }
+
+    fn commit(&mut self) -> Result<(), PersistentDbError> {
+        PersistentDb::commit(self)
+    }
+
+    fn sync(&mut self) -> Result<(), PersistentDbError> {
+        PersistentDb::sync(self)
+    }
+
+    fn pending_sync_deadline(&self) -> Option<Duration> {
+        PersistentDb::pending_sync_deadline(self)
+    }
+
+    fn compact(&mut self, snapshot: Node) -> Result<(), PersistentDbError> {
+        PersistentDb::compact(self, snapshot)
+    }
+
+    fn compact_interval(&self) -> Duration {
+        PersistentDb::compact_interval(self)
+    }
+
+    fn begin_backup(&self) -> Result<Box<dyn StorageBackup>, PersistentDbError> {
+        Ok(Box::new(PersistentDb::begin_backup(self)?))
+    }
+}
+
+impl StorageBackup for Backup {
+    fn write_to(&self, dest: &Path) -> Result<(), PersistentDbError> {
+        Backup::write_to(self, dest)
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryStorageInner {
+    snapshot: Option<(RecordStamp, Node)>,
+    changes: Vec<Change>,
+    last_record: RecordStamp,
+    /// The last record when it was last synced.
+    synced_record: RecordStamp,
+}
+
+impl MemoryStorageInner {
+    fn push(&mut self, change: Change) {
+        self.last_record = RecordStamp {
+            seq: change.seq,
+            timestamp: change.timestamp,
+        };
+        self.changes.push(change);
+    }
+}
+
+/// A [`Storage`] which keeps the document in memory, mostly useful for
+/// tests.
+///
+/// Clones share the same data, so a test can keep a clone to inspect what
+/// has been persisted.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<MemoryStorageInner>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of changes appended since the last compaction.
+    pub fn pending_records(&self) -> usize {
+        self.inner.lock().unwrap().changes.len()
+    }
+
+    /// Sequence number of the last record which has been explicitly synced.
+    pub fn synced_seq(&self) -> u64 {
+        self.inner.lock().unwrap().synced_record.seq
+    }
+
+    /// Returns a storage with only the changes which have been synced, as
+    /// they would be found after a crash.
+    pub fn crashed(&self) -> Self {
+        let inner = self.inner.lock().unwrap();
+        let synced_seq = inner.synced_record.seq;
+        Self {
+            inner: Arc::new(Mutex::new(MemoryStorageInner {
+                snapshot: inner.snapshot.clone(),
+                changes: inner
+                    .changes
+                    .iter()
+                    .filter(|change| change.seq <= synced_seq)
+                    .cloned()
+                    .collect(),
+                last_record: inner.synced_record,
+                synced_record: inner.synced_record,
+            })),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create_memdb(&self) -> Result<MemDb, PersistentDbError> {
+        let inner = self.inner.lock().unwrap();
+        let mut db = match &inner.snapshot {
+            Some((_, snapshot)) => MemDb::from(snapshot.clone()),
+            None => MemDb::default(),
+        };
+        for change in &inner.changes {
+            db.patch(change.prefix.as_ref(), change.patch.clone())?;
+        }
+        Ok(db)
+    }
+
+    fn append(
+        &mut self,
+        prefix: Option<&JsonPointer>,
+        patch: &[JsonPatch],
+    ) -> Result<RecordStamp, PersistentDbError> {
+        let mut inner = self.inner.lock().unwrap();
+        let change = Change {
+            seq: inner.last_record.seq + 1,
+            timestamp: unix_millis(SystemTime::now()),
+            prefix: prefix.cloned(),
+            patch: patch.to_vec(),
+        };
+        inner.push(change);
+        Ok(inner.last_record)
+    }
+
+    fn last_record(&self) -> RecordStamp {
+        self.inner.lock().unwrap().last_record
+    }
+
+    fn synced_record(&self) -> RecordStamp {
+        self.inner.lock().unwrap().synced_record
+    }
+
+    fn append_change(&mut self, change: &Change) -> Result<(), PersistentDbError> {
+        self.inner.lock().unwrap().push(change.clone());
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), PersistentDbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.synced_record = inner.last_record;
+        Ok(())
+    }
+
+    fn reset(&mut self, snapshot: Node, stamp: RecordStamp) -> Result<(), PersistentDbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.snapshot = Some((stamp, snapshot));
+        inner.changes.clear();
+        inner.last_record = stamp;
+        inner.synced_record = stamp;
+        Ok(())
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<Changes, PersistentDbError> {
+        let inner = self.inner.lock().unwrap();
+        if seq > inner.last_record.seq {
+            return Err(PersistentDbError::AheadOfLog {
+                seq,
+                last_seq: inner.last_record.seq,
+            });
+        }
+        let snapshot = inner
+            .snapshot
+            .clone()
+            .filter(|(stamp, _)| seq < stamp.seq || seq == 0)
+            .map(|(stamp, snapshot)| LazySnapshot::new(stamp, move || Ok(snapshot)));
+        let changes = inner
+            .changes
+            .iter()
+            .filter(|change| change.seq > seq)
+            .cloned()
+            .map(Ok)
+            .collect::<Vec<_>>();
+        Ok(Changes {
+            snapshot,
+            changes: Box::new(changes.into_iter()),
+        })
+    }
+
+    fn compact(&mut self, snapshot: Node) -> Result<(), PersistentDbError> {
+        let mut inner = self.inner.lock().unwrap();
+        // like a snapshot on the disk, which is synced before it is used
+        inner.snapshot = Some((inner.last_record, snapshot));
+        inner.changes.clear();
+        inner.synced_record = inner.last_record;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_pointer::json_pointer;
+    use serde_json::json;
+
+    use super::*;
+
+    fn add(storage: &mut dyn Storage, n: i32) {
+        storage
+            .append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/items/-"),
+                    value: json!(n),
+                }],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        let mut writer: Box<dyn Storage> = Box::new(storage.clone());
+        writer
+            .append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/items"),
+                    value: json!([]),
+                }],
+            )
+            .unwrap();
+        add(&mut *writer, 1);
+
+        let snapshot = storage.create_memdb().unwrap().snapshot();
+        writer.compact(snapshot).unwrap();
+        assert_eq!(storage.pending_records(), 0);
+
+        add(&mut *writer, 2);
+        assert_eq!(storage.pending_records(), 1);
+        assert_eq!(
+            storage.create_memdb().unwrap().root(),
+            json!({ "items": [1, 2] })
+        );
+
+        let changes = storage.changes_since(0).unwrap();
+        assert_eq!(changes.snapshot.unwrap().stamp.seq, 2);
+        let changes = changes.changes.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].seq, 3);
+        assert!(storage.changes_since(2).unwrap().snapshot.is_none());
+        assert!(matches!(
+            storage.changes_since(4),
+            Err(PersistentDbError::AheadOfLog {
+                seq: 4,
+                last_seq: 3
+            })
+        ));
+        assert!(matches!(
+            writer.begin_backup(),
+            Err(PersistentDbError::Unsupported { .. })
+        ));
+    }
+}
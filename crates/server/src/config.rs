@@ -1,22 +1,265 @@
-use std::path::PathBuf;
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
 use clap::Parser;
+use json_pointer::JsonPointer;
+use persistentdb::{
+    Compression, Durability, EncryptionKey, PersistentDbError, PersistentDbOptions, RecoveryTarget,
+    SeedFormat, SnapshotFormat, SnapshotRetention,
+};
 use serde::{Deserialize, Serialize};
 
+/// When a write request is acknowledged to the client.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteAck {
+    /// As soon as the change has been applied in memory.
+    #[default]
+    Fast,
+    /// After the change has been appended to the data directory and synced,
+    /// even if the durability level would sync it only later or never.
+    Durable,
+}
+
+impl Display for WriteAck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteAck::Fast => f.write_str("fast"),
+            WriteAck::Durable => f.write_str("durable"),
+        }
+    }
+}
+
+impl FromStr for WriteAck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(WriteAck::Fast),
+            "durable" => Ok(WriteAck::Durable),
+            _ => Err(format!(
+                "invalid write ack `{}`, expected `fast` or `durable`",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to writes when the persistence queue is full.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backpressure {
+    /// Wait until there is room in the queue.
+    #[default]
+    Block,
+    /// Reject the write with `503 Service Unavailable`.
+    Reject,
+    /// Reject all writes with `503 Service Unavailable` until the queue has
+    /// been drained and the disk can be written again.
+    ReadOnly,
+}
+
+impl Display for Backpressure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Backpressure::Block => f.write_str("block"),
+            Backpressure::Reject => f.write_str("reject"),
+            Backpressure::ReadOnly => f.write_str("read-only"),
+        }
+    }
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Backpressure::Block),
+            "reject" => Ok(Backpressure::Reject),
+            "read-only" => Ok(Backpressure::ReadOnly),
+            _ => Err(format!(
+                "invalid backpressure `{}`, expected `block`, `reject` or `read-only`",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Parser)]
 #[clap(author, version, about)]
 pub struct ServerConfig {
     #[clap(long)]
     pub(crate) data_dir: Option<PathBuf>,
+    /// Directory receiving the backups created with `POST /admin/backup`
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// Replace the data directory with this backup before starting
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) restore_from: Option<PathBuf>,
+    /// Initialize the empty data directory with the document in this file
+    #[clap(
+        long,
+        requires = "data-dir",
+        conflicts_with_all = &["restore-from", "recover-to", "follow", "start-from-generation"]
+    )]
+    #[serde(default)]
+    pub(crate) seed_from: Option<PathBuf>,
+    /// Format of the seed file: `json` or `ndjson` with one JSON patch per
+    /// line, guessed from its extension by default
+    #[clap(long, requires = "seed-from")]
+    #[serde(default)]
+    pub(crate) seed_format: Option<SeedFormat>,
+    /// Replace the data of a data directory which is not empty with the seed
+    /// file
+    #[clap(long, requires = "seed-from")]
+    #[serde(default)]
+    pub(crate) force_seed: bool,
     #[clap(long, default_value = "127.0.0.1:3000")]
     pub(crate) bind: String,
+    /// Restore the data as it was at `seq:<sequence number>` or
+    /// `timestamp:<unix milliseconds>`, discarding all later changes
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) recover_to: Option<RecoveryTarget>,
+    /// Replicate the document from the primary at this URL, serving reads
+    /// and rejecting writes
+    #[clap(long, conflicts_with = "recover-to")]
+    #[serde(default)]
+    pub(crate) follow: Option<String>,
+    /// Start from the snapshot generation with this number, discarding all
+    /// later changes
+    #[clap(long, conflicts_with_all = &["recover-to", "follow"])]
+    #[serde(default)]
+    pub(crate) start_from_generation: Option<u64>,
+    /// When writes are synced to the disk: `none`, `batch` or
+    /// `periodic:<milliseconds>`
+    #[clap(long, default_value = "none")]
+    #[serde(default)]
+    pub(crate) durability: Durability,
+    /// How snapshots and block files are compressed: `none`, `zstd` or
+    /// `zstd:<level>`
+    #[clap(long, default_value = "none")]
+    #[serde(default)]
+    pub(crate) compression: Compression,
+    /// Encrypt snapshots and block files with the key in this file, either
+    /// 32 raw bytes or 64 hexadecimal digits
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) encryption_key_file: Option<PathBuf>,
+    /// Encrypt snapshots and block files with the key in this environment
+    /// variable, as 64 hexadecimal digits
+    #[clap(long, conflicts_with = "encryption-key-file")]
+    #[serde(default)]
+    pub(crate) encryption_key_env: Option<String>,
+    /// Also read unencrypted snapshots and block files, to enable encryption
+    /// for an existing data directory until the next compaction
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) allow_unencrypted: bool,
+    /// Encoding of new snapshots: `json` or `binary`
+    #[clap(long, default_value = "json")]
+    #[serde(default)]
+    pub(crate) snapshot_format: SnapshotFormat,
+    /// Which snapshots are kept as restore points: `count:<snapshots>`,
+    /// including the current one, or `hours:<hours>`
+    #[clap(long, default_value = "count:1")]
+    #[serde(default)]
+    pub(crate) snapshot_retention: SnapshotRetention,
+    /// Default acknowledgement of writes: `fast` or `durable`, overridable
+    /// per request
+    #[clap(long, default_value = "fast")]
+    #[serde(default)]
+    pub(crate) write_ack: WriteAck,
+    /// Maximum number of writes waiting to be persisted
+    #[clap(long, default_value = "65536")]
+    #[serde(default = "default_write_queue_size")]
+    pub(crate) write_queue_size: usize,
+    /// Behavior when the write queue is full: `block`, `reject` or
+    /// `read-only`
+    #[clap(long, default_value = "block")]
+    #[serde(default)]
+    pub(crate) backpressure: Backpressure,
+    /// Size in bytes after which a new block file is started
+    #[clap(long, default_value = "268435456")]
+    #[serde(default = "default_max_block_size")]
+    pub(crate) max_block_size: u64,
+    /// Compact once more than this many block files are not covered by the
+    /// snapshot
+    #[clap(long, default_value = "5")]
+    #[serde(default = "default_compact_block_threshold")]
+    pub(crate) compact_block_threshold: usize,
+    /// Also compact once the block files are larger than this many times the
+    /// snapshot
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) compact_size_ratio: Option<f64>,
+    /// Seconds between checks whether the data should be compacted
+    #[clap(long, default_value = "1800")]
+    #[serde(default = "default_compact_interval")]
+    pub(crate) compact_interval: u64,
+    /// Number of changes which can be undone with `POST /undo`, 0 disables
+    /// undo
+    #[clap(long, default_value = "0")]
+    #[serde(default)]
+    pub(crate) history_size: usize,
+    /// Keep a separate undo history for this subtree instead of one for the
+    /// whole document, may be given more than once
+    #[clap(long, requires = "history-size")]
+    #[serde(default)]
+    pub(crate) history_scope: Vec<JsonPointer>,
+}
+
+fn default_write_queue_size() -> usize {
+    65536
+}
+
+fn default_max_block_size() -> u64 {
+    1024 * 1024 * 256
+}
+
+fn default_compact_block_threshold() -> usize {
+    5
+}
+
+fn default_compact_interval() -> u64 {
+    60 * 30
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             data_dir: None,
+            backup_dir: None,
+            restore_from: None,
+            seed_from: None,
+            seed_format: None,
+            force_seed: false,
             bind: "127.0.0.1:3000".to_string(),
+            recover_to: None,
+            follow: None,
+            start_from_generation: None,
+            durability: Durability::None,
+            compression: Compression::None,
+            encryption_key_file: None,
+            encryption_key_env: None,
+            allow_unencrypted: false,
+            snapshot_format: SnapshotFormat::Json,
+            snapshot_retention: SnapshotRetention::Count(1),
+            write_ack: WriteAck::Fast,
+            write_queue_size: default_write_queue_size(),
+            backpressure: Backpressure::Block,
+            max_block_size: default_max_block_size(),
+            compact_block_threshold: default_compact_block_threshold(),
+            compact_size_ratio: None,
+            compact_interval: default_compact_interval(),
+            history_size: 0,
+            history_scope: Vec::new(),
         }
     }
 }
@@ -30,7 +273,234 @@ impl ServerConfig {
         }
     }
 
+    #[must_use]
+    pub fn backup_dir(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            backup_dir: Some(path.into()),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn restore_from(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            restore_from: Some(path.into()),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn seed_from(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            seed_from: Some(path.into()),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn seed_format(self, format: SeedFormat) -> Self {
+        Self {
+            seed_format: Some(format),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn force_seed(self, force_seed: bool) -> Self {
+        Self { force_seed, ..self }
+    }
+
+    #[must_use]
+    pub fn recover_to(self, target: RecoveryTarget) -> Self {
+        Self {
+            recover_to: Some(target),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn follow(self, primary: impl Into<String>) -> Self {
+        Self {
+            follow: Some(primary.into()),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn start_from_generation(self, generation: u64) -> Self {
+        Self {
+            start_from_generation: Some(generation),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn durability(self, durability: Durability) -> Self {
+        Self { durability, ..self }
+    }
+
+    #[must_use]
+    pub fn compression(self, compression: Compression) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn encryption_key_file(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            encryption_key_file: Some(path.into()),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn encryption_key_env(self, name: impl Into<String>) -> Self {
+        Self {
+            encryption_key_env: Some(name.into()),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn allow_unencrypted(self, allow_unencrypted: bool) -> Self {
+        Self {
+            allow_unencrypted,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot_format(self, snapshot_format: SnapshotFormat) -> Self {
+        Self {
+            snapshot_format,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot_retention(self, snapshot_retention: SnapshotRetention) -> Self {
+        Self {
+            snapshot_retention,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn write_ack(self, write_ack: WriteAck) -> Self {
+        Self { write_ack, ..self }
+    }
+
+    #[must_use]
+    pub fn write_queue_size(self, write_queue_size: usize) -> Self {
+        Self {
+            write_queue_size,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn backpressure(self, backpressure: Backpressure) -> Self {
+        Self {
+            backpressure,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn max_block_size(self, max_block_size: u64) -> Self {
+        Self {
+            max_block_size,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn compact_block_threshold(self, compact_block_threshold: usize) -> Self {
+        Self {
+            compact_block_threshold,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn compact_size_ratio(self, ratio: f64) -> Self {
+        Self {
+            compact_size_ratio: Some(ratio),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn compact_interval(self, interval: Duration) -> Self {
+        Self {
+            compact_interval: interval.as_secs(),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn history_size(self, history_size: usize) -> Self {
+        Self {
+            history_size,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn history_scope(mut self, scope: JsonPointer) -> Self {
+        self.history_scope.push(scope);
+        self
+    }
+
     pub fn parse() -> Self {
         Parser::parse()
     }
+
+    pub(crate) fn persistent_db_options(&self) -> Result<PersistentDbOptions, PersistentDbError> {
+        let mut options = PersistentDbOptions::default()
+            .durability(self.durability)
+            .compression(self.compression)
+            .snapshot_format(self.snapshot_format)
+            .snapshot_retention(self.snapshot_retention)
+            .max_block_size(self.max_block_size)
+            .compact_block_threshold(self.compact_block_threshold)
+            .compact_interval(Duration::from_secs(self.compact_interval));
+        if let Some(ratio) = self.compact_size_ratio {
+            options = options.compact_size_ratio(ratio);
+        }
+        if let Some(key) = self.encryption_key()? {
+            options = options
+                .encryption_key(key)
+                .allow_unencrypted(self.allow_unencrypted);
+        }
+        Ok(options)
+    }
+
+    /// The subtrees with an undo history.
+    pub(crate) fn history_scopes(&self) -> Vec<JsonPointer> {
+        if self.history_size == 0 {
+            Vec::new()
+        } else if self.history_scope.is_empty() {
+            vec![JsonPointer::root()]
+        } else {
+            self.history_scope.clone()
+        }
+    }
+
+    fn encryption_key(&self) -> Result<Option<EncryptionKey>, PersistentDbError> {
+        if let Some(path) = &self.encryption_key_file {
+            return EncryptionKey::load(path).map(Some);
+        }
+        match &self.encryption_key_env {
+            Some(name) => match std::env::var(name) {
+                Ok(key) => EncryptionKey::from_hex(&key).map(Some),
+                Err(_) => Err(PersistentDbError::InvalidEncryptionKey {
+                    reason: format!("environment variable `{}` is not set", name),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
 }
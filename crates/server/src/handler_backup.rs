@@ -0,0 +1,60 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use persistentdb::PersistentDbError;
+use poem::{
+    error::InternalServerError,
+    handler,
+    http::StatusCode,
+    web::{Data, Json},
+    Error, Result,
+};
+use serde_json::{json, Value};
+
+use crate::state::State;
+
+#[handler]
+pub(crate) async fn handler_backup(state: Data<&State>) -> Result<Json<Value>> {
+    let (storage, backup_dir) = match (&state.storage, &state.backup_dir) {
+        (Some(storage), Some(backup_dir)) => (storage, backup_dir),
+        _ => {
+            return Err(Error::from_string(
+                "backups are not configured",
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let dest = backup_dir.join(format!("backup-{}", millis));
+    tracing::info!(dest = %dest.display(), "backup");
+
+    // the storage lock is blocking, and may be held by the sync loop while it
+    // retries a failed write
+    let res = tokio::task::spawn_blocking({
+        let storage = storage.clone();
+        let dest = dest.clone();
+        move || {
+            // only collecting the files blocks the sync loop, copying them
+            // does not
+            let backup = storage.lock().begin_backup()?;
+            backup.write_to(&dest)
+        }
+    })
+    .await
+    .map_err(InternalServerError)?;
+    match res {
+        Ok(()) => {}
+        Err(err @ PersistentDbError::Unsupported { .. }) => {
+            return Err(Error::from_string(
+                err.to_string(),
+                StatusCode::NOT_IMPLEMENTED,
+            ))
+        }
+        Err(err) => return Err(InternalServerError(err)),
+    }
+
+    Ok(Json(json!({ "path": dest })))
+}
@@ -2,22 +2,45 @@ use json_pointer::JsonPointer;
 use poem::{
     error::{BadRequest, InternalServerError},
     handler,
+    http::{header, HeaderMap, StatusCode},
     web::{Data, Path},
-    Result,
+    IntoResponse, Response, Result,
 };
-use serde_json::Value;
 
 use crate::{state::State, utils::normalize_path};
 
 #[handler]
-pub(crate) async fn handler_get(state: Data<&State>, path: Path<String>) -> Result<String> {
+pub(crate) async fn handler_get(
+    state: Data<&State>,
+    path: Path<String>,
+    headers: &HeaderMap,
+) -> Result<Response> {
     let path = normalize_path(&path);
     tracing::debug!(path = path.as_str(), "get");
 
     let path = path.parse::<JsonPointer>().map_err(BadRequest)?;
-    let locked_state = state.locked_state.read();
-    let value = locked_state.mdb.get(&path).unwrap_or(&Value::Null);
-    let value_str = serde_json::to_string(&value).map_err(InternalServerError)?;
+    // cloning a node is cheap, serialize it without holding the lock
+    let (value, revision) = {
+        let locked_state = state.locked_state.read();
+        let (value, revision) = locked_state.mdb.get_node_with_revision(&path);
+        (value.cloned().unwrap_or_default(), revision)
+    };
+    let etag = state.etag(revision);
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if not_modified {
+        return Ok(StatusCode::NOT_MODIFIED
+            .with_header(header::ETAG, etag)
+            .into_response());
+    }
 
-    Ok(value_str)
+    let value_str = serde_json::to_string(&value).map_err(InternalServerError)?;
+    Ok(value_str.with_header(header::ETAG, etag).into_response())
 }
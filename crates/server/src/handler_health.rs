@@ -0,0 +1,23 @@
+use poem::{handler, http::StatusCode, web::Data, IntoResponse, Response};
+
+use crate::{
+    state::State,
+    write_queue::{Health, HealthStatus},
+};
+
+#[handler]
+pub(crate) fn handler_health(state: Data<&State>) -> Response {
+    let health = match &state.write_queue {
+        Some(write_queue) => write_queue.health(),
+        None => Health {
+            status: HealthStatus::Ok,
+            write_queue_len: None,
+            write_queue_capacity: None,
+        },
+    };
+    let status = match health.status {
+        HealthStatus::Ok => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    poem::web::Json(health).with_status(status).into_response()
+}
@@ -1,38 +1,48 @@
 use json_patch::JsonPatch;
 use json_pointer::JsonPointer;
 use poem::{
-    error::BadRequest,
+    error::{BadRequest, PreconditionFailed},
     handler,
     web::{Data, Json, Path},
     Result,
 };
 use serde_json::Value;
 
-use crate::{state::State, subscription_patch::publish, utils::normalize_path};
+use crate::{
+    state::State,
+    subscription_patch::publish,
+    utils::{normalize_path, wait_durable, IfMatch},
+    WriteAck,
+};
 
 #[handler]
-pub(crate) fn handler_put(
+pub(crate) async fn handler_put(
     state: Data<&State>,
     path: Path<String>,
     value: Json<Value>,
+    write_ack: WriteAck,
+    if_match: IfMatch,
 ) -> Result<()> {
     let path = normalize_path(&path);
     tracing::debug!(path = path.as_str(), "post");
 
     let path = path.parse::<JsonPointer>().map_err(BadRequest)?;
-    let mut locked_state = state.locked_state.write();
-    let patch = vec![JsonPatch::Replace {
-        path,
-        value: value.0,
-    }];
-    locked_state
-        .mdb
-        .patch(None, patch.clone())
-        .map_err(BadRequest)?;
-    publish(&locked_state.mdb, &locked_state.subscriptions, None, &patch);
-
-    if let Some(patch_sender) = &state.patch_sender {
-        let _ = patch_sender.send((None, patch));
-    }
-    Ok(())
+    let slot = state.reserve_write().await?;
+    let ack = {
+        let mut locked_state = state.locked_state.write();
+        if_match
+            .check(&locked_state.mdb, &path)
+            .map_err(PreconditionFailed)?;
+        let patch = vec![JsonPatch::Replace {
+            path,
+            value: value.0,
+        }];
+        locked_state
+            .mdb
+            .patch(None, patch.clone())
+            .map_err(BadRequest)?;
+        publish(&locked_state.mdb, &locked_state.subscriptions, None, &patch);
+        slot.persist(None, patch, write_ack)
+    };
+    wait_durable(ack).await
 }
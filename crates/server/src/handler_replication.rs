@@ -0,0 +1,171 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use persistentdb::{Changes, PersistentDbError};
+use poem::{
+    error::InternalServerError,
+    handler,
+    http::StatusCode,
+    web::{
+        websocket::{Message, WebSocket},
+        Data, Query,
+    },
+    Error, IntoResponse, Result,
+};
+use serde::Deserialize;
+use tokio::{
+    sync::{broadcast::error::RecvError as BroadcastRecvError, mpsc},
+    time::MissedTickBehavior,
+};
+
+use crate::{replication::ReplicationMessage, state::State};
+
+/// How often an idle follower is pinged, well within its read timeout.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ReplicationParams {
+    /// Sequence number of the last change the follower has applied.
+    #[serde(default)]
+    from: u64,
+}
+
+/// Streams the persisted changes after `from` to a follower, first those in
+/// the storage and then those persisted while the follower is connected.
+///
+/// Only synced changes are streamed, so a follower does not get ahead of a
+/// primary which loses its unsynced changes in a crash. With
+/// [`Durability::None`](persistentdb::Durability::None) nothing is synced
+/// before shutdown and every change is streamed, so a follower can get ahead
+/// and is then rejected until it is seeded again.
+#[handler]
+pub(crate) async fn handler_replication(
+    state: Data<&State>,
+    Query(params): Query<ReplicationParams>,
+    ws: WebSocket,
+) -> Result<impl IntoResponse> {
+    let (storage, changes) = match (&state.storage, &state.changes) {
+        (Some(storage), Some(changes)) => (storage.clone(), changes.clone()),
+        _ => {
+            return Err(Error::from_string(
+                "replication requires persistence",
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+
+    // changes are published once they are synced, which happens while the
+    // storage is locked, so every change is either synced now or received
+    // later, possibly both
+    let from = params.from;
+    let res = tokio::task::spawn_blocking(move || {
+        let storage = storage.lock();
+        let catch_up = storage.changes_since(from)?;
+        Ok((changes.subscribe(), storage.synced_record().seq, catch_up))
+    })
+    .await
+    .map_err(InternalServerError)?;
+    let (mut live, last_seq, catch_up) = res.map_err(|err| match err {
+        PersistentDbError::AheadOfLog { .. } => {
+            Error::from_string(err.to_string(), StatusCode::BAD_REQUEST)
+        }
+        err => InternalServerError(err),
+    })?;
+    tracing::info!(from, last_seq, "follower connected");
+
+    // stops as soon as the receiver is dropped, also if the upgrade fails
+    let (tx, mut rx) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || read_catch_up(catch_up, from, last_seq, tx));
+
+    Ok(ws.on_upgrade(move |socket| async move {
+        let (mut sink, mut stream) = socket.split();
+
+        while let Some(msg) = rx.recv().await {
+            if sink.send(msg).await.is_err() {
+                return;
+            }
+        }
+
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ping.tick() => {
+                    if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                }
+                res = live.recv() => {
+                    let change = match res {
+                        Ok(change) if change.seq <= last_seq => continue,
+                        Ok(change) => change,
+                        Err(BroadcastRecvError::Lagged(_)) => {
+                            tracing::warn!("follower is too slow, disconnect");
+                            break;
+                        }
+                        Err(BroadcastRecvError::Closed) => break,
+                    };
+                    if sink.send(message(ReplicationMessage::Change(change))).await.is_err() {
+                        break;
+                    }
+                }
+                item = stream.next() => {
+                    if !matches!(item, Some(Ok(_))) {
+                        // follower closed
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = sink.close().await;
+    }))
+}
+
+/// Sends the snapshot and the changes up to `last_seq`, never reading further
+/// because the record after it may still be being written.
+fn read_catch_up(catch_up: Changes, from: u64, last_seq: u64, tx: mpsc::Sender<Message>) {
+    let mut seq = from;
+    if let Some(snapshot) = catch_up.snapshot {
+        let stamp = snapshot.stamp;
+        let value = match snapshot.load() {
+            Ok(value) => value,
+            Err(err) => {
+                tracing::error!(error = %err, "failed to read the snapshot for a follower");
+                let _ = tx.blocking_send(Message::Close(None));
+                return;
+            }
+        };
+        seq = stamp.seq;
+        let msg = ReplicationMessage::Snapshot {
+            seq: stamp.seq,
+            timestamp: stamp.timestamp,
+            value,
+        };
+        if tx.blocking_send(message(msg)).is_err() {
+            return;
+        }
+    }
+
+    let mut changes = catch_up.changes;
+    while seq < last_seq {
+        let change = match changes.next() {
+            Some(Ok(change)) => change,
+            Some(Err(err)) => {
+                // the follower reconnects and tries again
+                tracing::error!(error = %err, "failed to read changes for a follower");
+                let _ = tx.blocking_send(Message::Close(None));
+                return;
+            }
+            None => break,
+        };
+        seq = change.seq;
+        let msg = ReplicationMessage::Change(Arc::new(change));
+        if tx.blocking_send(message(msg)).is_err() {
+            return;
+        }
+    }
+}
+
+fn message(msg: ReplicationMessage) -> Message {
+    Message::text(serde_json::to_string(&msg).unwrap())
+}
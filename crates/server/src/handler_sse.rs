@@ -8,7 +8,6 @@ use poem::{
     },
     Result,
 };
-use serde_json::Value;
 use tokio_stream::StreamExt;
 
 use crate::{state::State, utils::normalize_path};
@@ -20,7 +19,11 @@ pub(crate) async fn handler_sse(state: Data<&State>, path: Path<String>) -> Resu
 
     let path = path.parse::<JsonPointer>().map_err(BadRequest)?;
     let mut locked_state = state.locked_state.write();
-    let value = locked_state.mdb.get(&path).cloned().unwrap_or(Value::Null);
+    let value = locked_state
+        .mdb
+        .get_node(&path)
+        .cloned()
+        .unwrap_or_default();
 
     let receiver = locked_state
         .subscriptions
@@ -30,6 +33,7 @@ pub(crate) async fn handler_sse(state: Data<&State>, path: Path<String>) -> Resu
             sender
         })
         .subscribe();
+    drop(locked_state);
 
     let first_item = Event::message(serde_json::to_string(&value).unwrap()).event_type("value");
     let stream = tokio_stream::once(first_item).chain(
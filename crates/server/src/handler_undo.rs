@@ -0,0 +1,96 @@
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use memdb::MemDbError;
+use poem::{
+    error::BadRequest,
+    handler,
+    http::StatusCode,
+    web::{Data, Json, Path},
+    Error, Result,
+};
+
+use crate::{
+    state::State,
+    subscription_patch::publish,
+    utils::{normalize_path, wait_durable},
+    WriteAck,
+};
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum HistoryOp {
+    Undo,
+    Redo,
+}
+
+#[handler]
+pub(crate) async fn handler_undo(
+    state: Data<&State>,
+    scope: Path<String>,
+    write_ack: WriteAck,
+) -> Result<Json<Vec<JsonPatch>>> {
+    handle(&state, &scope, HistoryOp::Undo, write_ack).await
+}
+
+#[handler]
+pub(crate) async fn handler_redo(
+    state: Data<&State>,
+    scope: Path<String>,
+    write_ack: WriteAck,
+) -> Result<Json<Vec<JsonPatch>>> {
+    handle(&state, &scope, HistoryOp::Redo, write_ack).await
+}
+
+async fn handle(
+    state: &State,
+    scope: &str,
+    op: HistoryOp,
+    write_ack: WriteAck,
+) -> Result<Json<Vec<JsonPatch>>> {
+    let scope = normalize_path(scope);
+    tracing::debug!(scope = scope.as_str(), ?op, "history");
+
+    let scope = scope.parse::<JsonPointer>().map_err(BadRequest)?;
+    let (patch, _) = undo_or_redo(state, &scope, op, write_ack).await?;
+    Ok(Json(patch))
+}
+
+/// Undoes or redoes the latest change below `scope`, publishing and
+/// persisting it like any other patch, and returns the applied patch with
+/// the revision it took.
+pub(crate) async fn undo_or_redo(
+    state: &State,
+    scope: &JsonPointer,
+    op: HistoryOp,
+    write_ack: WriteAck,
+) -> Result<(Vec<JsonPatch>, u64)> {
+    let slot = state.reserve_write().await?;
+    let (patch, revision, ack) = {
+        let mut locked_state = state.locked_state.write();
+        let res = match op {
+            HistoryOp::Undo => locked_state.mdb.undo(scope),
+            HistoryOp::Redo => locked_state.mdb.redo(scope),
+        };
+        let patch = match res {
+            Ok(Some(patch)) => patch,
+            Ok(None) => {
+                return Err(Error::from_string(
+                    match op {
+                        HistoryOp::Undo => "nothing to undo",
+                        HistoryOp::Redo => "nothing to redo",
+                    },
+                    StatusCode::CONFLICT,
+                ))
+            }
+            Err(err @ MemDbError::NoHistory { .. }) => {
+                return Err(Error::from_string(err.to_string(), StatusCode::NOT_FOUND))
+            }
+            Err(err) => return Err(BadRequest(err)),
+        };
+
+        publish(&locked_state.mdb, &locked_state.subscriptions, None, &patch);
+        let ack = slot.persist(None, patch.clone(), write_ack);
+        (patch, locked_state.mdb.revision(), ack)
+    };
+    wait_durable(ack).await?;
+    Ok((patch, revision))
+}
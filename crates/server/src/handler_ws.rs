@@ -3,6 +3,7 @@ use std::{collections::HashMap, sync::Arc};
 use futures_util::{stream::SplitSink, Sink, SinkExt, StreamExt};
 use json_patch::JsonPatch;
 use json_pointer::JsonPointer;
+use memdb::Node;
 use poem::{
     handler,
     web::{
@@ -12,12 +13,16 @@ use poem::{
     IntoResponse,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 use tokio::sync::{
     broadcast::error::RecvError as BroadcastRecvError, mpsc, mpsc::UnboundedSender, oneshot,
 };
 
-use crate::{state::State, subscription_patch::publish};
+use crate::{
+    handler_undo::{undo_or_redo, HistoryOp},
+    state::State,
+    subscription_patch::publish,
+    WriteAck,
+};
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
@@ -37,6 +42,22 @@ enum ClientRequest {
         id: i64,
         prefix: Option<JsonPointer>,
         patch: Vec<JsonPatch>,
+        #[serde(default)]
+        write_ack: Option<WriteAck>,
+    },
+    Undo {
+        id: i64,
+        #[serde(default)]
+        scope: Option<JsonPointer>,
+        #[serde(default)]
+        write_ack: Option<WriteAck>,
+    },
+    Redo {
+        id: i64,
+        #[serde(default)]
+        scope: Option<JsonPointer>,
+        #[serde(default)]
+        write_ack: Option<WriteAck>,
     },
 }
 
@@ -46,7 +67,7 @@ enum ServerResponse<'a> {
     Patch {
         id: i64,
         #[serde(skip_serializing_if = "Option::is_none")]
-        value: Option<&'a Value>,
+        value: Option<&'a Node>,
         #[serde(skip_serializing_if = "Option::is_none")]
         patch: Option<&'a [JsonPatch]>,
     },
@@ -56,7 +77,14 @@ enum ServerResponse<'a> {
     Response {
         id: i64,
         #[serde(skip_serializing_if = "Option::is_none")]
-        value: Option<&'a Value>,
+        value: Option<&'a Node>,
+    },
+    /// Answers an undo or redo with the patch it applied and the revision it
+    /// took.
+    History {
+        id: i64,
+        patch: &'a [JsonPatch],
+        revision: u64,
     },
     Error {
         id: i64,
@@ -173,7 +201,11 @@ async fn handle_client_request_subscribe(
 
     let (id, value, mut receiver, patch_tx, cancel_tx, mut cancel_rx) = {
         let mut locked_state = client_state.state.locked_state.write();
-        let value = locked_state.mdb.get(&path).cloned().unwrap_or(Value::Null);
+        let value = locked_state
+            .mdb
+            .get_node(&path)
+            .cloned()
+            .unwrap_or_default();
         let receiver = locked_state
             .subscriptions
             .entry(path)
@@ -235,7 +267,7 @@ async fn handle_client_request_unsubscribe(client_state: &mut ClientState, id: i
 async fn handle_client_request_get(client_state: &mut ClientState, id: i64, path: JsonPointer) {
     let value = {
         let locked_state = client_state.state.locked_state.read();
-        locked_state.mdb.get(path).cloned()
+        locked_state.mdb.get_node(path).cloned()
     };
     let _ = send_response(
         &mut client_state.sink,
@@ -252,7 +284,23 @@ async fn handle_client_request_patch(
     id: i64,
     prefix: Option<JsonPointer>,
     patch: Vec<JsonPatch>,
+    write_ack: Option<WriteAck>,
 ) {
+    let write_ack = write_ack.unwrap_or(client_state.state.write_ack);
+    let slot = match client_state.state.reserve_write().await {
+        Ok(slot) => slot,
+        Err(err) => {
+            let _ = send_response(
+                &mut client_state.sink,
+                ServerResponse::Error {
+                    id,
+                    message: &err.to_string(),
+                },
+            )
+            .await;
+            return;
+        }
+    };
     let res = {
         let mut locked_state = client_state.state.locked_state.write();
         match locked_state.mdb.patch(prefix.as_ref(), patch.clone()) {
@@ -263,14 +311,18 @@ async fn handle_client_request_patch(
                     prefix.as_ref(),
                     &patch,
                 );
-                if let Some(patch_sender) = &client_state.state.patch_sender {
-                    let _ = patch_sender.send((prefix, patch));
-                }
-                Ok(())
+                Ok(slot.persist(prefix, patch, write_ack))
             }
-            Err(err) => Err(err),
+            Err(err) => Err(err.to_string()),
         }
     };
+    let res = match res {
+        Ok(Some(ack)) => ack
+            .await
+            .map_err(|_| "failed to persist the change".to_string()),
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
 
     match res {
         Ok(()) => {
@@ -280,6 +332,37 @@ async fn handle_client_request_patch(
             )
             .await;
         }
+        Err(err) => {
+            let _ = send_response(
+                &mut client_state.sink,
+                ServerResponse::Error { id, message: &err },
+            )
+            .await;
+        }
+    }
+}
+
+async fn handle_client_request_history(
+    client_state: &mut ClientState,
+    id: i64,
+    scope: Option<JsonPointer>,
+    op: HistoryOp,
+    write_ack: Option<WriteAck>,
+) {
+    let write_ack = write_ack.unwrap_or(client_state.state.write_ack);
+    let scope = scope.unwrap_or_else(JsonPointer::root);
+    match undo_or_redo(&client_state.state, &scope, op, write_ack).await {
+        Ok((patch, revision)) => {
+            let _ = send_response(
+                &mut client_state.sink,
+                ServerResponse::History {
+                    id,
+                    patch: &patch,
+                    revision,
+                },
+            )
+            .await;
+        }
         Err(err) => {
             let _ = send_response(
                 &mut client_state.sink,
@@ -302,8 +385,25 @@ async fn handle_client_request(client_state: &mut ClientState, req: ClientReques
             handle_client_request_unsubscribe(client_state, id).await
         }
         ClientRequest::Get { id, path } => handle_client_request_get(client_state, id, path).await,
-        ClientRequest::Patch { id, prefix, patch } => {
-            handle_client_request_patch(client_state, id, prefix, patch).await
+        ClientRequest::Patch {
+            id,
+            prefix,
+            patch,
+            write_ack,
+        } => handle_client_request_patch(client_state, id, prefix, patch, write_ack).await,
+        ClientRequest::Undo {
+            id,
+            scope,
+            write_ack,
+        } => {
+            handle_client_request_history(client_state, id, scope, HistoryOp::Undo, write_ack).await
+        }
+        ClientRequest::Redo {
+            id,
+            scope,
+            write_ack,
+        } => {
+            handle_client_request_history(client_state, id, scope, HistoryOp::Redo, write_ack).await
         }
     }
 }
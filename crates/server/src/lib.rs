@@ -1,15 +1,25 @@
 mod config;
+mod handler_backup;
 mod handler_delete;
 mod handler_get;
+mod handler_health;
 mod handler_patch;
 mod handler_post;
 mod handler_put;
+mod handler_replication;
 mod handler_sse;
+mod handler_undo;
 mod handler_ws;
+mod replication;
 mod server;
 mod state;
 mod subscription_patch;
 mod utils;
+mod write_queue;
 
-pub use config::ServerConfig;
-pub use server::create_server;
+pub use config::{Backpressure, ServerConfig, WriteAck};
+pub use persistentdb::{
+    Compression, Durability, MemoryStorage, RecoveryTarget, SeedFormat, SnapshotFormat,
+    SnapshotRetention, Storage,
+};
+pub use server::{create_server, create_server_with_storage};
@@ -0,0 +1,479 @@
+use std::{
+    error::Error,
+    io::ErrorKind,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use memdb::Node;
+use parking_lot::{Mutex, RwLock};
+use persistentdb::{Change, RecordStamp, Storage};
+use serde::{Deserialize, Serialize};
+use tungstenite::{http::Uri, Message, WebSocket};
+
+use crate::{state::LockedState, subscription_patch::publish};
+
+/// Wait before a follower reconnects to the primary.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long a follower waits for the primary to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a follower waits for the primary to send anything before it
+/// reconnects, the primary pings idle followers more often.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sent from the primary to a follower over `/replication`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum ReplicationMessage {
+    /// Replaces the whole document, sent first if the follower is too far
+    /// behind.
+    Snapshot {
+        seq: u64,
+        timestamp: u64,
+        value: Node,
+    },
+    Change(Arc<Change>),
+}
+
+/// Applies the changes of the primary at `primary` to `storage` and the
+/// document, reconnecting from the last applied change whenever the
+/// connection is lost.
+pub(crate) fn follow_loop(
+    primary: String,
+    storage: Arc<Mutex<dyn Storage>>,
+    locked_state: Arc<RwLock<LockedState>>,
+) {
+    let compact_interval = storage.lock().compact_interval();
+    let mut prev_compact_at = Instant::now();
+
+    loop {
+        let seq = storage.lock().last_record().seq;
+        let url = replication_url(&primary, seq);
+        tracing::info!(url = url.as_str(), "connect to the primary");
+
+        let res = follow(
+            &url,
+            &storage,
+            &locked_state,
+            compact_interval,
+            &mut prev_compact_at,
+        );
+        match res {
+            Ok(()) => tracing::warn!("the primary closed the connection"),
+            Err(err) => tracing::error!(error = %err, "replication failed"),
+        }
+        std::thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn follow(
+    url: &str,
+    storage: &Mutex<dyn Storage>,
+    locked_state: &RwLock<LockedState>,
+    compact_interval: Duration,
+    prev_compact_at: &mut Instant,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut socket = connect(url)?;
+    loop {
+        let msg = match socket.read_message() {
+            Ok(Message::Text(text)) => serde_json::from_str(&text)?,
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                return Err("timed out waiting for the primary".into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        apply_message(storage, locked_state, msg)?;
+
+        if prev_compact_at.elapsed() > compact_interval {
+            let snapshot = locked_state.read().mdb.snapshot();
+            if let Err(err) = storage.lock().compact(snapshot) {
+                tracing::error!(error = %err, "failed to compact data");
+            }
+            *prev_compact_at = Instant::now();
+        }
+    }
+}
+
+/// Opens the WebSocket connection with timeouts, so that a primary which
+/// stops responding is noticed.
+fn connect(url: &str) -> Result<WebSocket<TcpStream>, Box<dyn Error + Send + Sync>> {
+    let uri = url.parse::<Uri>()?;
+    let host = uri.host().ok_or("replication URL without a host")?;
+    let addrs = (host, uri.port_u16().unwrap_or(80)).to_socket_addrs()?;
+
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                stream.set_write_timeout(Some(READ_TIMEOUT))?;
+                let (socket, _) = tungstenite::client(url, stream)?;
+                return Ok(socket);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(match last_err {
+        Some(err) => err.into(),
+        None => format!("{} does not resolve to any address", host).into(),
+    })
+}
+
+fn replication_url(primary: &str, seq: u64) -> String {
+    let primary = primary.trim_end_matches('/');
+    let primary = match primary.strip_prefix("http://") {
+        Some(rest) => format!("ws://{}", rest),
+        None => primary.to_string(),
+    };
+    format!("{}/replication?from={}", primary, seq)
+}
+
+/// Applies a message from the primary to the document and persists it, so
+/// that the document always contains what the storage does and a follower
+/// never applies a change twice after reconnecting.
+///
+/// A change is applied in a transaction which is rolled back if it cannot be
+/// appended to the storage. A snapshot replaces the document first, which is
+/// repeated harmlessly if the primary sends it again after a failed reset.
+pub(crate) fn apply_message(
+    storage: &Mutex<dyn Storage>,
+    locked_state: &RwLock<LockedState>,
+    msg: ReplicationMessage,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // the storage is always locked before the state
+    let mut storage = storage.lock();
+    match msg {
+        ReplicationMessage::Snapshot {
+            seq,
+            timestamp,
+            value,
+        } => {
+            tracing::info!(seq, "replace the document with a snapshot of the primary");
+            {
+                let mut locked_state = locked_state.write();
+                let locked_state = &mut *locked_state;
+                locked_state.mdb.replace_root(value.clone());
+                publish(
+                    &locked_state.mdb,
+                    &locked_state.subscriptions,
+                    None,
+                    &[JsonPatch::Add {
+                        path: JsonPointer::root(),
+                        value: value.to_value(),
+                    }],
+                );
+            }
+
+            storage.reset(value, RecordStamp { seq, timestamp })?;
+            storage.sync()?;
+        }
+        ReplicationMessage::Change(change) => {
+            if change.seq <= storage.last_record().seq {
+                return Ok(());
+            }
+
+            {
+                let mut locked_state = locked_state.write();
+                let locked_state = &mut *locked_state;
+                let mut txn = locked_state.mdb.transaction();
+                txn.patch(change.prefix.as_ref(), change.patch.clone())?;
+                storage.append_change(&change)?;
+                txn.commit();
+                publish(
+                    &locked_state.mdb,
+                    &locked_state.subscriptions,
+                    change.prefix.as_ref(),
+                    &change.patch,
+                );
+            }
+
+            // the change is in the storage even if this fails
+            storage.commit()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use json_pointer::json_pointer;
+    use memdb::MemDb;
+    use persistentdb::MemoryStorage;
+    use serde_json::{json, Value};
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::{create_server_with_storage, utils::WRITE_ACK_HEADER, ServerConfig, WriteAck};
+
+    /// Runs a server on its own thread until the returned sender is dropped.
+    fn start_server(config: ServerConfig, storage: MemoryStorage) -> oneshot::Sender<()> {
+        let (stop, stopped) = oneshot::channel::<()>();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let server = create_server_with_storage(config, storage).unwrap();
+            runtime.block_on(async {
+                tokio::select! {
+                    res = server => res.unwrap(),
+                    _ = stopped => {}
+                }
+            });
+        });
+        stop
+    }
+
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn request(addr: &str, method: &str, path: &str, body: &str) -> Option<Value> {
+        send_request(addr, method, path, "", body)
+    }
+
+    fn request_with_ack(
+        addr: &str,
+        method: &str,
+        path: &str,
+        body: &str,
+        write_ack: WriteAck,
+    ) -> Option<Value> {
+        let header = format!("{}: {}\r\n", WRITE_ACK_HEADER, write_ack);
+        send_request(addr, method, path, &header, body)
+    }
+
+    fn send_request(
+        addr: &str,
+        method: &str,
+        path: &str,
+        headers: &str,
+        body: &str,
+    ) -> Option<Value> {
+        let mut stream = TcpStream::connect(addr).ok()?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            body.len(),
+            headers,
+            body
+        )
+        .ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        let (head, body) = response.split_once("\r\n\r\n")?;
+        if !head.starts_with("HTTP/1.1 200") {
+            return None;
+        }
+        Some(serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    fn wait_for(addr: &str, path: &str, expected: Option<Value>) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let value = request(addr, "GET", path, "");
+            match &expected {
+                Some(expected) if value.as_ref() == Some(expected) => return,
+                None if value.is_some() => return,
+                _ => {}
+            }
+            assert!(
+                Instant::now() < deadline,
+                "{} is {:?} instead of {:?}",
+                path,
+                value,
+                expected
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_follow_primary() {
+        let primary_addr = free_addr();
+        let follower_addr = free_addr();
+        let primary_storage = MemoryStorage::new();
+        // the memory storage syncs only for durable writes, and followers
+        // get synced changes only
+        let primary_config = || {
+            ServerConfig {
+                bind: primary_addr.clone(),
+                ..Default::default()
+            }
+            .write_ack(WriteAck::Durable)
+        };
+        let primary = start_server(primary_config(), primary_storage.clone());
+        let _follower = start_server(
+            ServerConfig {
+                bind: follower_addr.clone(),
+                ..Default::default()
+            }
+            .follow(format!("http://{}", primary_addr)),
+            MemoryStorage::new(),
+        );
+
+        wait_for(&primary_addr, "/health", None);
+        request(&primary_addr, "POST", "/data/a", "1").unwrap();
+        request(&primary_addr, "POST", "/data/b", "2").unwrap();
+        wait_for(&follower_addr, "/data/b", Some(json!(2)));
+
+        // the primary restarts with a compaction which folds a change the
+        // follower has not seen into the snapshot
+        drop(primary);
+        let mut storage = primary_storage.clone();
+        storage
+            .append(
+                None,
+                &[JsonPatch::Add {
+                    path: json_pointer!("/c"),
+                    value: json!(3),
+                }],
+            )
+            .unwrap();
+        let _primary = start_server(primary_config(), primary_storage);
+        wait_for(&primary_addr, "/health", None);
+
+        wait_for(&follower_addr, "/data/c", Some(json!(3)));
+        request(&primary_addr, "POST", "/data/d", "4").unwrap();
+        wait_for(&follower_addr, "/data/d", Some(json!(4)));
+        wait_for(&follower_addr, "/data/a", Some(json!(1)));
+    }
+
+    #[test]
+    fn test_follow_primary_after_crash() {
+        let primary_addr = free_addr();
+        let follower_addr = free_addr();
+        let primary_config = || ServerConfig {
+            bind: primary_addr.clone(),
+            ..Default::default()
+        };
+        let primary_storage = MemoryStorage::new();
+        let primary = start_server(primary_config(), primary_storage.clone());
+        let _follower = start_server(
+            ServerConfig {
+                bind: follower_addr.clone(),
+                ..Default::default()
+            }
+            .follow(format!("http://{}", primary_addr)),
+            MemoryStorage::new(),
+        );
+
+        wait_for(&primary_addr, "/health", None);
+        request_with_ack(&primary_addr, "POST", "/data/a", "1", WriteAck::Durable).unwrap();
+        wait_for(&follower_addr, "/data/a", Some(json!(1)));
+
+        // an unsynced change is not streamed
+        request(&primary_addr, "POST", "/data/b", "2").unwrap();
+        assert_eq!(request(&primary_addr, "GET", "/data/b", ""), Some(json!(2)));
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            request(&follower_addr, "GET", "/data/b", ""),
+            Some(Value::Null)
+        );
+
+        // the primary loses it in a crash, and the follower is not ahead of it
+        let crashed_storage = primary_storage.crashed();
+        drop(primary);
+        let _primary = start_server(primary_config(), crashed_storage);
+        wait_for(&primary_addr, "/health", None);
+
+        request_with_ack(&primary_addr, "POST", "/data/c", "3", WriteAck::Durable).unwrap();
+        wait_for(&follower_addr, "/data/c", Some(json!(3)));
+        assert_eq!(
+            request(&follower_addr, "GET", "/data/b", ""),
+            Some(Value::Null)
+        );
+        assert_eq!(
+            request(&follower_addr, "GET", "/data/a", ""),
+            Some(json!(1))
+        );
+    }
+
+    #[test]
+    fn test_apply_message() {
+        let storage = MemoryStorage::new();
+        let locked_state = RwLock::new(LockedState {
+            mdb: MemDb::default(),
+            subscriptions: Default::default(),
+        });
+        let shared_storage: Arc<Mutex<dyn Storage>> = Arc::new(Mutex::new(storage.clone()));
+        let change = |seq, n| {
+            ReplicationMessage::Change(Arc::new(Change {
+                seq,
+                timestamp: 0,
+                prefix: None,
+                patch: vec![JsonPatch::Add {
+                    path: json_pointer!("/b"),
+                    value: json!(n),
+                }],
+            }))
+        };
+
+        for msg in [
+            ReplicationMessage::Snapshot {
+                seq: 5,
+                timestamp: 0,
+                value: Node::from(json!({ "a": 1 })),
+            },
+            change(6, 1),
+            // already applied before a reconnect
+            change(6, 2),
+            change(7, 3),
+        ] {
+            apply_message(&shared_storage, &locked_state, msg).unwrap();
+        }
+
+        // a change which cannot be applied is not persisted either
+        let failing = ReplicationMessage::Change(Arc::new(Change {
+            seq: 8,
+            timestamp: 0,
+            prefix: None,
+            patch: vec![
+                JsonPatch::Add {
+                    path: json_pointer!("/c"),
+                    value: json!(1),
+                },
+                JsonPatch::Remove {
+                    path: json_pointer!("/missing"),
+                },
+            ],
+        }));
+        apply_message(&shared_storage, &locked_state, failing).unwrap_err();
+
+        assert_eq!(locked_state.read().mdb.root(), json!({ "a": 1, "b": 3 }));
+        assert_eq!(storage.last_record().seq, 7);
+        assert_eq!(
+            storage.create_memdb().unwrap().root(),
+            json!({ "a": 1, "b": 3 })
+        );
+    }
+
+    #[test]
+    fn test_replication_url() {
+        assert_eq!(
+            replication_url("http://127.0.0.1:3000/", 5),
+            "ws://127.0.0.1:3000/replication?from=5"
+        );
+        assert_eq!(
+            replication_url("ws://primary:3000", 0),
+            "ws://primary:3000/replication?from=0"
+        );
+    }
+}
@@ -2,48 +2,149 @@ use std::{
     future::Future,
     io::Result as IoResult,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crossbeam::channel::Receiver;
-use json_patch::JsonPatch;
-use json_pointer::JsonPointer;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
 use memdb::MemDb;
-use parking_lot::RwLock;
-use persistentdb::{PersistentDb, PersistentDbError};
+use parking_lot::{Mutex, RwLock};
+use persistentdb::{Change, MemoryStorage, PersistentDb, PersistentDbError, SeedFormat, Storage};
 use poem::{
-    endpoint::make_sync,
     get,
     listener::TcpListener,
     middleware::{NormalizePath, TrailingSlash},
-    EndpointExt, Route, Server,
+    post, EndpointExt, Route, Server,
 };
+use tokio::sync::broadcast::{self, Sender as BroadcastSender};
 
 use crate::{
+    handler_backup::handler_backup,
     handler_delete::handler_delete,
     handler_get::handler_get,
+    handler_health::handler_health,
     handler_patch::handler_patch,
     handler_post::handler_post,
     handler_put::handler_put,
+    handler_replication::handler_replication,
     handler_sse::handler_sse,
+    handler_undo::{handler_redo, handler_undo},
     handler_ws::handler_ws,
+    replication::follow_loop,
     state::{LockedState, State},
+    write_queue::{PendingWrite, WriteQueue},
     ServerConfig,
 };
 
 pub fn create_server(
     config: ServerConfig,
 ) -> Result<impl Future<Output = IoResult<()>>, PersistentDbError> {
-    let (mdb, tx) = if let Some(data_dir) = &config.data_dir {
-        let pdb = PersistentDb::open(data_dir)?;
-        let memdb = pdb.create_memdb()?;
-        let (tx, rx) = crossbeam::channel::unbounded();
-        pdb.compact();
-        std::thread::spawn(move || sync_loop(rx, pdb));
-        (memdb, Some(tx))
-    } else {
-        (MemDb::default(), None)
+    let storage = match &config.data_dir {
+        Some(data_dir) => {
+            let options = config.persistent_db_options()?;
+            if let Some(backup) = &config.restore_from {
+                PersistentDb::restore_with_options(backup, data_dir, &options)?;
+            }
+            let mut pdb = PersistentDb::open_with_options(data_dir, options)?;
+            let memdb = match (
+                &config.seed_from,
+                config.recover_to,
+                config.start_from_generation,
+            ) {
+                (Some(seed), _, _) => {
+                    let format = config
+                        .seed_format
+                        .unwrap_or_else(|| SeedFormat::from_path(seed));
+                    pdb.seed(seed, format, config.force_seed)?
+                }
+                (None, Some(target), _) => pdb.recover_to(target)?,
+                (None, None, Some(generation)) => pdb.start_from_generation(generation)?,
+                (None, None, None) => pdb.create_memdb()?,
+            };
+            Some((Arc::new(Mutex::new(pdb)) as Arc<Mutex<dyn Storage>>, memdb))
+        }
+        // a follower always needs a storage to track the position it has
+        // replicated up to
+        None if config.follow.is_some() => {
+            let storage = MemoryStorage::new();
+            let memdb = storage.create_memdb()?;
+            Some((
+                Arc::new(Mutex::new(storage)) as Arc<Mutex<dyn Storage>>,
+                memdb,
+            ))
+        }
+        None => None,
     };
+    serve(config, storage)
+}
+
+/// Creates a server which persists the document to `storage` instead of the
+/// data directory.
+///
+/// The options of the data directory in `config` are ignored.
+pub fn create_server_with_storage(
+    config: ServerConfig,
+    storage: impl Storage,
+) -> Result<impl Future<Output = IoResult<()>>, PersistentDbError> {
+    let memdb = storage.create_memdb()?;
+    serve(
+        config,
+        Some((
+            Arc::new(Mutex::new(storage)) as Arc<Mutex<dyn Storage>>,
+            memdb,
+        )),
+    )
+}
+
+fn serve(
+    config: ServerConfig,
+    storage: Option<(Arc<Mutex<dyn Storage>>, MemDb)>,
+) -> Result<impl Future<Output = IoResult<()>>, PersistentDbError> {
+    let (locked_state, write_queue, storage, changes) = match storage {
+        Some((storage, memdb)) => {
+            storage.lock().compact(memdb.snapshot())?;
+            let locked_state = new_locked_state(memdb);
+            match config.follow.clone() {
+                Some(primary) => {
+                    std::thread::spawn({
+                        let locked_state = locked_state.clone();
+                        let storage = storage.clone();
+                        move || follow_loop(primary, storage, locked_state)
+                    });
+                    (locked_state, None, Some(storage), None)
+                }
+                None => {
+                    let (write_queue, rx) =
+                        WriteQueue::new(config.write_queue_size, config.backpressure);
+                    let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+                    std::thread::spawn({
+                        let locked_state = locked_state.clone();
+                        let write_queue = write_queue.clone();
+                        let storage = storage.clone();
+                        let changes = changes.clone();
+                        move || {
+                            let publisher = ChangePublisher::new(changes);
+                            sync_loop(rx, storage, locked_state, write_queue, publisher)
+                        }
+                    });
+                    (
+                        locked_state,
+                        Some(write_queue),
+                        Some(storage),
+                        Some(changes),
+                    )
+                }
+            }
+        }
+        None => (new_locked_state(MemDb::default()), None, None, None),
+    };
+
+    // a follower applies the changes of the primary, including its undos
+    if config.follow.is_none() {
+        let mut locked_state = locked_state.write();
+        for scope in config.history_scopes() {
+            locked_state.mdb.enable_history(scope, config.history_size);
+        }
+    }
 
     let routes = Route::new()
         .nest(
@@ -58,15 +159,25 @@ pub fn create_server(
             ),
         )
         .nest("/sse", Route::new().at("/*path", handler_sse))
+        .nest("/undo", Route::new().at("/*path", post(handler_undo)))
+        .nest("/redo", Route::new().at("/*path", post(handler_redo)))
         .at("/ws", get(handler_ws))
-        .at("/health", get(make_sync(|_| "OK")))
+        .at("/health", get(handler_health))
+        .at("/admin/backup", post(handler_backup))
+        .at("/replication", get(handler_replication))
         .with(NormalizePath::new(TrailingSlash::Trim))
         .data(State {
-            locked_state: Arc::new(RwLock::new(LockedState {
-                mdb,
-                subscriptions: Default::default(),
-            })),
-            patch_sender: tx,
+            locked_state,
+            write_queue,
+            write_ack: config.write_ack,
+            storage,
+            backup_dir: config.backup_dir,
+            changes,
+            follower: config.follow.is_some(),
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
         });
 
     tracing::info!(bind = config.bind.as_str(), "listening");
@@ -74,24 +185,259 @@ pub fn create_server(
     Ok(server.run(routes))
 }
 
-fn sync_loop(rx: Receiver<(Option<JsonPointer>, Vec<JsonPatch>)>, mut pdb: PersistentDb) {
+fn new_locked_state(mdb: MemDb) -> Arc<RwLock<LockedState>> {
+    Arc::new(RwLock::new(LockedState {
+        mdb,
+        subscriptions: Default::default(),
+    }))
+}
+
+/// Maximum number of records written before they are committed together.
+const MAX_BATCH_SIZE: usize = 1024;
+
+/// Number of changes buffered for each follower before it is disconnected.
+const CHANGES_CAPACITY: usize = 1024;
+
+fn sync_loop(
+    rx: Receiver<PendingWrite>,
+    storage: Arc<Mutex<dyn Storage>>,
+    locked_state: Arc<RwLock<LockedState>>,
+    write_queue: Arc<WriteQueue>,
+    mut publisher: ChangePublisher,
+) {
     let mut prev_compact_at = Instant::now();
-    let compact_interval = Duration::from_secs(60 * 30);
-
-    for (prefix, patch) in rx {
-        loop {
-            match pdb.append(prefix.as_ref(), &patch, false) {
-                Ok(()) => break,
-                Err(err) => {
-                    tracing::error!(error = %err, "failed to write data");
-                    std::thread::sleep(Duration::from_secs(5));
+    let compact_interval = storage.lock().compact_interval();
+
+    loop {
+        let pending_sync_deadline = storage.lock().pending_sync_deadline();
+        let first = match pending_sync_deadline {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(item) => item,
+                Err(RecvTimeoutError::Timeout) => {
+                    retry(&write_queue, "failed to sync data", || {
+                        storage.lock().commit()
+                    });
+                    publisher.publish_synced(&*storage.lock());
+                    continue;
                 }
-            }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(item) => item,
+                Err(_) => break,
+            },
+        };
+
+        let mut storage = storage.lock();
+
+        // group commit: everything already queued is synced together
+        write_batch(
+            &mut *storage,
+            &write_queue,
+            &mut publisher,
+            std::iter::once(first).chain(rx.try_iter().take(MAX_BATCH_SIZE - 1)),
+        );
+        if rx.is_empty() {
+            write_queue.drained();
         }
 
         if Instant::now() - prev_compact_at > compact_interval {
-            pdb.compact();
+            // writes are queued while the state is locked, so the snapshot
+            // contains exactly the writes persisted so far plus those queued
+            let (snapshot, queued) = {
+                let locked_state = locked_state.read();
+                (locked_state.mdb.snapshot(), rx.len())
+            };
+            write_batch(
+                &mut *storage,
+                &write_queue,
+                &mut publisher,
+                rx.try_iter().take(queued),
+            );
+            if let Err(err) = storage.compact(snapshot) {
+                tracing::error!(error = %err, "failed to compact data");
+            }
+            publisher.publish_synced(&*storage);
             prev_compact_at = Instant::now();
         }
     }
+
+    retry(&write_queue, "failed to sync data", || {
+        storage.lock().sync()
+    });
+    publisher.publish_synced(&*storage.lock());
+}
+
+/// Publishes persisted changes to the followers once they have been synced,
+/// so that a follower never gets ahead of what the primary recovers after a
+/// crash.
+struct ChangePublisher {
+    changes: BroadcastSender<Arc<Change>>,
+    /// Persisted changes which have not been synced yet, oldest first.
+    unsynced: Vec<Arc<Change>>,
+}
+
+impl ChangePublisher {
+    fn new(changes: BroadcastSender<Arc<Change>>) -> Self {
+        Self {
+            changes,
+            unsynced: Vec::new(),
+        }
+    }
+
+    /// Publishes the changes up to the last one synced to `storage`.
+    ///
+    /// Followers which connect later read them from the storage instead,
+    /// see [`handler_replication`].
+    fn publish_synced(&mut self, storage: &dyn Storage) {
+        let synced_seq = storage.synced_record().seq;
+        let count = self
+            .unsynced
+            .partition_point(|change| change.seq <= synced_seq);
+        for change in self.unsynced.drain(..count) {
+            if self.changes.receiver_count() > 0 {
+                let _ = self.changes.send(change);
+            }
+        }
+    }
+}
+
+/// Appends and commits the writes, then acknowledges them and publishes them
+/// to the followers once they are synced.
+///
+/// A batch with a [`WriteAck::Durable`](crate::WriteAck::Durable) write is synced whatever the
+/// durability level, since a periodic sync would otherwise acknowledge it
+/// before it is durable.
+fn write_batch(
+    storage: &mut dyn Storage,
+    write_queue: &WriteQueue,
+    publisher: &mut ChangePublisher,
+    writes: impl Iterator<Item = PendingWrite>,
+) {
+    let mut written = Vec::new();
+    let mut durable = false;
+    for write in writes {
+        durable |= write.ack.is_some();
+        let stamp = retry(write_queue, "failed to write data", || {
+            storage.append(write.prefix.as_ref(), &write.patch)
+        });
+        written.push((stamp, write));
+    }
+    retry(write_queue, "failed to sync data", || {
+        if durable {
+            storage.sync()
+        } else {
+            storage.commit()
+        }
+    });
+
+    // dropping the writes also releases their slots in the queue
+    for (stamp, write) in written {
+        if let Some(ack) = write.ack {
+            let _ = ack.send(());
+        }
+        publisher.unsynced.push(Arc::new(Change {
+            seq: stamp.seq,
+            timestamp: stamp.timestamp,
+            prefix: write.prefix,
+            patch: write.patch,
+        }));
+    }
+    publisher.publish_synced(storage);
+}
+
+fn retry<T>(
+    write_queue: &WriteQueue,
+    message: &str,
+    mut f: impl FnMut() -> Result<T, PersistentDbError>,
+) -> T {
+    loop {
+        match f() {
+            Ok(value) => {
+                write_queue.set_failing(false);
+                return value;
+            }
+            Err(err) => {
+                tracing::error!(error = %err, message);
+                write_queue.set_failing(true);
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use json_patch::JsonPatch;
+    use json_pointer::json_pointer;
+    use persistentdb::MemoryStorage;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{write_queue::WriteSlot, Backpressure, WriteAck};
+
+    #[test]
+    fn test_write_batch() {
+        let storage = MemoryStorage::new();
+        let (write_queue, rx) = WriteQueue::new(2, Backpressure::Block);
+        let ack = WriteSlot::reserve(Some(&write_queue))
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+            .persist(
+                None,
+                vec![JsonPatch::Add {
+                    path: json_pointer!("/a"),
+                    value: json!(1),
+                }],
+                WriteAck::Durable,
+            )
+            .unwrap();
+
+        let (changes, mut followers) = broadcast::channel(1);
+        let mut publisher = ChangePublisher::new(changes);
+        write_batch(
+            &mut storage.clone(),
+            &write_queue,
+            &mut publisher,
+            rx.try_iter(),
+        );
+        assert!(ack.now_or_never().unwrap().is_ok());
+        assert_eq!(storage.pending_records(), 1);
+        assert_eq!(storage.create_memdb().unwrap().root(), json!({ "a": 1 }));
+        assert_eq!(followers.try_recv().unwrap().seq, 1);
+
+        // a durable ack forces a sync, a fast one leaves it to the durability
+        // level
+        assert_eq!(storage.synced_seq(), 1);
+        let ack = WriteSlot::reserve(Some(&write_queue))
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+            .persist(
+                None,
+                vec![JsonPatch::Add {
+                    path: json_pointer!("/b"),
+                    value: json!(2),
+                }],
+                WriteAck::Fast,
+            );
+        assert!(ack.is_none());
+        write_batch(
+            &mut storage.clone(),
+            &write_queue,
+            &mut publisher,
+            rx.try_iter(),
+        );
+        assert_eq!(storage.pending_records(), 2);
+        assert_eq!(storage.synced_seq(), 1);
+
+        // followers get a change only once it is synced
+        assert!(followers.try_recv().is_err());
+        let mut storage = storage;
+        storage.sync().unwrap();
+        publisher.publish_synced(&storage);
+        assert_eq!(followers.try_recv().unwrap().seq, 2);
+    }
 }
@@ -1,12 +1,18 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
-use crossbeam::channel::Sender;
 use json_patch::JsonPatch;
 use json_pointer::JsonPointer;
 use memdb::MemDb;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use persistentdb::{Change, Storage};
+use poem::{http::StatusCode, Error, Result};
 use tokio::sync::broadcast::Sender as BroadcastSender;
 
+use crate::{
+    write_queue::{WriteQueue, WriteSlot},
+    WriteAck,
+};
+
 pub(crate) type SubscriptionHashMap = HashMap<JsonPointer, BroadcastSender<Arc<[JsonPatch]>>>;
 
 pub(crate) struct LockedState {
@@ -17,5 +23,50 @@ pub(crate) struct LockedState {
 #[derive(Clone)]
 pub(crate) struct State {
     pub(crate) locked_state: Arc<RwLock<LockedState>>,
-    pub(crate) patch_sender: Option<Sender<(Option<JsonPointer>, Vec<JsonPatch>)>>,
+    pub(crate) write_queue: Option<Arc<WriteQueue>>,
+    pub(crate) write_ack: WriteAck,
+    /// Shared with the sync loop, `None` without persistence.
+    pub(crate) storage: Option<Arc<Mutex<dyn Storage>>>,
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// Every persisted change for the followers, `None` on a follower or
+    /// without persistence.
+    pub(crate) changes: Option<BroadcastSender<Arc<Change>>>,
+    /// Whether the document is replicated from a primary.
+    pub(crate) follower: bool,
+    /// Distinguishes the entity tags of this process from those of earlier
+    /// ones, whose revisions started over.
+    pub(crate) epoch: u64,
+}
+
+impl State {
+    /// Returns the entity tag of a subtree at `revision`.
+    pub(crate) fn etag(&self, revision: u64) -> String {
+        format!("\"{:x}-{}\"", self.epoch, revision)
+    }
+
+    /// Returns the revision of an entity tag made by [`State::etag`], or
+    /// `None` if it is malformed or from another process.
+    pub(crate) fn parse_etag(&self, etag: &str) -> Option<u64> {
+        let (epoch, revision) = etag
+            .trim()
+            .strip_prefix('"')?
+            .strip_suffix('"')?
+            .split_once('-')?;
+        if u64::from_str_radix(epoch, 16).ok()? != self.epoch {
+            return None;
+        }
+        revision.parse().ok()
+    }
+
+    /// Waits for, or fails to get, room for one more write according to the
+    /// configured [`Backpressure`](crate::Backpressure).
+    pub(crate) async fn reserve_write(&self) -> Result<WriteSlot> {
+        if self.follower {
+            return Err(Error::from_string(
+                "writes are not accepted by a follower",
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        WriteSlot::reserve(self.write_queue.as_ref()).await
+    }
 }
@@ -1,3 +1,16 @@
+use json_pointer::JsonPointer;
+use memdb::{MemDb, MemDbError};
+use poem::{
+    http::{header, StatusCode},
+    Error, FromRequest, Request, RequestBody, Result,
+};
+use tokio::sync::oneshot;
+
+use crate::{state::State, WriteAck};
+
+/// Request header overriding the configured [`WriteAck`].
+pub(crate) const WRITE_ACK_HEADER: &str = "x-bigjson-write-ack";
+
 pub(crate) fn normalize_path(path: &str) -> String {
     if !path.is_empty() {
         format!("/{}", path)
@@ -5,3 +18,91 @@ pub(crate) fn normalize_path(path: &str) -> String {
         path.to_string()
     }
 }
+
+/// Extracts the [`WriteAck`] requested by the [`WRITE_ACK_HEADER`], falling
+/// back to the configured default.
+#[poem::async_trait]
+impl<'a> FromRequest<'a> for WriteAck {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        match req.headers().get(WRITE_ACK_HEADER) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    Error::from_string(
+                        format!("invalid `{}` header", WRITE_ACK_HEADER),
+                        StatusCode::BAD_REQUEST,
+                    )
+                }),
+            None => Ok(req
+                .data::<State>()
+                .map(|state| state.write_ack)
+                .unwrap_or_default()),
+        }
+    }
+}
+
+/// The precondition of an `If-Match` header on the subtree being written,
+/// see [`State::etag`].
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum IfMatch {
+    /// No header, or `*`.
+    Any,
+    /// The subtree must be unchanged since this revision.
+    Revision(u64),
+    /// Only tags which are malformed or from another process, which never
+    /// match.
+    Stale,
+}
+
+impl IfMatch {
+    /// Fails unless the value at `path` is unchanged since the revision of
+    /// the tag.
+    pub(crate) fn check(self, mdb: &MemDb, path: &JsonPointer) -> Result<(), MemDbError> {
+        match self {
+            IfMatch::Any => Ok(()),
+            IfMatch::Revision(revision) => mdb.check_unchanged(path, revision),
+            IfMatch::Stale => Err(MemDbError::Changed {
+                path: path.clone(),
+                revision: mdb.revision_of(path),
+            }),
+        }
+    }
+}
+
+#[poem::async_trait]
+impl<'a> FromRequest<'a> for IfMatch {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        let value = match req.headers().get(header::IF_MATCH) {
+            Some(value) => value.to_str().map_err(|_| {
+                Error::from_string("invalid `If-Match` header", StatusCode::BAD_REQUEST)
+            })?,
+            None => return Ok(IfMatch::Any),
+        };
+        if value.trim() == "*" {
+            return Ok(IfMatch::Any);
+        }
+
+        // any of the listed tags may match, the one with the latest revision
+        // is the weakest precondition
+        let state = req.data::<State>();
+        Ok(value
+            .split(',')
+            .filter_map(|etag| state.and_then(|state| state.parse_etag(etag)))
+            .max()
+            .map_or(IfMatch::Stale, IfMatch::Revision))
+    }
+}
+
+pub(crate) async fn wait_durable(ack: Option<oneshot::Receiver<()>>) -> Result<()> {
+    if let Some(ack) = ack {
+        ack.await.map_err(|_| {
+            Error::from_string(
+                "failed to persist the change",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+    }
+    Ok(())
+}
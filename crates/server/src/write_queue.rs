@@ -0,0 +1,231 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+use json_patch::JsonPatch;
+use json_pointer::JsonPointer;
+use poem::{http::StatusCode, Error, Result};
+use serde::Serialize;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::{Backpressure, WriteAck};
+
+/// A change waiting to be appended to the persistent database.
+pub(crate) struct PendingWrite {
+    pub(crate) prefix: Option<JsonPointer>,
+    pub(crate) patch: Vec<JsonPatch>,
+    /// Notified once the change has been appended and committed.
+    pub(crate) ack: Option<oneshot::Sender<()>>,
+    /// The queue slot, released when the write is dropped by the sync loop.
+    pub(crate) _permit: OwnedSemaphorePermit,
+}
+
+/// Bounded queue between the request handlers and the sync loop.
+pub(crate) struct WriteQueue {
+    sender: Sender<PendingWrite>,
+    permits: Arc<Semaphore>,
+    capacity: usize,
+    backpressure: Backpressure,
+    /// Set when the queue overflowed with [`Backpressure::ReadOnly`], cleared
+    /// once the sync loop has drained it.
+    read_only: AtomicBool,
+    /// Set while the sync loop fails to write to the disk.
+    failing: AtomicBool,
+}
+
+/// A reserved place in the [`WriteQueue`], empty without persistence.
+pub(crate) struct WriteSlot(Option<(Arc<WriteQueue>, OwnedSemaphorePermit)>);
+
+impl WriteSlot {
+    pub(crate) async fn reserve(queue: Option<&Arc<WriteQueue>>) -> Result<Self> {
+        match queue {
+            Some(queue) => Ok(Self(Some((queue.clone(), queue.reserve().await?)))),
+            None => Ok(Self(None)),
+        }
+    }
+
+    /// Queues an applied change for persistence.
+    ///
+    /// With [`WriteAck::Durable`] the returned receiver completes once the
+    /// change is durable, without persistence there is nothing to wait for.
+    pub(crate) fn persist(
+        self,
+        prefix: Option<JsonPointer>,
+        patch: Vec<JsonPatch>,
+        write_ack: WriteAck,
+    ) -> Option<oneshot::Receiver<()>> {
+        let (queue, permit) = self.0?;
+        let (ack, ack_rx) = match write_ack {
+            WriteAck::Fast => (None, None),
+            WriteAck::Durable => {
+                let (tx, rx) = oneshot::channel();
+                (Some(tx), Some(rx))
+            }
+        };
+        // if the sync loop is gone the dropped ack fails the receiver
+        queue.send(PendingWrite {
+            prefix,
+            patch,
+            ack,
+            _permit: permit,
+        });
+        ack_rx
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HealthStatus {
+    Ok,
+    Lagging,
+    ReadOnly,
+    Failing,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Health {
+    pub(crate) status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) write_queue_len: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) write_queue_capacity: Option<usize>,
+}
+
+impl WriteQueue {
+    pub(crate) fn new(
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> (Arc<Self>, Receiver<PendingWrite>) {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = crossbeam::channel::bounded(capacity);
+        let queue = Arc::new(Self {
+            sender,
+            permits: Arc::new(Semaphore::new(capacity)),
+            capacity,
+            backpressure,
+            read_only: AtomicBool::new(false),
+            failing: AtomicBool::new(false),
+        });
+        (queue, receiver)
+    }
+
+    /// Reserves a slot before a change is applied, so a change is never
+    /// applied in memory without being queued for persistence.
+    async fn reserve(&self) -> Result<OwnedSemaphorePermit> {
+        match self.backpressure {
+            Backpressure::Block => Ok(self
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed")),
+            Backpressure::Reject => self.permits.clone().try_acquire_owned().map_err(|_| {
+                Error::from_string("write queue is full", StatusCode::SERVICE_UNAVAILABLE)
+            }),
+            Backpressure::ReadOnly => {
+                if self.read_only.load(Ordering::Acquire) || self.failing.load(Ordering::Acquire) {
+                    return Err(Error::from_string(
+                        "server is read-only until persistence catches up",
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    ));
+                }
+                self.permits.clone().try_acquire_owned().map_err(|_| {
+                    tracing::warn!("write queue is full, switch to read-only mode");
+                    self.read_only.store(true, Ordering::Release);
+                    Error::from_string(
+                        "server is read-only until persistence catches up",
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    )
+                })
+            }
+        }
+    }
+
+    fn send(&self, write: PendingWrite) {
+        // never blocks because every queued write holds one of `capacity` permits
+        let _ = self.sender.send(write);
+    }
+
+    pub(crate) fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Release);
+    }
+
+    /// Called by the sync loop whenever it has emptied the queue.
+    pub(crate) fn drained(&self) {
+        if self.read_only.swap(false, Ordering::AcqRel) {
+            tracing::info!("write queue drained, leave read-only mode");
+        }
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        // includes the writes the sync loop is currently working on
+        let len = self.capacity - self.permits.available_permits();
+        let status = if self.failing.load(Ordering::Acquire) {
+            HealthStatus::Failing
+        } else if self.read_only.load(Ordering::Acquire) {
+            HealthStatus::ReadOnly
+        } else if len * 2 >= self.capacity {
+            HealthStatus::Lagging
+        } else {
+            HealthStatus::Ok
+        };
+
+        Health {
+            status,
+            write_queue_len: Some(len),
+            write_queue_capacity: Some(self.capacity),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn reserve(queue: &Arc<WriteQueue>) -> Result<WriteSlot, StatusCode> {
+        WriteSlot::reserve(Some(queue))
+            .now_or_never()
+            .expect("does not wait")
+            .map_err(|err| err.into_response().status())
+    }
+
+    #[test]
+    fn test_reject() {
+        let (queue, rx) = WriteQueue::new(1, Backpressure::Reject);
+        reserve(&queue)
+            .unwrap()
+            .persist(None, vec![], WriteAck::Fast);
+        assert_eq!(
+            reserve(&queue).err().unwrap(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(matches!(queue.health().status, HealthStatus::Lagging));
+
+        drop(rx.recv().unwrap());
+        assert!(reserve(&queue).is_ok());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let (queue, rx) = WriteQueue::new(1, Backpressure::ReadOnly);
+        reserve(&queue)
+            .unwrap()
+            .persist(None, vec![], WriteAck::Fast);
+        assert!(reserve(&queue).is_err());
+        assert!(matches!(queue.health().status, HealthStatus::ReadOnly));
+
+        // stays read-only until the sync loop reports the queue as drained
+        drop(rx.recv().unwrap());
+        assert!(reserve(&queue).is_err());
+        queue.drained();
+        assert!(reserve(&queue).is_ok());
+
+        queue.set_failing(true);
+        assert!(reserve(&queue).is_err());
+        assert!(matches!(queue.health().status, HealthStatus::Failing));
+    }
+}
@@ -1,12 +1,40 @@
+mod tool;
+
+use clap::Parser;
 use server::{create_server, ServerConfig};
 
+#[derive(Debug, Parser)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<tool::Command>,
+    #[clap(flatten)]
+    server: ServerConfig,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "bigjson=debug");
     }
-    tracing_subscriber::fmt::init();
 
-    let config = ServerConfig::parse();
-    Ok(create_server(config)?.await?)
+    let cli = Cli::parse();
+    match cli.command {
+        Some(command) => {
+            // the standard output is reserved for the data
+            tracing_subscriber::fmt()
+                .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+                .with_writer(std::io::stderr)
+                .init();
+            if let Err(err) = tool::run(command) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        None => {
+            tracing_subscriber::fmt::init();
+            Ok(create_server(cli.server)?.await?)
+        }
+    }
 }
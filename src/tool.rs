@@ -0,0 +1,146 @@
+use std::{
+    error::Error,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use clap::{Args, Subcommand};
+use persistentdb::{
+    Compression, EncryptionKey, PersistentDb, PersistentDbError, PersistentDbOptions,
+    RecoveryTarget, SnapshotFormat, SnapshotRetention,
+};
+
+// offline tools for a data directory, next to the server
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Print the snapshot and block files of a data directory as JSON
+    Inspect(DataDir),
+    /// Check that the snapshot loads and every record parses and applies
+    Verify(DataDir),
+    /// Print the records after the snapshot as NDJSON with their positions
+    Dump(DataDir),
+    /// Print the document as it was after the last record included by
+    /// `--at`, or after the last record
+    Materialize {
+        #[clap(flatten)]
+        data_dir: DataDir,
+        /// `seq:<sequence number>` or `timestamp:<unix milliseconds>`
+        #[clap(long)]
+        at: Option<RecoveryTarget>,
+    },
+    /// Fold all records into a new snapshot, the data directory must not be
+    /// in use
+    Compact {
+        #[clap(flatten)]
+        data_dir: DataDir,
+        /// How the snapshot is compressed: `none`, `zstd` or `zstd:<level>`
+        #[clap(long, default_value = "none")]
+        compression: Compression,
+        /// Encoding of the snapshot: `json` or `binary`
+        #[clap(long, default_value = "json")]
+        snapshot_format: SnapshotFormat,
+        /// Which snapshots are kept as restore points: `count:<snapshots>`,
+        /// including the new one, or `hours:<hours>`
+        #[clap(long, default_value = "count:1")]
+        snapshot_retention: SnapshotRetention,
+    },
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct DataDir {
+    #[clap(long)]
+    data_dir: PathBuf,
+    /// Key of an encrypted data directory, either 32 raw bytes or 64
+    /// hexadecimal digits
+    #[clap(long)]
+    encryption_key_file: Option<PathBuf>,
+    /// Also read unencrypted snapshots and block files of a data directory
+    /// whose encryption has just been enabled
+    #[clap(long)]
+    allow_unencrypted: bool,
+}
+
+impl DataDir {
+    fn open(&self, options: PersistentDbOptions) -> Result<PersistentDb, PersistentDbError> {
+        let options = match &self.encryption_key_file {
+            Some(path) => options
+                .encryption_key(EncryptionKey::load(path)?)
+                .allow_unencrypted(self.allow_unencrypted),
+            None => options,
+        };
+        PersistentDb::open_with_options(&self.data_dir, options)
+    }
+
+    /// Opens the data directory without changing it, even while a server is
+    /// using it.
+    fn open_read_only(&self) -> Result<PersistentDb, PersistentDbError> {
+        self.open(PersistentDbOptions::default().read_only(true))
+    }
+}
+
+pub(crate) fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    match command {
+        Command::Inspect(data_dir) => {
+            let info = data_dir.open_read_only()?.inspect()?;
+            serde_json::to_writer_pretty(&mut out, &info)?;
+            writeln!(out)?;
+        }
+        Command::Verify(data_dir) => {
+            let pdb = data_dir.open_read_only()?;
+            let mut db = pdb.load_snapshot()?;
+            let mut records = pdb.log_records()?;
+            let mut count = 0;
+            while let Some(res) = records.next() {
+                let record = res.map_err(|err| match records.block() {
+                    Some(block) => format!("block {} is invalid: {}", block, err),
+                    None => err.to_string(),
+                })?;
+                let (block, offset, seq) = (record.block, record.offset, record.seq);
+                db.patch(record.prefix.as_ref(), record.patch)
+                    .map_err(|err| {
+                        format!(
+                            "record {} at block {} offset {} does not apply: {}",
+                            seq, block, offset, err
+                        )
+                    })?;
+                count += 1;
+            }
+            writeln!(out, "ok, {} records applied to the snapshot", count)?;
+        }
+        Command::Dump(data_dir) => {
+            for res in data_dir.open_read_only()?.log_records()? {
+                serde_json::to_writer(&mut out, &res?)?;
+                writeln!(out)?;
+            }
+        }
+        Command::Materialize { data_dir, at } => {
+            let pdb = data_dir.open_read_only()?;
+            let db = match at {
+                Some(target) => pdb.create_memdb_at(target)?,
+                None => pdb.create_memdb()?,
+            };
+            serde_json::to_writer(&mut out, db.root_node())?;
+            writeln!(out)?;
+        }
+        Command::Compact {
+            data_dir,
+            compression,
+            snapshot_format,
+            snapshot_retention,
+        } => {
+            let mut pdb = data_dir.open(
+                PersistentDbOptions::default()
+                    .compression(compression)
+                    .snapshot_format(snapshot_format)
+                    .snapshot_retention(snapshot_retention),
+            )?;
+            let db = pdb.create_memdb()?;
+            pdb.compact_now(db.snapshot())?;
+            serde_json::to_writer_pretty(&mut out, &pdb.inspect()?)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}